@@ -0,0 +1,149 @@
+use crate::unsigned_integer_12::u12;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// BIN format, as punched by the assembler and read by the BIN loader. Unlike RIM only the
+// first word of a block has an address, an origin, and the words after it go in one after
+// another. The tape is between leader and trailer of 200s:
+//   1 0 a a a a a a  Origin, two frames of six bits each. The 100 bit marks them.
+//   0 0 d d d d d d  Data word, two frames of six bits each, at the origin which then goes up.
+//   1 1 f f f 0 0 0  Field setting, we only have field 0 here.
+//   1 1 1 1 1 1 1 1  Rubout, everything up to the next rubout is ignored.
+// The last word before the trailer is a checksum rather than data, the sum of every origin
+// and data frame before it in 12 bits.
+
+const LEADER_TRAILER_CODE: u8 = 0o200;
+const ORIGIN: u8 = 0o100;
+const FIELD_SETTING: u8 = 0o300;
+const RUBOUT: u8 = 0o377;
+const DATA_MASK: u8 = 0o77;
+
+pub struct BinFormat {}
+impl BinFormat {
+    pub fn load_from_file<'a>(
+        path: &Path,
+        buf: &'a mut [u12; 4096],
+    ) -> Result<&'a mut [u12; 4096], Error> {
+        let data: Vec<u8> = fs::read(path)?;
+        BinFormat::load(&data, buf)
+    }
+
+    pub fn load<'a>(data: &[u8], buf: &'a mut [u12; 4096]) -> Result<&'a mut [u12; 4096], Error> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+        let mut origin: u12 = 0.into();
+        let mut checksum: u12 = 0.into();
+        // The last data word, which is only stored once we know it isn't the checksum.
+        let mut pending: Option<(u12, u12)> = None;
+        let mut started = false;
+        let mut ignoring = false;
+        let mut frames = data.iter().copied();
+        while let Some(frame) = frames.next() {
+            if frame == RUBOUT {
+                ignoring = !ignoring;
+                continue;
+            }
+            if ignoring {
+                continue;
+            }
+            if frame == LEADER_TRAILER_CODE {
+                match started {
+                    true => break,
+                    false => continue,
+                }
+            }
+            if frame & FIELD_SETTING == FIELD_SETTING {
+                if frame != FIELD_SETTING {
+                    return Err(invalid("BIN tape is for a field other than 0."));
+                }
+                continue;
+            }
+            started = true;
+            let second = frames
+                .next()
+                .filter(|second| second & !DATA_MASK == 0)
+                .ok_or_else(|| invalid("BIN tape has half a word."))?;
+            let word: u12 = ((u16::from(frame & DATA_MASK) << 6) | u16::from(second)).into();
+            if let Some((address, word)) = pending.take() {
+                buf[address] = word;
+                checksum += (word >> 6.into()) + (word & 0o77.into());
+            }
+            match frame & ORIGIN == ORIGIN {
+                true => {
+                    origin = word;
+                    checksum += u12::from(u16::from(frame)) + u16::from(second).into();
+                }
+                false => {
+                    pending = Some((origin, word));
+                    origin += 1.into();
+                }
+            }
+        }
+        match pending {
+            None => Err(invalid("BIN tape has no checksum.")),
+            Some((_, word)) if word != checksum => Err(invalid("BIN tape checksum is wrong.")),
+            Some(_) => Ok(&mut *buf),
+        }
+    }
+
+    /// A BIN tape of blocks of words, each starting at its origin, with leader, checksum and trailer.
+    #[cfg(test)]
+    pub fn punch(blocks: &[(u16, &[u16])]) -> Vec<u8> {
+        let mut frames = Vec::new();
+        for (origin, words) in blocks {
+            frames.extend([ORIGIN | (origin >> 6) as u8, (origin & 0o77) as u8]);
+            for word in *words {
+                frames.extend([(word >> 6) as u8, (word & 0o77) as u8]);
+            }
+        }
+        let checksum = frames.iter().map(|&frame| u16::from(frame)).sum::<u16>() & 0o7777;
+        let mut tape = vec![LEADER_TRAILER_CODE; 16];
+        tape.extend(frames);
+        tape.extend([(checksum >> 6) as u8, (checksum & 0o77) as u8]);
+        tape.extend([LEADER_TRAILER_CODE; 16]);
+        tape
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let tape = BinFormat::punch(&[
+            (0o200, &[0o7300, 0o1205, 0o7402]),
+            (0o7776, &[0o0001, 0o0002]),
+        ]);
+        let mut buf: [u12; 4096] = [0.into(); 4096];
+        let result = BinFormat::load(&tape, &mut buf).unwrap();
+        assert_eq!(result[0o200], 0o7300.into());
+        assert_eq!(result[0o202], 0o7402.into());
+        assert_eq!(result[0o203], 0.into());
+        assert_eq!(result[0o7777], 0o0002.into());
+        // The checksum isn't loaded anywhere.
+        assert_eq!(result[0], 0.into());
+    }
+
+    #[test]
+    fn test_rubouts_and_field_settings() {
+        let mut tape = BinFormat::punch(&[(0o200, &[0o1234])]);
+        tape.splice(16..16, [FIELD_SETTING, RUBOUT, 0o12, 0o34, RUBOUT]);
+        let mut buf: [u12; 4096] = [0.into(); 4096];
+        assert_eq!(
+            BinFormat::load(&tape, &mut buf).unwrap()[0o200],
+            0o1234.into()
+        );
+    }
+
+    #[test]
+    fn test_bad_tapes() {
+        let mut buf: [u12; 4096] = [0.into(); 4096];
+        let mut tape = BinFormat::punch(&[(0o200, &[0o1234])]);
+        tape[19] ^= 1; // A bit of the data.
+        assert!(BinFormat::load(&tape, &mut buf).is_err());
+        assert!(BinFormat::load(&[0o200, 0o102, 0o200], &mut buf).is_err());
+        assert!(BinFormat::load(&[0o200; 10], &mut buf).is_err());
+        assert!(BinFormat::load(&[0o310, 0o102, 0o00], &mut buf).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+use crate::consts::*;
+use crate::instruction::OpCode;
+use crate::memory::{MemoryAccess, MemoryAccessKind};
+use crate::unsigned_integer_12::u12;
+use crate::{MachineState, Register};
+use std::fmt::{Display, Formatter};
+
+// Breakpoints are looked at in two places. Anything to do with the instruction about to be
+// done (its address, the registers, which device an IOT selects) is checked at the instruction
+// boundary before it is fetched. Watchpoints look at the memory cycles as they happen and stop
+// the machine at the end of the instruction that did the access. The P cycle's own reading and
+// rewriting of location 0 isn't reported, so a watchpoint on the PC catches the program touching it.
+// An interrupt storing the return address in location 1 is.
+
+/// What watchpoints get to see of a cycle's memory accesses, forwards or backwards.
+pub fn watched_accesses(accesses: &[MemoryAccess], program_counter: bool) -> Vec<MemoryAccess> {
+    accesses
+        .iter()
+        .filter(|access| {
+            !program_counter || access.field != 0 || access.address != PC_ADDRESS.into()
+        })
+        .copied()
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+impl Comparison {
+    // Longest first so <= isn't taken as <.
+    const OPERATORS: [(&'static str, Comparison); 7] = [
+        ("==", Comparison::Equal),
+        ("!=", Comparison::NotEqual),
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("=", Comparison::Equal),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+    ];
+
+    fn compare(&self, left: u16, right: u16) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        }
+    }
+}
+
+/// A register compared against an octal constant, e.g AC==7777 or L!=0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        let invalid = || format!("Invalid condition: {text}");
+        let (position, operator, comparison) = Comparison::OPERATORS
+            .iter()
+            .filter_map(|(operator, comparison)| {
+                text.find(operator)
+                    .map(|position| (position, *operator, *comparison))
+            })
+            .min_by_key(|(position, _, _)| *position)
+            .ok_or_else(invalid)?;
+        let register =
+            Register::parse(&text[..position].trim().to_ascii_uppercase()).ok_or_else(invalid)?;
+        let value = match u16::from_str_radix(text[position + operator.len()..].trim(), 8) {
+            Ok(value) if value <= 0o7777 => value,
+            _ => return Err(invalid()),
+        };
+        Ok(Condition {
+            register,
+            comparison,
+            value,
+        })
+    }
+
+    fn holds(&self, state: &MachineState) -> bool {
+        self.comparison
+            .compare(state.read_register(self.register), self.value)
+    }
+}
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{:o}",
+            self.register.name(),
+            self.comparison.symbol(),
+            self.value
+        )
+    }
+}
+
+/// Which memory cycles a watchpoint is interested in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
+}
+impl Watch {
+    fn matches(&self, kind: MemoryAccessKind) -> bool {
+        matches!(
+            (self, kind),
+            (Watch::ReadWrite, _)
+                | (Watch::Read, MemoryAccessKind::Read)
+                | (Watch::Write, MemoryAccessKind::Write)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Stop before the instruction at address is done, if the condition holds when there is one.
+    Execute {
+        address: u12,
+        condition: Option<Condition>,
+    },
+    /// Stop after an instruction (or data break) reads or writes anything from first to last.
+    Watch { watch: Watch, first: u12, last: u12 },
+    /// Stop at the first instruction boundary the condition holds at.
+    Condition(Condition),
+    /// Stop before any IOT selecting the device code, 00 being ION and IOF.
+    Iot(u8),
+}
+impl Display for Breakpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Breakpoint::Execute {
+                address,
+                condition: None,
+            } => write!(f, "{address:04o}"),
+            Breakpoint::Execute {
+                address,
+                condition: Some(condition),
+            } => write!(f, "{address:04o} if {condition}"),
+            Breakpoint::Watch { watch, first, last } => {
+                let watch = match watch {
+                    Watch::Read => "read",
+                    Watch::Write => "write",
+                    Watch::ReadWrite => "read/write",
+                };
+                match first == last {
+                    true => write!(f, "{watch} {first:04o}"),
+                    false => write!(f, "{watch} {first:04o}-{last:04o}"),
+                }
+            }
+            Breakpoint::Condition(condition) => write!(f, "if {condition}"),
+            Breakpoint::Iot(device) => write!(f, "IOT device {device:02o}"),
+        }
+    }
+}
+
+/// Which breakpoint stopped the machine and where.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakpointHit {
+    pub id: usize,
+    pub breakpoint: Breakpoint,
+    pub pc: u12, // Address of the instruction about to be done, or that did the access for a watchpoint.
+    pub access: Option<MemoryAccess>, // The memory cycle that set off a watchpoint.
+}
+
+/// Breakpoints are numbered from 1 in the order they were set, numbers aren't reused.
+pub struct Breakpoints {
+    breakpoints: Vec<(usize, Breakpoint)>,
+    next_id: usize,
+}
+impl Breakpoints {
+    pub fn default() -> Breakpoints {
+        Breakpoints {
+            breakpoints: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn add(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push((id, breakpoint));
+        id
+    }
+
+    /// False if there was no breakpoint with that number.
+    pub fn remove(&mut self, id: usize) -> bool {
+        let before = self.breakpoints.len();
+        self.breakpoints.retain(|(number, _)| *number != id);
+        self.breakpoints.len() != before
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(usize, Breakpoint)> {
+        self.breakpoints.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    /// Memory accesses only need logging if something is watching them.
+    pub fn has_watchpoints(&self) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|(_, breakpoint)| matches!(breakpoint, Breakpoint::Watch { .. }))
+    }
+
+    /// At an instruction boundary, before the instruction at the PC is fetched.
+    pub fn check_instruction(&self, state: &MachineState) -> Option<BreakpointHit> {
+        let pc = state.memory[PC_ADDRESS.into()];
+        let instruction = state.memory[pc];
+        self.breakpoints
+            .iter()
+            .find(|(_, breakpoint)| match breakpoint {
+                Breakpoint::Execute { address, condition } => {
+                    *address == pc && condition.is_none_or(|condition| condition.holds(state))
+                }
+                Breakpoint::Condition(condition) => condition.holds(state),
+                Breakpoint::Iot(device) => {
+                    OpCode::from_instruction(instruction) == OpCode::Iot
+                        && (u16::from(instruction) >> 3) & 0o77 == *device as u16
+                }
+                Breakpoint::Watch { .. } => false,
+            })
+            .map(|(id, breakpoint)| BreakpointHit {
+                id: *id,
+                breakpoint: *breakpoint,
+                pc,
+                access: None,
+            })
+    }
+
+    /// The memory cycles of the cycle just done, pc being the instruction they belong to.
+    pub fn check_accesses(&self, accesses: &[MemoryAccess], pc: u12) -> Option<BreakpointHit> {
+        accesses.iter().find_map(|access| {
+            self.breakpoints
+                .iter()
+                .find(|(_, breakpoint)| match breakpoint {
+                    Breakpoint::Watch { watch, first, last } => {
+                        watch.matches(access.kind)
+                            && access.address >= *first
+                            && access.address <= *last
+                    }
+                    _ => false,
+                })
+                .map(|(id, breakpoint)| BreakpointHit {
+                    id: *id,
+                    breakpoint: *breakpoint,
+                    pc,
+                    access: Some(*access),
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StopReason;
+
+    fn hit(stop: StopReason) -> BreakpointHit {
+        match stop {
+            StopReason::Breakpoint(hit) => hit,
+            other => panic!("Expected a breakpoint, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_execute_breakpoint() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o7001), // IAC
+            (0o201, 0o7001), // IAC
+            (0o202, 0o5200), // JMP 200
+        ]);
+        let id = pdp5.breakpoints.add(Breakpoint::Execute {
+            address: 0o202.into(),
+            condition: None,
+        });
+        let hit = hit(pdp5.start_program(100));
+        assert_eq!(hit.id, id);
+        assert_eq!(hit.pc, 0o202.into());
+        assert_eq!(pdp5.registers.hardware_registers.AC, 2.into());
+        // Carrying on doesn't stop straight away on the same breakpoint.
+        pdp5.start_program(100);
+        assert_eq!(pdp5.registers.hardware_registers.AC, 4.into());
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o7001), // IAC
+            (0o201, 0o5200), // JMP 200
+        ]);
+        pdp5.breakpoints.add(Breakpoint::Execute {
+            address: 0o201.into(),
+            condition: Some(Condition::parse("AC==3").unwrap()),
+        });
+        hit(pdp5.start_program(100));
+        assert_eq!(pdp5.registers.hardware_registers.AC, 3.into());
+        pdp5.breakpoints.clear();
+        pdp5.breakpoints
+            .add(Breakpoint::Condition(Condition::parse("ac >= 10").unwrap()));
+        let hit = hit(pdp5.start_program(100));
+        assert_eq!(pdp5.registers.hardware_registers.AC, 0o10.into());
+        assert_eq!(hit.pc, 0o201.into());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o1410), // TAD I 10, autoindexes 10
+            (0o201, 0o3300), // DCA 300
+            (0o202, 0o7402), // HLT
+            (0o010, 0o0376),
+            (0o377, 0o0055),
+        ]);
+        pdp5.breakpoints.add(Breakpoint::Watch {
+            watch: Watch::Write,
+            first: 0o300.into(),
+            last: 0o377.into(),
+        });
+        let id = pdp5.breakpoints.add(Breakpoint::Watch {
+            watch: Watch::Write,
+            first: 0o10.into(),
+            last: 0o17.into(),
+        });
+        let first = hit(pdp5.start_program(100));
+        assert_eq!(first.id, id);
+        assert_eq!(first.pc, 0o200.into());
+        let access = first.access.unwrap();
+        assert_eq!(access.address, 0o10.into());
+        assert_eq!(access.value, 0o377.into());
+        assert_eq!(access.previous, 0o376.into());
+        // Stopped at the end of the instruction.
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o201.into());
+        assert_eq!(pdp5.registers.hardware_registers.AC, 0o55.into());
+        let second = hit(pdp5.start_program(100));
+        assert_eq!(second.pc, 0o201.into());
+        assert_eq!(pdp5.memory[0o300.into()], 0o55.into());
+        assert_eq!(pdp5.start_program(100), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_pc_watchpoint() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o7001), // IAC
+            (0o201, 0o1000), // TAD 0, reads the PC
+            (0o202, 0o7402), // HLT
+        ]);
+        pdp5.breakpoints.add(Breakpoint::Watch {
+            watch: Watch::ReadWrite,
+            first: PC_ADDRESS.into(),
+            last: PC_ADDRESS.into(),
+        });
+        // The P cycles going through location 0 don't count.
+        let hit = hit(pdp5.start_program(100));
+        assert_eq!(hit.pc, 0o201.into());
+        assert_eq!(pdp5.registers.hardware_registers.AC, 0o202.into());
+    }
+
+    #[test]
+    fn test_iot_breakpoint() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o7001), // IAC
+            (0o201, 0o6001), // ION
+            (0o202, 0o6046), // TLS
+            (0o203, 0o7402), // HLT
+        ]);
+        pdp5.breakpoints.add(Breakpoint::Iot(0o04));
+        let hit = hit(pdp5.start_program(100));
+        assert_eq!(hit.pc, 0o202.into());
+        assert!(!pdp5.run);
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        let condition = Condition::parse("L!=1").unwrap();
+        assert_eq!(condition.register, Register::Link);
+        assert_eq!(condition.comparison, Comparison::NotEqual);
+        assert_eq!(condition.to_string(), "L!=1");
+        assert_eq!(Condition::parse("PC=7600").unwrap().value, 0o7600);
+        assert!(Condition::parse("XY=1").is_err());
+        assert!(Condition::parse("AC=9").is_err());
+    }
+}
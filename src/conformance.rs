@@ -0,0 +1,231 @@
+// Per instruction conformance cases, one file per instruction class in src/conformance.
+// Each case sets up the registers and memory, runs exactly one instruction with
+// step_instruction and checks what the handbook says it should leave behind.
+//
+// All numbers are octal. A file looks like
+//
+//     # Comments and blank lines are ignored.
+//     case TAD carries out of bit 0 into the link
+//     set AC=7777 L=0           registers before, PC is 0200 unless given
+//     memory 0200=1250 0250=0001
+//     expect AC=0000 L=1 PC=0201 SKIP=0 CYCLES=3
+//     check 0250=0001           memory after
+//
+// expect takes the registers plus SKIP (the instruction skipped), CYCLES (major cycles
+// including P), ION (interrupts on, or coming on after the next instruction), RUN
+// (still running, so 0 after a HLT) and WRITES (how many memory writes the instruction
+// made, the P cycle aside).
+
+use crate::unsigned_integer_12::u12;
+use crate::{MachineState, Register};
+
+#[derive(Debug, Default)]
+struct Case {
+    name: String,
+    line: usize,
+    set: Vec<(String, u16)>,
+    memory: Vec<(u16, u16)>,
+    expect: Vec<(String, u16)>,
+    check: Vec<(u16, u16)>,
+}
+
+fn octal(text: &str, line: usize) -> u16 {
+    match u16::from_str_radix(text, 8) {
+        Ok(value) if value <= 0o7777 => value,
+        _ => panic!("line {line}: {text} is not a 12 bit octal number"),
+    }
+}
+
+fn pairs(words: &[&str], line: usize) -> Vec<(String, u16)> {
+    words
+        .iter()
+        .map(|word| match word.split_once('=') {
+            Some((name, value)) => (name.to_string(), octal(value, line)),
+            None => panic!("line {line}: expected NAME=value, got {word}"),
+        })
+        .collect()
+}
+
+fn locations(words: &[&str], line: usize) -> Vec<(u16, u16)> {
+    pairs(words, line)
+        .into_iter()
+        .map(|(address, word)| (octal(&address, line), word))
+        .collect()
+}
+
+fn parse(text: &str) -> Vec<Case> {
+    let mut cases: Vec<Case> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        if keyword == "case" {
+            cases.push(Case {
+                name: rest.trim().to_string(),
+                line: number,
+                ..Case::default()
+            });
+            continue;
+        }
+        let Some(case) = cases.last_mut() else {
+            panic!("line {number}: {keyword} before the first case");
+        };
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        match keyword {
+            "set" => case.set.extend(pairs(&words, number)),
+            "memory" => case.memory.extend(locations(&words, number)),
+            "expect" => case.expect.extend(pairs(&words, number)),
+            "check" => case.check.extend(locations(&words, number)),
+            _ => panic!("line {number}: unknown keyword {keyword}"),
+        }
+    }
+    cases
+}
+
+/// Runs the case, handing back what didn't match.
+fn run(case: &Case) -> Vec<String> {
+    let mut pdp5 = MachineState::with_program(&case.memory);
+    for (name, value) in &case.set {
+        match name.as_str() {
+            "ION" => pdp5.interrupts.enabled = *value != 0,
+            _ => match Register::parse(name) {
+                Some(register) => pdp5.write_register(register, (*value).into()),
+                None => panic!("line {}: no register called {name}", case.line),
+            },
+        }
+    }
+    pdp5.run = true;
+    let record = pdp5.step_instruction();
+
+    let mut failures = Vec::new();
+    for (name, expected) in &case.expect {
+        let actual = match name.as_str() {
+            "SKIP" => record.skipped as u16,
+            "CYCLES" => record.cycles as u16,
+            "WRITES" => record.writes.len() as u16,
+            "ION" => (pdp5.interrupts.enabled || pdp5.interrupts.enable_pending) as u16,
+            "RUN" => pdp5.run as u16,
+            _ => match Register::parse(name) {
+                Some(register) => pdp5.read_register(register),
+                None => panic!("line {}: no register called {name}", case.line),
+            },
+        };
+        if actual != *expected {
+            failures.push(format!("{name} is {actual:o}, expected {expected:o}"));
+        }
+    }
+    for (address, expected) in &case.check {
+        let actual: u12 = pdp5.memory[(*address).into()];
+        if actual != (*expected).into() {
+            failures.push(format!(
+                "{address:04o} is {actual:04o}, expected {expected:04o}"
+            ));
+        }
+    }
+    failures
+}
+
+fn conform(file: &str, text: &str) {
+    let cases = parse(text);
+    assert!(!cases.is_empty(), "{file} has no cases");
+    let failures: Vec<String> = cases
+        .iter()
+        .filter_map(|case| {
+            let failures = run(case);
+            (!failures.is_empty()).then(|| {
+                format!(
+                    "{file}:{} {}: {}",
+                    case.line,
+                    case.name,
+                    failures.join(", ")
+                )
+            })
+        })
+        .collect();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn test_and() {
+    conform("and.txt", include_str!("conformance/and.txt"));
+}
+
+#[test]
+fn test_tad() {
+    conform("tad.txt", include_str!("conformance/tad.txt"));
+}
+
+#[test]
+fn test_isz() {
+    conform("isz.txt", include_str!("conformance/isz.txt"));
+}
+
+#[test]
+fn test_dca() {
+    conform("dca.txt", include_str!("conformance/dca.txt"));
+}
+
+#[test]
+fn test_jms() {
+    conform("jms.txt", include_str!("conformance/jms.txt"));
+}
+
+#[test]
+fn test_jmp() {
+    conform("jmp.txt", include_str!("conformance/jmp.txt"));
+}
+
+#[test]
+fn test_iot() {
+    conform("iot.txt", include_str!("conformance/iot.txt"));
+}
+
+#[test]
+fn test_operate_group_1() {
+    conform(
+        "operate_group_1.txt",
+        include_str!("conformance/operate_group_1.txt"),
+    );
+}
+
+#[test]
+fn test_operate_group_2() {
+    conform(
+        "operate_group_2.txt",
+        include_str!("conformance/operate_group_2.txt"),
+    );
+}
+
+#[test]
+fn test_addressing() {
+    conform("addressing.txt", include_str!("conformance/addressing.txt"));
+}
+
+#[test]
+fn test_location_0() {
+    conform("location_0.txt", include_str!("conformance/location_0.txt"));
+}
+
+#[test]
+fn test_parse() {
+    let cases = parse(
+        "# comment\n\
+         case one\n\
+         set AC=7777 L=1\n\
+         memory 0200=7001\n\
+         expect AC=0000 SKIP=0\n\
+         check 0200=7001\n",
+    );
+    assert_eq!(cases.len(), 1);
+    assert_eq!(cases[0].name, "one");
+    assert_eq!(cases[0].line, 2);
+    assert_eq!(
+        cases[0].set,
+        vec![("AC".to_string(), 0o7777), ("L".to_string(), 1)]
+    );
+    assert_eq!(cases[0].memory, vec![(0o200, 0o7001)]);
+    assert_eq!(cases[0].check, vec![(0o200, 0o7001)]);
+}
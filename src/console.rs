@@ -0,0 +1,197 @@
+use crate::consts::PC_ADDRESS;
+use crate::unsigned_integer_12::u12;
+use crate::{MachineState, StopReason};
+
+// The console keys, as the operator's handbook has them. The switch register is SR and
+// the console's idea of the current address lives in the MA, so the lamps show the
+// address and word after an Examine or Deposit. Examine and Deposit go straight to
+// memory, no breakpoints, no history, the same as the front panel bypassing the processor.
+//
+// To toggle in a program: set the SR to the address and press Load Address, then for each
+// word set the SR to it and press Deposit. Load Address again and Start to run it.
+
+/// The low speed reader RIM loader, 7756 to 7775, as toggled in from the handbook.
+#[cfg(test)]
+pub const RIM_LOADER_ADDRESS: u16 = 0o7756;
+#[cfg(test)]
+pub const RIM_LOADER: [u16; 16] = [
+    0o6032, // KCC
+    0o6031, // KSF
+    0o5357, // JMP .-1
+    0o6036, // KRB
+    0o7106, // CLL RTL
+    0o7006, // RTL
+    0o7510, // SPA
+    0o5357, // JMP 7757, still leader
+    0o7006, // RTL
+    0o6031, // KSF
+    0o5367, // JMP .-1
+    0o6034, // KRS
+    0o7420, // SNL
+    0o3776, // DCA I 7776
+    0o3376, // DCA 7776
+    0o5356, // JMP 7756
+];
+
+impl MachineState {
+    /// The switch register, what Load Address and Deposit take.
+    pub fn set_switches(&mut self, value: u12) {
+        self.registers.hardware_registers.SR = value;
+    }
+
+    /// LOAD ADDRESS: the SR goes into the PC and the MA, Examine and Deposit start from there.
+    pub fn load_address(&mut self) {
+        let address = self.registers.hardware_registers.SR;
+        self.set_initial_start_address(address.into());
+        self.registers.hardware_registers.MA = address;
+        self.console_advance = false;
+    }
+
+    /// The address for an Examine or Deposit, one on from the last one unless there
+    /// has been a Load Address in between.
+    fn console_address(&mut self) -> u12 {
+        let registers = &mut self.registers.hardware_registers;
+        if self.console_advance {
+            registers.MA += 1.into();
+        }
+        self.console_advance = true;
+        registers.MA
+    }
+
+    /// EXAMINE: the word at the next address into the MB.
+    pub fn examine(&mut self) -> u12 {
+        let address = self.console_address();
+        let word = self.memory[address];
+        self.registers.hardware_registers.MB = word;
+        word
+    }
+
+    /// DEPOSIT: the SR into the next address, by way of the MB.
+    pub fn deposit(&mut self) {
+        let address = self.console_address();
+        let registers = &mut self.registers.hardware_registers;
+        registers.MB = registers.SR;
+        self.memory[address] = registers.MB;
+    }
+
+    /// Load Address and Deposit each word in turn from address.
+    #[cfg(test)]
+    pub fn toggle_in(&mut self, address: u12, words: &[u16]) {
+        self.set_switches(address);
+        self.load_address();
+        for word in words {
+            self.set_switches((*word).into());
+            self.deposit();
+        }
+    }
+
+    /// START: clears the AC, link and program interrupt and runs from the PC.
+    pub fn start(&mut self, max_cycles: u64) -> StopReason {
+        let registers = &mut self.registers.hardware_registers;
+        registers.AC = 0.into();
+        registers.L = 0;
+        self.interrupts.enabled = false;
+        self.interrupts.enable_pending = false;
+        let pc = self.memory[PC_ADDRESS.into()];
+        self.set_initial_start_address(pc.into());
+        self.start_program(max_cycles)
+    }
+
+    /// CONTINUE: carries on from wherever the machine stopped with everything as it was.
+    pub fn continue_program(&mut self, max_cycles: u64) -> StopReason {
+        self.start_program(max_cycles)
+    }
+
+    /// STOP: the machine stops at the end of the instruction it is on.
+    pub fn stop(&mut self) {
+        self.run = false;
+    }
+
+    /// SINGLE STEP: one major cycle and stop.
+    pub fn single_step(&mut self) {
+        self.run = true;
+        self.step_cycle();
+        self.run = false;
+    }
+
+    /// SINGLE INSTRUCTION: the rest of the instruction in progress, or the next one
+    /// if we are between instructions, and stop.
+    pub fn single_instruction(&mut self) {
+        self.run = true;
+        self.step_instruction();
+        self.run = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggle_in_and_examine_the_rim_loader() {
+        let mut pdp5 = MachineState::with_program(&[]);
+        pdp5.toggle_in(RIM_LOADER_ADDRESS.into(), &RIM_LOADER);
+        // The lamps show the last word deposited.
+        assert_eq!(pdp5.registers.hardware_registers.MA, 0o7775.into());
+        assert_eq!(pdp5.registers.hardware_registers.MB, 0o5356.into());
+
+        pdp5.set_switches(RIM_LOADER_ADDRESS.into());
+        pdp5.load_address();
+        for word in RIM_LOADER {
+            assert_eq!(pdp5.examine(), word.into());
+        }
+        assert_eq!(pdp5.registers.hardware_registers.MA, 0o7775.into());
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], RIM_LOADER_ADDRESS.into());
+    }
+
+    #[test]
+    fn test_start_and_continue() {
+        let mut pdp5 = MachineState::with_program(&[]);
+        pdp5.toggle_in(
+            0o200.into(),
+            &[
+                0o7001, // IAC
+                0o7402, // HLT
+                0o7001, // IAC
+                0o7402, // HLT
+            ],
+        );
+        pdp5.registers.hardware_registers.AC = 0o1234.into();
+        pdp5.registers.hardware_registers.L = 1;
+        pdp5.set_switches(0o200.into());
+        pdp5.load_address();
+        assert_eq!(pdp5.start(100), StopReason::Halted);
+        assert_eq!(pdp5.registers.hardware_registers.AC, 1.into());
+        assert_eq!(pdp5.registers.hardware_registers.L, 0);
+        assert_eq!(pdp5.continue_program(100), StopReason::Halted);
+        assert_eq!(pdp5.registers.hardware_registers.AC, 2.into());
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o204.into());
+    }
+
+    #[test]
+    fn test_single_step_and_single_instruction() {
+        let mut pdp5 = MachineState::with_program(&[]);
+        pdp5.toggle_in(
+            0o200.into(),
+            &[
+                0o1205, // TAD 205
+                0o3206, // DCA 206
+                0o7402, // HLT
+            ],
+        );
+        pdp5.memory[0o205.into()] = 0o42.into();
+        pdp5.set_switches(0o200.into());
+        pdp5.load_address();
+        pdp5.single_step();
+        assert_eq!(pdp5.state.name(), "E1");
+        assert!(!pdp5.run);
+        pdp5.single_instruction();
+        assert_eq!(pdp5.state.name(), "F");
+        assert_eq!(pdp5.registers.hardware_registers.AC, 0o42.into());
+        pdp5.single_instruction();
+        assert_eq!(pdp5.memory[0o206.into()], 0o42.into());
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o202.into());
+        pdp5.stop();
+        assert!(!pdp5.run);
+    }
+}
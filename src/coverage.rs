@@ -0,0 +1,395 @@
+use crate::disassembler::disassemble_with_symbols;
+use crate::instruction::{InstrIot, InstrOperate, OpCode};
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+use crate::unsigned_integer_12::u12;
+use std::fmt::Write;
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+
+// Which instructions a run got to and which way its conditional skips went. A skip is
+// covered once it has been seen both skipping and falling through. The program is whatever
+// was in memory when coverage started, or the words in an assembler listing if there is one.
+// Data can't be told apart from code that never ran, so data words come out as not executed.
+
+/// A line of a PAL listing with a word on it, the rest being comments and the like.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingLine {
+    pub number: usize, // Line in the listing file, from 1.
+    pub address: u12,
+    pub word: u12,
+    pub source: String,
+}
+
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+}
+
+fn octal_word(token: &str) -> Option<u12> {
+    match token.len() {
+        4 => u16::from_str_radix(token, 8).ok().map(u12::from),
+        _ => None,
+    }
+}
+
+/// The first word of text and what follows it.
+fn next_token(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], text[end..].trim_start())
+}
+
+impl Listing {
+    /// Lines look like `0200 7300   START, CLA CLL`, optionally with the
+    /// assembler's line number in front. Anything else in the listing is skipped.
+    pub fn parse(text: &str) -> Listing {
+        let mut lines = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let (first, rest) = next_token(line);
+            let (second, after_second) = next_token(rest);
+            let (third, after_third) = next_token(after_second);
+            let numbered = first.chars().all(|c| c.is_ascii_digit());
+            let parsed = match (octal_word(first), octal_word(second), octal_word(third)) {
+                (_, Some(address), Some(word)) if numbered => Some((address, word, after_third)),
+                (Some(address), Some(word), _) => Some((address, word, after_second)),
+                _ => None,
+            };
+            if let Some((address, word, source)) = parsed {
+                lines.push(ListingLine {
+                    number: index + 1,
+                    address,
+                    word,
+                    source: source.trim_end().to_string(),
+                });
+            }
+        }
+        Listing { lines }
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<Listing, Error> {
+        Ok(Listing::parse(&fs::read_to_string(path)?))
+    }
+}
+
+/// What the report and lcov output go through, a word of the program.
+struct Entry {
+    line: usize,
+    address: u12,
+    source: String,
+}
+
+pub struct Coverage {
+    program: Vec<bool>, // Loaded when coverage started.
+    executions: Vec<u64>,
+    skipped: Vec<u64>,
+    fell_through: Vec<u64>,
+    can_skip: Vec<bool>,  // Was a conditional skip when loaded or last fetched.
+    pending: Option<u12>, // Conditional skip waiting on its P cycle.
+}
+impl Coverage {
+    /// Everything non zero in memory is taken to be the program, bar the PC and
+    /// the interrupt return address.
+    pub fn new(memory: &Memory) -> Coverage {
+        let program: Vec<bool> = (0..4096usize)
+            .map(|address| address > 1 && memory[address.into()] != u12::ZERO)
+            .collect();
+        // So skips that never ran count as not taken either way.
+        let can_skip = (0..4096usize)
+            .map(|address| program[address] && Coverage::conditional_skip(memory[address.into()]))
+            .collect();
+        Coverage {
+            program,
+            executions: vec![0; 4096],
+            skipped: vec![0; 4096],
+            fell_through: vec![0; 4096],
+            can_skip,
+            pending: None,
+        }
+    }
+
+    /// Might this instruction skip or not depending on what it finds?
+    pub fn conditional_skip(instruction: u12) -> bool {
+        match OpCode::from_instruction(instruction) {
+            OpCode::Isz => true,
+            OpCode::Iot => InstrIot(instruction).skips_conditionally(),
+            OpCode::Operate => InstrOperate(instruction).skips_conditionally(),
+            _ => false,
+        }
+    }
+
+    /// A Fetch of the instruction at pc.
+    pub fn note_fetch(&mut self, pc: u12, instruction: u12) {
+        let address = usize::from(pc);
+        self.executions[address] += 1;
+        self.can_skip[address] = Coverage::conditional_skip(instruction);
+        self.pending = self.can_skip[address].then_some(pc);
+    }
+
+    /// The P cycle finishing the instruction at pc, skipping or not.
+    pub fn note_outcome(&mut self, pc: u12, skipped: bool) {
+        if self.pending.take() != Some(pc) {
+            return;
+        }
+        match skipped {
+            true => self.skipped[usize::from(pc)] += 1,
+            false => self.fell_through[usize::from(pc)] += 1,
+        }
+    }
+
+    pub fn executions(&self, address: u12) -> u64 {
+        self.executions[usize::from(address)]
+    }
+
+    /// How many times a conditional skip skipped and fell through, None for anything else.
+    pub fn skips(&self, address: u12) -> Option<(u64, u64)> {
+        let address = usize::from(address);
+        self.can_skip[address].then(|| (self.skipped[address], self.fell_through[address]))
+    }
+
+    fn entries(&self, memory: &Memory, symbols: &Symbols, listing: Option<&Listing>) -> Vec<Entry> {
+        match listing {
+            Some(listing) => listing
+                .lines
+                .iter()
+                .map(|line| Entry {
+                    line: line.number,
+                    address: line.address,
+                    source: line.source.clone(),
+                })
+                .collect(),
+            // Addresses stand in for line numbers, counting from 1 as lcov does.
+            None => (0..4096usize)
+                .filter(|&address| self.program[address] || self.executions[address] > 0)
+                .map(|address| {
+                    let instruction =
+                        disassemble_with_symbols(address.into(), memory[address.into()], symbols);
+                    Entry {
+                        line: address + 1,
+                        address: address.into(),
+                        // Labelled the way a listing would be.
+                        source: match symbols.name(address.into()) {
+                            Some(label) => format!("{label}, {instruction}"),
+                            None => instruction,
+                        },
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Every word of the program with how often it ran, marked # if it never did
+    /// and > if it is a skip that has only gone one way.
+    pub fn report(&self, memory: &Memory, symbols: &Symbols, listing: Option<&Listing>) -> String {
+        let entries = self.entries(memory, symbols, listing);
+        let executed = entries
+            .iter()
+            .filter(|entry| self.executions(entry.address) > 0)
+            .count();
+        let skips: Vec<(u64, u64)> = entries
+            .iter()
+            .filter_map(|entry| self.skips(entry.address))
+            .collect();
+        let both_ways = skips
+            .iter()
+            .filter(|&&(skipped, fell_through)| skipped > 0 && fell_through > 0)
+            .count();
+        let percent = |count: usize, total: usize| match total {
+            0 => 0.0,
+            _ => count as f64 * 100.0 / total as f64,
+        };
+
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "{executed} of {} words executed ({:.1}%), {both_ways} of {} skips taken both ways ({:.1}%)\n",
+            entries.len(),
+            percent(executed, entries.len()),
+            skips.len(),
+            percent(both_ways, skips.len())
+        );
+        for entry in &entries {
+            let count = self.executions(entry.address);
+            let skips = self.skips(entry.address);
+            let mark = match skips {
+                _ if count == 0 => '#',
+                Some((skipped, fell_through)) if skipped == 0 || fell_through == 0 => '>',
+                _ => ' ',
+            };
+            let _ = write!(
+                report,
+                "{mark} {:04o}  {count:8}  {}",
+                entry.address, entry.source
+            );
+            if let Some((skipped, fell_through)) = skips {
+                let _ = write!(report, "  (skipped {skipped}, fell through {fell_through})");
+            }
+            report.push('\n');
+        }
+        report
+    }
+
+    /// lcov's tracefile format, each skip being a branch with skipping as its first
+    /// arm and falling through as its second. source is the file the lines are in.
+    pub fn lcov(&self, memory: &Memory, source: &str, listing: Option<&Listing>) -> String {
+        // Only line numbers go in, so names wouldn't show up anyway.
+        let entries = self.entries(memory, &Symbols::default(), listing);
+        let mut lcov = String::new();
+        let _ = writeln!(lcov, "TN:");
+        let _ = writeln!(lcov, "SF:{source}");
+        let (mut branches, mut branches_hit) = (0, 0);
+        for entry in &entries {
+            let Some((skipped, fell_through)) = self.skips(entry.address) else {
+                continue;
+            };
+            for (arm, taken) in [skipped, fell_through].into_iter().enumerate() {
+                let _ = writeln!(lcov, "BRDA:{},0,{arm},{taken}", entry.line);
+                branches += 1;
+                branches_hit += usize::from(taken > 0);
+            }
+        }
+        let _ = writeln!(lcov, "BRF:{branches}");
+        let _ = writeln!(lcov, "BRH:{branches_hit}");
+        for entry in &entries {
+            let _ = writeln!(lcov, "DA:{},{}", entry.line, self.executions(entry.address));
+        }
+        let _ = writeln!(lcov, "LF:{}", entries.len());
+        let _ = writeln!(
+            lcov,
+            "LH:{}",
+            entries
+                .iter()
+                .filter(|entry| self.executions(entry.address) > 0)
+                .count()
+        );
+        let _ = writeln!(lcov, "end_of_record");
+        lcov
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MachineState;
+
+    // Counts 0300 up from -2 and halts when it gets to 0, never getting to 0204 or 0206.
+    const PROGRAM: [(u16, u16); 8] = [
+        (0o200, 0o2300), // ISZ 300
+        (0o201, 0o5203), // JMP 203
+        (0o202, 0o7402), // HLT
+        (0o203, 0o7440), // SZA
+        (0o204, 0o7402), // HLT
+        (0o205, 0o5200), // JMP 200
+        (0o206, 0o7410), // SKP
+        (0o300, 0o7776),
+    ];
+
+    fn run() -> MachineState {
+        let mut pdp5 = MachineState::with_program(&PROGRAM);
+        pdp5.enable_coverage();
+        assert_eq!(pdp5.start_program(1000), crate::StopReason::Halted);
+        pdp5
+    }
+
+    #[test]
+    fn test_counts() {
+        let pdp5 = run();
+        let coverage = pdp5.coverage.as_ref().unwrap();
+        assert_eq!(coverage.executions(0o200.into()), 2);
+        assert_eq!(coverage.executions(0o204.into()), 0);
+        // ISZ went 7777 then 0000.
+        assert_eq!(coverage.skips(0o200.into()), Some((1, 1)));
+        // The AC is never anything but 0, so SZA always skipped.
+        assert_eq!(coverage.skips(0o203.into()), Some((1, 0)));
+        assert_eq!(coverage.skips(0o201.into()), None);
+        assert!(Coverage::conditional_skip(0o6031.into())); // KSF
+        assert!(!Coverage::conditional_skip(0o6046.into())); // TLS
+        assert!(!Coverage::conditional_skip(0o6001.into())); // ION
+        assert!(!Coverage::conditional_skip(0o7410.into())); // SKP
+    }
+
+    #[test]
+    fn test_report() {
+        let pdp5 = run();
+        let coverage = pdp5.coverage.as_ref().unwrap();
+        let report = coverage.report(&pdp5.memory, &Symbols::default(), None);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "5 of 8 words executed (62.5%), 1 of 3 skips taken both ways (33.3%)"
+        );
+        assert_eq!(
+            lines[2],
+            "  0200         2  ISZ 0300  (skipped 1, fell through 1)"
+        );
+        assert_eq!(
+            lines[5],
+            "> 0203         1  SZA  (skipped 1, fell through 0)"
+        );
+        assert_eq!(lines[6], "# 0204         0  HLT");
+
+        let symbols = Symbols::parse("START=0200 TEST=0203 COUNT=0300").unwrap();
+        let report = coverage.report(&pdp5.memory, &symbols, None);
+        assert!(
+            report.contains("\n  0200         2  START, ISZ COUNT  (skipped 1, fell through 1)\n")
+        );
+        assert!(report.contains("\n  0201         1  JMP TEST\n"));
+    }
+
+    #[test]
+    fn test_listing() {
+        let listing = Listing::parse(
+            "/ A comment\n\
+             \n\
+             \x20   5 0200 2300  START,  ISZ COUNT\n\
+             0201 5203          JMP TEST\n\
+             0202 7402          HLT\n\
+             \x20  12 0204 7402  HLT   / Never got here\n\
+             COUNT=0300\n",
+        );
+        assert_eq!(listing.lines.len(), 4);
+        assert_eq!(
+            listing.lines[0],
+            ListingLine {
+                number: 3,
+                address: 0o200.into(),
+                word: 0o2300.into(),
+                source: "START,  ISZ COUNT".to_string(),
+            }
+        );
+        assert_eq!(listing.lines[3].address, 0o204.into());
+
+        let pdp5 = run();
+        let coverage = pdp5.coverage.as_ref().unwrap();
+        let report = coverage.report(&pdp5.memory, &Symbols::default(), Some(&listing));
+        assert!(report.starts_with("3 of 4 words executed (75.0%), 1 of 1 skips"));
+        assert!(report.contains("\n  0201         1  JMP TEST\n"));
+        assert!(report.contains("\n# 0204         0  HLT   / Never got here\n"));
+
+        let lcov = coverage.lcov(&pdp5.memory, "counter.lst", Some(&listing));
+        assert_eq!(
+            lcov,
+            "TN:\nSF:counter.lst\n\
+             BRDA:3,0,0,1\nBRDA:3,0,1,1\nBRF:2\nBRH:2\n\
+             DA:3,2\nDA:4,1\nDA:5,1\nDA:6,0\nLF:4\nLH:3\n\
+             end_of_record\n"
+        );
+    }
+
+    #[test]
+    fn test_lcov_without_a_listing() {
+        let pdp5 = run();
+        let lcov = pdp5
+            .coverage
+            .as_ref()
+            .unwrap()
+            .lcov(&pdp5.memory, "counter.rim", None);
+        // Line numbers are the address plus one.
+        assert!(lcov.contains("\nBRDA:129,0,0,1\nBRDA:129,0,1,1\n"));
+        assert!(lcov.contains("\nBRDA:132,0,0,1\nBRDA:132,0,1,0\n"));
+        assert!(lcov.contains("\nDA:133,0\n"));
+        // The count looks like a skip to us, it would be SMA SZA SNL if it ran.
+        assert!(lcov.contains("\nBRDA:193,0,0,0\nBRDA:193,0,1,0\n"));
+        assert!(lcov.contains("\nLF:8\nLH:5\n"));
+    }
+}
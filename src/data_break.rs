@@ -0,0 +1,364 @@
+use crate::memory::Field;
+use crate::snapshot::{SnapshotReader, SnapshotWriter, load_cycle_state, save_cycle_state};
+use crate::unsigned_integer_12::u12;
+use crate::{CycleState, MachineState};
+use std::io::{Error, ErrorKind};
+
+// BREAK (B): A device steals memory cycles to transfer data directly to or
+// from core memory without going through the AC. A break is granted at the end
+// of whichever major cycle is in progress, so it can land between any two cycles
+// of an instruction, and once it is over the processor carries on with the cycle
+// it would have done next. The break uses the MA and MB so we put the processor's
+// values back afterwards.
+
+/// Which way the word goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakDirection {
+    /// Device to memory, with the word to store.
+    In(u12),
+    /// Memory to device.
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakMode {
+    /// The device supplies the address itself, one memory cycle.
+    SingleCycle(u12),
+    /// The word count is kept in memory at the given location and the current address in
+    /// the location after it. The word count cycle increments the word count (it overflows to
+    /// zero on the last word), the current address cycle increments the current address and
+    /// that becomes the address for the transfer cycle.
+    ThreeCycle(u12),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakRequest {
+    pub priority: u8, // Lower numbers are serviced first.
+    pub mode: BreakMode,
+    pub direction: BreakDirection,
+}
+
+/// Handed back to the device once its break has happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakTransfer {
+    pub address: u12,
+    pub data: u12, // The word written to memory for In, or read from memory for Out.
+    pub word_count_overflow: bool, // Always false for single cycle breaks.
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakCycle {
+    WordCount,
+    CurrentAddress,
+    Transfer,
+}
+
+pub struct Break {
+    device: usize,
+    request: BreakRequest,
+    cycle: BreakCycle,
+    address: u12,
+    word_count_overflow: bool,
+    resume: Box<CycleState>,
+    saved_ma: u12,
+    saved_mb: u12,
+}
+impl Break {
+    /// Checked before every major cycle. If a device wants a break the next cycle(s) are
+    /// break cycles for it. A three cycle break runs to completion before anyone else gets a look in.
+    pub fn grant(state: &mut MachineState) {
+        if matches!(state.state, CycleState::B(_)) {
+            return;
+        }
+        let Some((device, request)) = state.iot_bus.data_break_request() else {
+            return;
+        };
+        let (cycle, address) = match request.mode {
+            BreakMode::SingleCycle(address) => (BreakCycle::Transfer, address),
+            BreakMode::ThreeCycle(_) => (BreakCycle::WordCount, 0.into()),
+        };
+        let resume = std::mem::replace(&mut state.state, CycleState::default());
+        state.state = CycleState::B(Break {
+            device,
+            request,
+            cycle,
+            address,
+            word_count_overflow: false,
+            resume: Box::new(resume),
+            saved_ma: state.registers.hardware_registers.MA,
+            saved_mb: state.registers.hardware_registers.MB,
+        });
+    }
+
+    pub fn execute(mut self, state: &mut MachineState) -> CycleState {
+        let registers = &mut state.registers.hardware_registers;
+        state.memory.select(Field::Zero);
+        let word_count_address = match self.request.mode {
+            BreakMode::ThreeCycle(address) => address,
+            BreakMode::SingleCycle(_) => 0.into(),
+        };
+        match self.cycle {
+            BreakCycle::WordCount => {
+                registers.MA = word_count_address;
+                registers.MB = state.memory.read(registers.MA) + 1.into();
+                state.memory.write(registers.MA, registers.MB);
+                self.word_count_overflow = registers.MB == 0.into();
+                self.cycle = BreakCycle::CurrentAddress;
+                CycleState::B(self)
+            }
+            BreakCycle::CurrentAddress => {
+                registers.MA = word_count_address + 1.into();
+                registers.MB = state.memory.read(registers.MA) + 1.into();
+                state.memory.write(registers.MA, registers.MB);
+                self.address = registers.MB;
+                self.cycle = BreakCycle::Transfer;
+                CycleState::B(self)
+            }
+            BreakCycle::Transfer => {
+                registers.MA = self.address;
+                match self.request.direction {
+                    BreakDirection::In(data) => {
+                        registers.MB = data;
+                        state.memory.write(registers.MA, registers.MB);
+                    }
+                    BreakDirection::Out => registers.MB = state.memory.read(registers.MA),
+                }
+                let transfer = BreakTransfer {
+                    address: self.address,
+                    data: registers.MB,
+                    word_count_overflow: self.word_count_overflow,
+                };
+                registers.MA = self.saved_ma;
+                registers.MB = self.saved_mb;
+                state.iot_bus.data_break_granted(self.device, transfer);
+                *self.resume
+            }
+        }
+    }
+
+    /// A snapshot can be taken part way through a break so all of it has to go in.
+    pub fn save(&self, writer: &mut SnapshotWriter) {
+        writer.u16(self.device as u16);
+        writer.u8(self.request.priority);
+        match self.request.mode {
+            BreakMode::SingleCycle(address) => {
+                writer.u8(0);
+                writer.u12(address);
+            }
+            BreakMode::ThreeCycle(address) => {
+                writer.u8(1);
+                writer.u12(address);
+            }
+        }
+        match self.request.direction {
+            BreakDirection::In(data) => {
+                writer.u8(0);
+                writer.u12(data);
+            }
+            BreakDirection::Out => writer.u8(1),
+        }
+        writer.u8(match self.cycle {
+            BreakCycle::WordCount => 0,
+            BreakCycle::CurrentAddress => 1,
+            BreakCycle::Transfer => 2,
+        });
+        writer.u12(self.address);
+        writer.bool(self.word_count_overflow);
+        save_cycle_state(&self.resume, writer);
+        writer.u12(self.saved_ma);
+        writer.u12(self.saved_mb);
+    }
+
+    pub fn load(reader: &mut SnapshotReader) -> Result<Break, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "Snapshot has a bad data break.");
+        let device = reader.u16()? as usize;
+        let priority = reader.u8()?;
+        let mode = match reader.u8()? {
+            0 => BreakMode::SingleCycle(reader.u12()?),
+            1 => BreakMode::ThreeCycle(reader.u12()?),
+            _ => return Err(invalid()),
+        };
+        let direction = match reader.u8()? {
+            0 => BreakDirection::In(reader.u12()?),
+            1 => BreakDirection::Out,
+            _ => return Err(invalid()),
+        };
+        let cycle = match reader.u8()? {
+            0 => BreakCycle::WordCount,
+            1 => BreakCycle::CurrentAddress,
+            2 => BreakCycle::Transfer,
+            _ => return Err(invalid()),
+        };
+        let address = reader.u12()?;
+        let word_count_overflow = reader.bool()?;
+        let resume = load_cycle_state(reader)?;
+        if matches!(resume, CycleState::B(_)) {
+            return Err(invalid());
+        }
+        Ok(Break {
+            device,
+            request: BreakRequest {
+                priority,
+                mode,
+                direction,
+            },
+            cycle,
+            address,
+            word_count_overflow,
+            resume: Box::new(resume),
+            saved_ma: reader.u12()?,
+            saved_mb: reader.u12()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iot_bus::{IotDevice, IotResponse};
+    use crate::scheduler::Events;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Requests a break for every word it has left to send (or receive) and records what it was given.
+    struct BreakDevice {
+        priority: u8,
+        mode: BreakMode,
+        words: Vec<Option<u12>>, // Some for input, None for output.
+        transfers: Rc<RefCell<Vec<(u8, BreakTransfer)>>>,
+    }
+    impl IotDevice for BreakDevice {
+        fn device_codes(&self) -> &[u8] {
+            &[]
+        }
+        fn pulse(
+            &mut self,
+            _device_code: u8,
+            _pulse: u8,
+            ac: u12,
+            _events: &mut Events,
+        ) -> IotResponse {
+            IotResponse::unchanged(ac)
+        }
+        fn data_break_request(&self) -> Option<BreakRequest> {
+            let word = self.words.first()?;
+            Some(BreakRequest {
+                priority: self.priority,
+                mode: self.mode,
+                direction: match word {
+                    Some(data) => BreakDirection::In(*data),
+                    None => BreakDirection::Out,
+                },
+            })
+        }
+        fn data_break_granted(&mut self, transfer: BreakTransfer, _events: &mut Events) {
+            self.words.remove(0);
+            if let BreakMode::SingleCycle(address) = self.mode {
+                self.mode = BreakMode::SingleCycle(address + 1.into());
+            }
+            self.transfers.borrow_mut().push((self.priority, transfer));
+        }
+    }
+
+    #[test]
+    fn test_three_cycle_break_input() {
+        let transfers = Rc::new(RefCell::new(Vec::new()));
+        let mut pdp5 = MachineState::with_program(&[]);
+        // Word count of -3 at 030, current address one before the buffer at 031.
+        pdp5.memory[0o30.into()] = (-3).into();
+        pdp5.memory[0o31.into()] = 0o377.into();
+        pdp5.iot_bus.attach(Box::new(BreakDevice {
+            priority: 0,
+            mode: BreakMode::ThreeCycle(0o30.into()),
+            words: vec![
+                Some(0o1111.into()),
+                Some(0o2222.into()),
+                Some(0o3333.into()),
+            ],
+            transfers: transfers.clone(),
+        }));
+        for _ in 0..9 {
+            pdp5.step_cycle();
+        }
+        assert_eq!(pdp5.memory[0o400.into()], 0o1111.into());
+        assert_eq!(pdp5.memory[0o401.into()], 0o2222.into());
+        assert_eq!(pdp5.memory[0o402.into()], 0o3333.into());
+        assert_eq!(pdp5.memory[0o30.into()], 0.into());
+        assert_eq!(pdp5.memory[0o31.into()], 0o402.into());
+        let overflows: Vec<bool> = transfers
+            .borrow()
+            .iter()
+            .map(|(_, transfer)| transfer.word_count_overflow)
+            .collect();
+        assert_eq!(overflows, vec![false, false, true]);
+    }
+
+    #[test]
+    fn test_single_cycle_break_output() {
+        let transfers = Rc::new(RefCell::new(Vec::new()));
+        let mut pdp5 = MachineState::with_program(&[]);
+        pdp5.memory[0o500.into()] = 0o7070.into();
+        pdp5.iot_bus.attach(Box::new(BreakDevice {
+            priority: 0,
+            mode: BreakMode::SingleCycle(0o500.into()),
+            words: vec![None],
+            transfers: transfers.clone(),
+        }));
+        pdp5.step_cycle();
+        assert_eq!(transfers.borrow()[0].1.data, 0o7070.into());
+        assert_eq!(transfers.borrow()[0].1.address, 0o500.into());
+    }
+
+    #[test]
+    fn test_priority() {
+        let transfers = Rc::new(RefCell::new(Vec::new()));
+        let mut pdp5 = MachineState::with_program(&[]);
+        for priority in [2, 1] {
+            pdp5.iot_bus.attach(Box::new(BreakDevice {
+                priority,
+                mode: BreakMode::SingleCycle((0o100 * priority as u16).into()),
+                words: vec![Some(1.into()), Some(2.into())],
+                transfers: transfers.clone(),
+            }));
+        }
+        for _ in 0..4 {
+            pdp5.step_cycle();
+        }
+        let order: Vec<u8> = transfers
+            .borrow()
+            .iter()
+            .map(|(priority, _)| *priority)
+            .collect();
+        assert_eq!(order, vec![1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn test_break_between_cycles_of_an_instruction() {
+        let transfers = Rc::new(RefCell::new(Vec::new()));
+        let mut pdp5 = MachineState::with_program(&[]);
+        // 0200 TAD I 0210, 0210 points at 0300 which holds 5.
+        pdp5.memory[0o200.into()] = 0o1610.into();
+        pdp5.memory[0o210.into()] = 0o300.into();
+        pdp5.memory[0o300.into()] = 5.into();
+        pdp5.set_initial_start_address(0o200);
+        // Fetch, the next cycle is the Defer.
+        pdp5.step_cycle();
+        assert!(matches!(pdp5.state, CycleState::D(_)));
+        pdp5.iot_bus.attach(Box::new(BreakDevice {
+            priority: 0,
+            mode: BreakMode::SingleCycle(0o1000.into()),
+            words: vec![Some(0o4444.into())],
+            transfers: transfers.clone(),
+        }));
+        pdp5.step_cycle();
+        assert!(matches!(pdp5.state, CycleState::D(_)));
+        assert_eq!(pdp5.memory[0o1000.into()], 0o4444.into());
+        // Defer, Execute and P carry on as if nothing happened.
+        pdp5.step_cycle();
+        pdp5.step_cycle();
+        pdp5.step_cycle();
+        assert_eq!(pdp5.registers.hardware_registers.AC, 5.into());
+        assert_eq!(pdp5.memory[0.into()], 0o201.into());
+        assert_eq!(pdp5.cycles, 5);
+    }
+}
@@ -0,0 +1,905 @@
+use crate::breakpoints::{Breakpoint, BreakpointHit, Condition, Watch};
+use crate::consts::*;
+use crate::disassembler::disassemble_with_symbols;
+use crate::memory::MemoryAccessKind;
+use crate::rim_format_reader::RimFormat;
+use crate::snapshot::Snapshot;
+use crate::symbols::Symbols;
+use crate::unsigned_integer_12::u12;
+use crate::write_protect::{ProtectedRange, Protection, RIM_LOADER};
+use crate::{MachineState, Register, StopReason};
+use std::io::{BufRead, Error, Write};
+use std::path::Path;
+
+// Commands are in the style of SIMH and can be shortened to any prefix at least
+// as long as the number given here, so E 200 is EXAMINE 200 but CY is needed for CYCLE.
+const COMMANDS: [(&str, usize); 20] = [
+    ("EXAMINE", 1),
+    ("DEPOSIT", 1),
+    ("STEP", 1),
+    ("CONTINUE", 1),
+    ("CYCLE", 2),
+    ("GO", 1),
+    ("LOAD", 2),
+    ("SAVE", 2),
+    ("RESTORE", 3),
+    ("BREAK", 2),
+    ("NOBREAK", 3),
+    ("BACK", 2),
+    ("REVERSE", 3),
+    ("HISTORY", 2),
+    ("SYMBOLS", 2),
+    ("PROTECT", 2),
+    ("NOPROTECT", 3),
+    ("HELP", 1),
+    ("QUIT", 1),
+    ("EXIT", 3),
+];
+
+const HELP: &str = "\
+Numbers are octal except for step and cycle counts. Anywhere an address is
+wanted a symbol can be given instead, or a symbol plus an offset e.g LOOP+3.
+E{XAMINE} {-M} <what>       Examine a register (AC L MB MA IR SR PC), STATE,
+                            an address or a range of addresses e.g 200-217.
+                            -M shows memory as instructions.
+D{EPOSIT} <what> <value>    Deposit into a register, address or range.
+S{TEP} {n}                  Execute n instructions, default 1.
+CY{CLE} {n}                 Execute n major cycles, default 1.
+C{ONTINUE}                  Run from where we are until a halt.
+G{O} {address}              Start at address, default the PC, and run until a halt.
+LO{AD} <file>               Load a RIM format tape into memory.
+SA{VE} <file>               Save a snapshot of the whole machine.
+RES{TORE} <file>            Put the machine back as it was in a snapshot, the same
+                            devices need to be attached.
+BR{EAK}                     List the breakpoints.
+BR{EAK} <address> {IF <c>}  Stop before the instruction at address, optionally only
+                            if a condition like AC==7777 or L!=0 holds.
+BR{EAK} IF <c>              Stop as soon as the condition holds.
+BR{EAK} -R|-W <what>        Stop after an address, range or the PC is read or
+                            written, -R -W for either.
+BR{EAK} -I <device>         Stop before an IOT to the device.
+NOB{REAK} <n>|ALL           Remove breakpoint n or all of them.
+BA{CK} {n}                  Undo n instructions, default 1.
+REV{ERSE} {address}         Go backwards to the last breakpoint, or to just before
+                            the last write to address.
+HI{STORY} {n|OFF}           Show, set or turn off how many instructions are kept
+                            for going backwards.
+SY{MBOLS} {file}            List the symbols, or add those in a name=value file.
+PR{OTECT}                   List the write protected ranges and logged writes.
+PR{OTECT} <what> {how}      Write protect an address, range or RIM for the RIM
+                            loader. Writes there don't happen and are IGNOREd,
+                            TRAP to stop after the instruction (the default) or
+                            are kept to LOG.
+NOP{ROTECT}                 Remove all write protection and forget logged writes.
+H{ELP}                      This.
+Q{UIT}, EXI{T}              Leave the debugger.";
+
+// About 10 seconds of PDP5 time before CONTINUE and GO give up and hand back control.
+const MAX_CYCLES: u64 = 10_000_000_000 / CYCLE_TIME_NS as u64;
+
+// Instructions kept for going backwards unless HISTORY says otherwise.
+const DEFAULT_HISTORY: usize = 100_000;
+
+const REGISTERS: [Register; 7] = [
+    Register::Pc,
+    Register::Ac,
+    Register::Link,
+    Register::Mb,
+    Register::Ma,
+    Register::Ir,
+    Register::Sr,
+];
+
+/// What EXAMINE and DEPOSIT are pointed at.
+enum Location {
+    Register(Register),
+    Memory(u12, u12), // First and last address, inclusive.
+    State,
+}
+
+fn parse_octal(text: &str) -> Result<u12, String> {
+    match u16::from_str_radix(text, 8) {
+        Ok(value) if value <= 0o7777 => Ok(value.into()),
+        _ => Err(format!("Invalid octal number: {text}")),
+    }
+}
+
+fn parse_count(argument: Option<&&str>) -> Result<u64, String> {
+    match argument {
+        None => Ok(1),
+        Some(text) => text.parse().map_err(|_| format!("Invalid count: {text}")),
+    }
+}
+
+fn parse_location(text: &str, symbols: &Symbols) -> Result<Location, String> {
+    if text.eq_ignore_ascii_case("STATE") {
+        return Ok(Location::State);
+    }
+    if let Some(register) = Register::parse(&text.to_ascii_uppercase()) {
+        return Ok(Location::Register(register));
+    }
+    match text.split_once('-') {
+        Some((first, last)) => {
+            let (first, last) = (symbols.parse_address(first)?, symbols.parse_address(last)?);
+            if first > last {
+                return Err(format!("Invalid address range: {text}"));
+            }
+            Ok(Location::Memory(first, last))
+        }
+        None => {
+            let address = symbols.parse_address(text)?;
+            Ok(Location::Memory(address, address))
+        }
+    }
+}
+
+/// Line oriented console debugger for a MachineState.
+pub struct Debugger {
+    pdp5: MachineState,
+    quit: bool,
+}
+impl Debugger {
+    pub fn new(mut pdp5: MachineState) -> Debugger {
+        pdp5.enable_history(DEFAULT_HISTORY);
+        Debugger { pdp5, quit: false }
+    }
+
+    /// Read commands until QUIT or the end of input.
+    pub fn run(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), Error> {
+        while !self.quit {
+            write!(output, "pdp5> ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                break;
+            }
+            match self.execute(&line) {
+                Ok(text) if text.is_empty() => {}
+                Ok(text) => writeln!(output, "{text}")?,
+                Err(message) => writeln!(output, "{message}")?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute a single command line, returning what to show the operator.
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        // Everything but file names is case insensitive.
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(String::new());
+        };
+        let command = command.to_ascii_uppercase();
+        let (switches, arguments): (Vec<&str>, Vec<&str>) =
+            words.partition(|word| word.starts_with('-'));
+        let command = COMMANDS
+            .iter()
+            .find(|(name, minimum)| command.len() >= *minimum && name.starts_with(&command))
+            .map(|(name, _)| *name)
+            .ok_or(format!("Unknown command: {command}"))?;
+        match command {
+            "EXAMINE" => self.examine(
+                &arguments,
+                switches
+                    .iter()
+                    .any(|switch| switch.eq_ignore_ascii_case("-M")),
+            ),
+            "DEPOSIT" => self.deposit(&arguments),
+            "STEP" => self.step(parse_count(arguments.first())?),
+            "CYCLE" => self.cycle(parse_count(arguments.first())?),
+            "CONTINUE" => Ok(self.run_program()),
+            "GO" => {
+                let address = match arguments.first() {
+                    Some(address) => self.pdp5.symbols.parse_address(address)?,
+                    None => self.pdp5.memory[PC_ADDRESS.into()],
+                };
+                self.pdp5.set_initial_start_address(address.into());
+                Ok(self.run_program())
+            }
+            "LOAD" => self.load(arguments.first().ok_or("LOAD needs a file name.")?),
+            "SAVE" => {
+                let path = arguments.first().ok_or("SAVE needs a file name.")?;
+                Snapshot::save_to_file(&self.pdp5, Path::new(path))
+                    .map(|_| String::new())
+                    .map_err(|error| format!("Can't save {path}: {error}"))
+            }
+            "RESTORE" => {
+                let path = arguments.first().ok_or("RESTORE needs a file name.")?;
+                Snapshot::restore_from_file(&mut self.pdp5, Path::new(path))
+                    .map(|_| self.show_pc())
+                    .map_err(|error| format!("Can't restore {path}: {error}"))
+            }
+            "BREAK" => self.set_breakpoint(&switches, &arguments),
+            "NOBREAK" => {
+                self.remove_breakpoint(arguments.first().ok_or("NOBREAK needs a number.")?)
+            }
+            "BACK" => Ok(self.back(parse_count(arguments.first())?)),
+            "REVERSE" => {
+                let stop = match arguments.first() {
+                    Some(address) => {
+                        let address = self.pdp5.symbols.parse_address(address)?;
+                        self.pdp5.reverse_to_write(address)
+                    }
+                    None => self.pdp5.reverse_continue(),
+                };
+                Ok(format!("{}, {}", self.show_stop(stop), self.show_pc()))
+            }
+            "HISTORY" => self.history(arguments.first()),
+            "SYMBOLS" => self.symbols(arguments.first()),
+            "PROTECT" => self.protect(&arguments),
+            "NOPROTECT" => {
+                self.pdp5.memory.clear_protection();
+                self.pdp5.memory.clear_logged_writes();
+                Ok(String::new())
+            }
+            "HELP" => Ok(HELP.to_string()),
+            _ => {
+                self.quit = true;
+                Ok(String::new())
+            }
+        }
+    }
+
+    fn show_register(&self, register: Register) -> String {
+        format!(
+            "{}:\t{:0width$o}",
+            register.name(),
+            self.pdp5.read_register(register),
+            width = register.digits()
+        )
+    }
+
+    fn examine(&self, arguments: &[&str], symbolic: bool) -> Result<String, String> {
+        let target = arguments
+            .first()
+            .ok_or("EXAMINE needs something to examine.")?;
+        let symbols = &self.pdp5.symbols;
+        let lines: Vec<String> = match parse_location(target, symbols)? {
+            Location::Register(register) => vec![self.show_register(register)],
+            Location::State => {
+                let mut lines: Vec<String> = REGISTERS
+                    .iter()
+                    .map(|register| self.show_register(*register))
+                    .collect();
+                lines.push(format!("STATE:\t{}", self.pdp5.state.name()));
+                lines.push(format!("ION:\t{}", self.pdp5.interrupts.enabled as u8));
+                lines
+            }
+            Location::Memory(first, last) => (u16::from(first)..=u16::from(last))
+                .map(|address| {
+                    let word = self.pdp5.memory[address.into()];
+                    let location = symbols.describe(address.into(), 4);
+                    match symbolic {
+                        true => format!(
+                            "{location}:\t{}",
+                            disassemble_with_symbols(address.into(), word, symbols)
+                        ),
+                        false => format!("{location}:\t{word:04o}"),
+                    }
+                })
+                .collect(),
+        };
+        Ok(lines.join("\n"))
+    }
+
+    fn deposit(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let [target, value] = arguments else {
+            return Err("DEPOSIT needs a location and a value.".to_string());
+        };
+        let value = parse_octal(value)?;
+        match parse_location(target, &self.pdp5.symbols)? {
+            Location::Register(register) => self.pdp5.write_register(register, value),
+            Location::Memory(first, last) => {
+                for address in u16::from(first)..=u16::from(last) {
+                    self.pdp5.memory[address.into()] = value;
+                }
+            }
+            Location::State => return Err("Can't deposit into STATE.".to_string()),
+        }
+        Ok(String::new())
+    }
+
+    /// The PC and what is there, shown whenever we stop.
+    fn show_pc(&self) -> String {
+        let pc = self.pdp5.memory[PC_ADDRESS.into()];
+        let instruction = self.pdp5.memory[pc];
+        let symbols = &self.pdp5.symbols;
+        format!(
+            "PC: {} ({})",
+            symbols.describe(pc, 5),
+            disassemble_with_symbols(pc, instruction, symbols)
+        )
+    }
+
+    fn step(&mut self, count: u64) -> Result<String, String> {
+        for _ in 0..count {
+            self.pdp5.run = true;
+            self.pdp5.step_instruction();
+            if let Some(write) = self.pdp5.memory.take_trapped_write() {
+                let stop = self.show_stop(StopReason::Protected(write));
+                return Ok(format!("{stop}, {}", self.show_pc()));
+            }
+            if !self.pdp5.run {
+                return Ok(format!("HALT instruction, {}", self.show_pc()));
+            }
+        }
+        self.pdp5.run = false;
+        Ok(format!("Step expired, {}", self.show_pc()))
+    }
+
+    fn cycle(&mut self, count: u64) -> Result<String, String> {
+        for _ in 0..count {
+            self.pdp5.step_cycle();
+        }
+        Ok(format!(
+            "Cycle expired, next cycle: {}, {}",
+            self.pdp5.state.name(),
+            self.show_pc()
+        ))
+    }
+
+    fn run_program(&mut self) -> String {
+        let stop = self.pdp5.start_program(MAX_CYCLES);
+        self.pdp5.run = false;
+        format!("{}, {}", self.show_stop(stop), self.show_pc())
+    }
+
+    fn back(&mut self, count: u64) -> String {
+        for _ in 0..count {
+            if self.pdp5.step_back().is_none() {
+                return format!(
+                    "{}, {}",
+                    self.show_stop(StopReason::HistoryStart),
+                    self.show_pc()
+                );
+            }
+        }
+        format!("Back expired, {}", self.show_pc())
+    }
+
+    fn history(&mut self, argument: Option<&&str>) -> Result<String, String> {
+        match argument {
+            None => Ok(match &self.pdp5.history {
+                Some(history) => format!("History: {} instructions", history.len()),
+                None => "History is off.".to_string(),
+            }),
+            Some(off) if off.eq_ignore_ascii_case("OFF") => {
+                self.pdp5.disable_history();
+                Ok(String::new())
+            }
+            Some(limit) => {
+                let limit = limit
+                    .parse()
+                    .map_err(|_| format!("Invalid count: {limit}"))?;
+                self.pdp5.enable_history(limit);
+                Ok(String::new())
+            }
+        }
+    }
+
+    fn symbols(&mut self, argument: Option<&&str>) -> Result<String, String> {
+        let Some(path) = argument else {
+            let symbols = &self.pdp5.symbols;
+            if symbols.is_empty() {
+                return Ok("No symbols.".to_string());
+            }
+            let lines: Vec<String> = symbols
+                .iter()
+                .map(|(name, address)| format!("{name}\t{address:04o}"))
+                .collect();
+            return Ok(lines.join("\n"));
+        };
+        let loaded = Symbols::load_from_file(Path::new(path))
+            .map_err(|error| format!("Can't load {path}: {error}"))?;
+        self.pdp5.symbols.extend(&loaded);
+        Ok(format!("{} symbols.", loaded.len()))
+    }
+
+    fn protect(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let (target, protection) = match arguments {
+            [] => return Ok(self.list_protection()),
+            [target] => (target, Protection::Trap),
+            [target, protection] => (target, Protection::parse(protection)?),
+            _ => return Err("PROTECT <address or range> {IGNORE|TRAP|LOG}".to_string()),
+        };
+        let (first, last) = match parse_location(target, &self.pdp5.symbols) {
+            _ if target.eq_ignore_ascii_case("RIM") => (RIM_LOADER.0.into(), RIM_LOADER.1.into()),
+            Ok(Location::Memory(first, last)) => (first, last),
+            Ok(_) => return Err("Only memory can be protected.".to_string()),
+            Err(error) => return Err(error),
+        };
+        let range = ProtectedRange {
+            field: 0,
+            first,
+            last,
+            protection,
+        };
+        self.pdp5.memory.protect(range);
+        Ok(format!("Protected {range}"))
+    }
+
+    fn list_protection(&self) -> String {
+        let memory = &self.pdp5.memory;
+        if memory.protected().is_empty() && memory.logged_writes().is_empty() {
+            return "Nothing is protected.".to_string();
+        }
+        let mut lines: Vec<String> = memory
+            .protected()
+            .iter()
+            .map(|range| format!("Protected {range}"))
+            .collect();
+        lines.extend(
+            memory
+                .logged_writes()
+                .iter()
+                .map(|write| write.describe(&self.pdp5.symbols)),
+        );
+        lines.join("\n")
+    }
+
+    fn set_breakpoint(&mut self, switches: &[&str], arguments: &[&str]) -> Result<String, String> {
+        let switch = |name: &str| {
+            switches
+                .iter()
+                .any(|switch| switch.eq_ignore_ascii_case(name))
+        };
+        let breakpoint = match (switch("-R"), switch("-W"), switch("-I")) {
+            (false, false, false) => match arguments {
+                [] => return Ok(self.list_breakpoints()),
+                [word, condition @ ..] if word.eq_ignore_ascii_case("IF") => {
+                    Breakpoint::Condition(Condition::parse(&condition.concat())?)
+                }
+                [address] => Breakpoint::Execute {
+                    address: self.pdp5.symbols.parse_address(address)?,
+                    condition: None,
+                },
+                [address, word, condition @ ..] if word.eq_ignore_ascii_case("IF") => {
+                    Breakpoint::Execute {
+                        address: self.pdp5.symbols.parse_address(address)?,
+                        condition: Some(Condition::parse(&condition.concat())?),
+                    }
+                }
+                _ => return Err("BREAK <address> {IF <condition>}".to_string()),
+            },
+            (false, false, true) => {
+                let device = arguments.first().ok_or("BREAK -I needs a device code.")?;
+                match u8::from_str_radix(device, 8) {
+                    Ok(device) if device <= 0o77 => Breakpoint::Iot(device),
+                    _ => return Err(format!("Invalid device code: {device}")),
+                }
+            }
+            (read, write, false) => {
+                let target = arguments
+                    .first()
+                    .ok_or("BREAK -R/-W needs something to watch.")?;
+                let (first, last) = match parse_location(target, &self.pdp5.symbols)? {
+                    Location::Memory(first, last) => (first, last),
+                    Location::Register(Register::Pc) => (PC_ADDRESS.into(), PC_ADDRESS.into()),
+                    _ => return Err("Only memory and the PC can be watched.".to_string()),
+                };
+                let watch = match (read, write) {
+                    (true, true) => Watch::ReadWrite,
+                    (true, false) => Watch::Read,
+                    _ => Watch::Write,
+                };
+                Breakpoint::Watch { watch, first, last }
+            }
+            _ => return Err("BREAK -I can't be combined with -R or -W.".to_string()),
+        };
+        let id = self.pdp5.breakpoints.add(breakpoint);
+        Ok(format!("Breakpoint {id}: {breakpoint}"))
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.pdp5.breakpoints.is_empty() {
+            return "No breakpoints.".to_string();
+        }
+        let lines: Vec<String> = self
+            .pdp5
+            .breakpoints
+            .iter()
+            .map(|(id, breakpoint)| format!("{id}:\t{breakpoint}"))
+            .collect();
+        lines.join("\n")
+    }
+
+    fn remove_breakpoint(&mut self, which: &str) -> Result<String, String> {
+        if which.eq_ignore_ascii_case("ALL") {
+            self.pdp5.breakpoints.clear();
+            return Ok(String::new());
+        }
+        let id = which
+            .parse()
+            .map_err(|_| format!("Invalid breakpoint number: {which}"))?;
+        match self.pdp5.breakpoints.remove(id) {
+            true => Ok(String::new()),
+            false => Err(format!("No breakpoint {id}.")),
+        }
+    }
+
+    fn load(&mut self, path: &str) -> Result<String, String> {
+        // The tape only sets the locations it has on it, everything else stays as it was.
+        let mut buf: [u12; 4096] = [0.into(); 4096];
+        for (address, word) in buf.iter_mut().enumerate() {
+            *word = self.pdp5.memory[address.into()];
+        }
+        RimFormat::load_from_file(Path::new(path), &mut buf)
+            .map_err(|error| format!("Can't load {path}: {error}"))?;
+        for (address, word) in buf.iter().enumerate() {
+            self.pdp5.memory[address.into()] = *word;
+        }
+        Ok(String::new())
+    }
+
+    /// Why we stopped, the PC follows this.
+    fn show_stop(&self, stop: StopReason) -> String {
+        let symbols = &self.pdp5.symbols;
+        match stop {
+            StopReason::Halted => "HALT instruction".to_string(),
+            StopReason::CycleLimit => "Cycle limit reached".to_string(),
+            StopReason::Breakpoint(hit) => self.show_breakpoint_hit(&hit),
+            StopReason::HistoryStart => "Start of history".to_string(),
+            StopReason::LastWrite(access) => format!(
+                "Last write {:04o} (was {:04o}) at {}",
+                access.value,
+                access.previous,
+                symbols.describe(access.address, 4)
+            ),
+            StopReason::Protected(write) => write.describe(symbols),
+        }
+    }
+
+    /// Why a breakpoint went off.
+    fn show_breakpoint_hit(&self, hit: &BreakpointHit) -> String {
+        let symbols = &self.pdp5.symbols;
+        match (hit.breakpoint, hit.access) {
+            (Breakpoint::Watch { .. }, Some(access)) => {
+                let what = match access.kind {
+                    MemoryAccessKind::Read => format!("read {:04o}", access.value),
+                    MemoryAccessKind::Write => {
+                        format!("write {:04o} (was {:04o})", access.value, access.previous)
+                    }
+                };
+                format!(
+                    "Watchpoint {}, {what} at {} by {}",
+                    hit.id,
+                    symbols.describe(access.address, 4),
+                    symbols.describe(hit.pc, 5)
+                )
+            }
+            (Breakpoint::Iot(device), _) => {
+                format!("Breakpoint {}, IOT device {device:02o}", hit.id)
+            }
+            (Breakpoint::Condition(condition), _) => format!("Breakpoint {}, {condition}", hit.id),
+            _ => format!("Breakpoint {}", hit.id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debugger() -> Debugger {
+        Debugger::new(MachineState::default([0.into(); 4096]))
+    }
+
+    #[test]
+    fn test_deposit_and_examine() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.execute("d 200-202 7001"), Ok(String::new()));
+        assert_eq!(debugger.execute("dep 203 7402"), Ok(String::new()));
+        assert_eq!(
+            debugger.execute("e 201-203"),
+            Ok("0201:\t7001\n0202:\t7001\n0203:\t7402".to_string())
+        );
+        assert_eq!(debugger.execute("ex -m 203"), Ok("0203:\tHLT".to_string()));
+        assert_eq!(debugger.execute("d sr 5252"), Ok(String::new()));
+        assert_eq!(debugger.execute("e sr"), Ok("SR:\t5252".to_string()));
+        assert_eq!(debugger.execute("d l 1"), Ok(String::new()));
+        assert_eq!(debugger.execute("e L"), Ok("L:\t1".to_string()));
+    }
+
+    #[test]
+    fn test_step_and_continue() {
+        let mut debugger = debugger();
+        debugger.execute("d 200-202 7001").unwrap();
+        debugger.execute("d 203 7402").unwrap();
+        debugger.execute("d pc 200").unwrap();
+        assert_eq!(
+            debugger.execute("s"),
+            Ok("Step expired, PC: 00201 (IAC)".to_string())
+        );
+        assert_eq!(debugger.execute("e ac"), Ok("AC:\t0001".to_string()));
+        assert_eq!(
+            debugger.execute("cont"),
+            Ok("HALT instruction, PC: 00204 (AND 0000)".to_string())
+        );
+        assert_eq!(debugger.execute("e ac"), Ok("AC:\t0003".to_string()));
+        assert_eq!(
+            debugger.execute("go 202"),
+            Ok("HALT instruction, PC: 00204 (AND 0000)".to_string())
+        );
+        assert_eq!(debugger.execute("e ac"), Ok("AC:\t0004".to_string()));
+    }
+
+    #[test]
+    fn test_cycle() {
+        let mut debugger = debugger();
+        // TAD I 210
+        debugger.execute("d 200 1610").unwrap();
+        debugger.execute("d 210 300").unwrap();
+        debugger.execute("d pc 200").unwrap();
+        assert_eq!(
+            debugger.execute("cy"),
+            Ok("Cycle expired, next cycle: D, PC: 00200 (TAD I 0210)".to_string())
+        );
+        assert_eq!(
+            debugger.execute("cycle 2"),
+            Ok("Cycle expired, next cycle: P, PC: 00200 (TAD I 0210)".to_string())
+        );
+        // STEP finishes the instruction.
+        assert_eq!(
+            debugger.execute("step"),
+            Ok("Step expired, PC: 00201 (AND 0000)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_load_and_errors() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.execute("load example_code/binhalt-pm/binhalt-pm"),
+            Ok(String::new())
+        );
+        assert_eq!(
+            debugger.execute("e -m 7600"),
+            Ok("7600:\tTAD 7611".to_string())
+        );
+        assert!(debugger.execute("frobnicate").is_err());
+        assert!(debugger.execute("e 8000").is_err());
+        assert!(debugger.execute("d ac").is_err());
+    }
+
+    #[test]
+    fn test_load_a_bad_tape() {
+        let path = std::env::temp_dir().join(format!("pdp5_debugger_{}.bin", std::process::id()));
+        let tape = crate::bin_format_reader::BinFormat::punch(&[(0o200, &[0o7001, 0o7001])]);
+        std::fs::write(&path, tape).unwrap();
+        let mut debugger = debugger();
+        debugger.execute("d 200 7402").unwrap();
+        let error = debugger
+            .execute(&format!("lo {}", path.display()))
+            .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.contains("Not a RIM tape"), "{error}");
+        // Nothing from the tape went in and the session carries on.
+        assert_eq!(debugger.execute("e 200"), Ok("0200:\t7402".to_string()));
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let path = std::env::temp_dir().join(format!("pdp5_debugger_{}.snap", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut debugger = debugger();
+        debugger.execute("d 200 7001").unwrap(); // IAC
+        debugger.execute("d pc 200").unwrap();
+        debugger.execute("s").unwrap();
+        assert_eq!(debugger.execute(&format!("sa {path}")), Ok(String::new()));
+        debugger.execute("s").unwrap();
+        debugger.execute("d 200 0").unwrap();
+        assert!(debugger.execute(&format!("res {path}")).is_ok());
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(debugger.execute("e ac"), Ok("AC:\t0001".to_string()));
+        assert_eq!(debugger.execute("e 200"), Ok("0200:\t7001".to_string()));
+        assert!(debugger.execute(&format!("res {path}")).is_err());
+    }
+
+    #[test]
+    fn test_run_reads_until_quit() {
+        let mut debugger = debugger();
+        let mut input = "d ac 17\ne ac\nquit\ne ac\n".as_bytes();
+        let mut output = Vec::new();
+        debugger.run(&mut input, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "pdp5> pdp5> AC:\t0017\npdp5> "
+        );
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger();
+        debugger.execute("d 200 7001").unwrap(); // IAC
+        debugger.execute("d 201 3010").unwrap(); // DCA 10
+        debugger.execute("d 202 6046").unwrap(); // TLS
+        debugger.execute("d 203 5200").unwrap(); // JMP 200
+        debugger.execute("d pc 200").unwrap();
+        assert_eq!(
+            debugger.execute("br 201 if ac=1"),
+            Ok("Breakpoint 1: 0201 if AC==1".to_string())
+        );
+        assert_eq!(
+            debugger.execute("break -w 10-17"),
+            Ok("Breakpoint 2: write 0010-0017".to_string())
+        );
+        assert_eq!(
+            debugger.execute("br -i 4"),
+            Ok("Breakpoint 3: IOT device 04".to_string())
+        );
+        assert_eq!(
+            debugger.execute("c"),
+            Ok("Breakpoint 1, PC: 00201 (DCA 0010)".to_string())
+        );
+        assert_eq!(
+            debugger.execute("c"),
+            Ok("Watchpoint 2, write 0001 (was 0000) at 0010 by 00201, PC: 00202 (TLS)".to_string())
+        );
+        assert_eq!(
+            debugger.execute("c"),
+            Ok("Breakpoint 3, IOT device 04, PC: 00202 (TLS)".to_string())
+        );
+        assert_eq!(debugger.execute("nob 2"), Ok(String::new()));
+        assert_eq!(debugger.execute("nob 3"), Ok(String::new()));
+        assert_eq!(
+            debugger.execute("c"),
+            Ok("Breakpoint 1, PC: 00201 (DCA 0010)".to_string())
+        );
+        assert_eq!(debugger.execute("br"), Ok("1:\t0201 if AC==1".to_string()));
+        assert!(debugger.execute("nob 2").is_err());
+        assert_eq!(debugger.execute("nob all"), Ok(String::new()));
+        assert_eq!(
+            debugger.execute("br if l = 0"),
+            Ok("Breakpoint 4: if L==0".to_string())
+        );
+        assert_eq!(
+            debugger.execute("c"),
+            Ok("Breakpoint 4, L==0, PC: 00202 (TLS)".to_string())
+        );
+        assert!(debugger.execute("br -r ac").is_err());
+    }
+
+    #[test]
+    fn test_protect() {
+        let mut debugger = debugger();
+        assert_eq!(
+            debugger.execute("pr"),
+            Ok("Nothing is protected.".to_string())
+        );
+        assert_eq!(
+            debugger.execute("pr rim"),
+            Ok("Protected 7756-7775 trap".to_string())
+        );
+        assert_eq!(
+            debugger.execute("protect 300-301 log"),
+            Ok("Protected 0300-0301 log".to_string())
+        );
+        debugger.execute("pr pc").unwrap_err();
+        debugger.execute("pr 300 shout").unwrap_err();
+        debugger.execute("d 200 7240").unwrap(); // CLA CMA
+        debugger.execute("d 201 3300").unwrap(); // DCA 300
+        debugger.execute("d 202 3610").unwrap(); // DCA I 210
+        debugger.execute("d 203 7402").unwrap(); // HLT
+        debugger.execute("d 210 7760").unwrap();
+        debugger.execute("d 7760 1234").unwrap(); // The console still gets in.
+        debugger.execute("d pc 200").unwrap();
+        assert_eq!(
+            debugger.execute("c"),
+            Ok("Write of 0000 to protected 7760 by 0202, PC: 00203 (HLT)".to_string())
+        );
+        assert_eq!(
+            debugger.execute("pr"),
+            Ok("Protected 7756-7775 trap\n\
+                Protected 0300-0301 log\n\
+                Write of 7777 to protected 0300 by 0201"
+                .to_string())
+        );
+        assert_eq!(debugger.execute("e 7760"), Ok("7760:\t1234".to_string()));
+        // Stepping stops too.
+        debugger.execute("d pc 202").unwrap();
+        assert_eq!(
+            debugger.execute("s 5"),
+            Ok("Write of 0000 to protected 7760 by 0202, PC: 00203 (HLT)".to_string())
+        );
+        debugger.execute("nop").unwrap();
+        assert_eq!(
+            debugger.execute("pr"),
+            Ok("Nothing is protected.".to_string())
+        );
+        debugger.execute("d pc 202").unwrap();
+        debugger.execute("s").unwrap();
+        assert_eq!(debugger.execute("e 7760"), Ok("7760:\t0000".to_string()));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let path = std::env::temp_dir().join(format!("pdp5_debugger_{}.sym", std::process::id()));
+        std::fs::write(
+            &path,
+            "START=0200 LOOP=0201
+COUNT=0300
+",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let mut debugger = debugger();
+        assert_eq!(debugger.execute("sy"), Ok("No symbols.".to_string()));
+        assert_eq!(
+            debugger.execute(&format!("symbols {path}")),
+            Ok("3 symbols.".to_string())
+        );
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            debugger.execute("sy"),
+            Ok("START\t0200\nLOOP\t0201\nCOUNT\t0300".to_string())
+        );
+        debugger.execute("d start 7001").unwrap(); // IAC
+        debugger.execute("d loop 2300").unwrap(); // ISZ 300
+        debugger.execute("d loop+1 5201").unwrap(); // JMP 201
+        assert_eq!(
+            debugger.execute("e -m start-loop+1"),
+            Ok("0200 <START>:\tIAC\n0201 <LOOP>:\tISZ COUNT\n0202 <LOOP+1>:\tJMP LOOP".to_string())
+        );
+        debugger.execute("d pc start").unwrap_err(); // Values are numbers.
+        debugger.execute("d pc 200").unwrap();
+        assert_eq!(
+            debugger.execute("br loop+1"),
+            Ok("Breakpoint 1: 0202".to_string())
+        );
+        assert_eq!(
+            debugger.execute("c"),
+            Ok("Breakpoint 1, PC: 00202 <LOOP+1> (JMP LOOP)".to_string())
+        );
+        debugger.execute("nob all").unwrap();
+        debugger.execute("br -w count").unwrap();
+        assert_eq!(
+            debugger.execute("c"),
+            Ok(
+                "Watchpoint 2, write 0002 (was 0001) at 0300 <COUNT> by 00201 <LOOP>, \
+                PC: 00202 <LOOP+1> (JMP LOOP)"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            debugger.execute("e nowhere"),
+            Err("Unknown symbol: nowhere".to_string())
+        );
+    }
+
+    #[test]
+    fn test_going_backwards() {
+        let mut debugger = debugger();
+        debugger.execute("d 200 2300").unwrap(); // ISZ 300
+        debugger.execute("d 201 7001").unwrap(); // IAC
+        debugger.execute("d 202 5200").unwrap(); // JMP 200
+        debugger.execute("d pc 200").unwrap();
+        debugger.execute("s 9").unwrap();
+        assert_eq!(debugger.execute("e 300"), Ok("0300:\t0003".to_string()));
+        assert_eq!(
+            debugger.execute("back 2"),
+            Ok("Back expired, PC: 00201 (IAC)".to_string())
+        );
+        assert_eq!(debugger.execute("e ac"), Ok("AC:\t0002".to_string()));
+        assert_eq!(
+            debugger.execute("rev 300"),
+            Ok("Last write 0003 (was 0002) at 0300, PC: 00200 (ISZ 0300)".to_string())
+        );
+        debugger.execute("br 202").unwrap();
+        assert_eq!(
+            debugger.execute("rev"),
+            Ok("Breakpoint 1, PC: 00202 (JMP 0200)".to_string())
+        );
+        assert_eq!(debugger.execute("e 300"), Ok("0300:\t0002".to_string()));
+        debugger.execute("nob all").unwrap();
+        assert_eq!(
+            debugger.execute("reverse"),
+            Ok("Start of history, PC: 00200 (ISZ 0300)".to_string())
+        );
+        assert_eq!(
+            debugger.execute("hi"),
+            Ok("History: 0 instructions".to_string())
+        );
+        debugger.execute("history off").unwrap();
+        assert_eq!(debugger.execute("hi"), Ok("History is off.".to_string()));
+    }
+}
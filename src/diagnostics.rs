@@ -0,0 +1,361 @@
+use crate::bin_format_reader::BinFormat;
+use crate::breakpoints::Breakpoint;
+use crate::consts::*;
+use crate::rim_format_reader::RimFormat;
+use crate::unsigned_integer_12::u12;
+use crate::{MachineState, StopReason};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+// MAINDEC style diagnostics run as an acceptance suite. A directory of RIM or BIN tapes has
+// a diagnostics.txt saying how to run each of them, taken from the diagnostic's write up:
+//
+//     # tape            start   switches  end of pass  passes  cycle budget
+//     maindec-5-d1a.bin start=0200 sr=4000 pass=0241 passes=2 cycles=50000000
+//
+// Addresses and switches are octal, passes and cycles decimal. passes defaults to 1 and
+// cycles to about a minute of PDP5 time. Tapes ending .bin are BIN format and anything else
+// RIM, format=rim or format=bin says otherwise. A diagnostic passes when it has got to its end of
+// pass address that many times. Halting anywhere is an error halt, the diagnostics halt with
+// the failing result in the AC, so we report where it halted and what was in the AC.
+
+pub const MANIFEST: &str = "diagnostics.txt";
+const DEFAULT_CYCLES: u64 = 60_000_000_000 / CYCLE_TIME_NS as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeFormat {
+    Rim,
+    Bin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub name: String,
+    pub tape: PathBuf,
+    pub format: TapeFormat,
+    pub start: u12,
+    pub switches: u12,
+    pub pass: u12, // Reaching this address is the end of a pass.
+    pub passes: u32,
+    pub max_cycles: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Passed {
+        cycles: u64,
+    },
+    /// Halted at pc, which is the address of the HLT rather than the one after it.
+    Failed {
+        pc: u12,
+        ac: u12,
+    },
+    /// Used up its cycles without finishing its passes, wherever it had got to.
+    TimedOut {
+        pc: u12,
+        ac: u12,
+    },
+}
+
+pub struct DiagnosticResult {
+    pub name: String,
+    pub verdict: Verdict,
+}
+impl DiagnosticResult {
+    pub fn passed(&self) -> bool {
+        matches!(self.verdict, Verdict::Passed { .. })
+    }
+}
+impl Display for DiagnosticResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.verdict {
+            Verdict::Passed { cycles } => write!(f, "{}: passed in {cycles} cycles", self.name),
+            Verdict::Failed { pc, ac } => {
+                write!(
+                    f,
+                    "{}: FAILED, halted at {pc:04o} with AC {ac:04o}",
+                    self.name
+                )
+            }
+            Verdict::TimedOut { pc, ac } => write!(
+                f,
+                "{}: FAILED, still running at {pc:04o} with AC {ac:04o}",
+                self.name
+            ),
+        }
+    }
+}
+
+fn octal(text: &str) -> Option<u12> {
+    match u16::from_str_radix(text, 8) {
+        Ok(value) if value <= 0o7777 => Some(value.into()),
+        _ => None,
+    }
+}
+
+impl Diagnostic {
+    /// A line of the manifest, the tape being relative to directory.
+    pub fn parse(line: &str, directory: &Path) -> Result<Diagnostic, String> {
+        let mut words = line.split_whitespace();
+        let tape = words.next().ok_or("No tape given.")?;
+        let bin = Path::new(tape)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("bin"));
+        let mut diagnostic = Diagnostic {
+            name: Path::new(tape)
+                .file_stem()
+                .map_or(tape.to_string(), |stem| stem.to_string_lossy().into_owned()),
+            tape: directory.join(tape),
+            format: match bin {
+                true => TapeFormat::Bin,
+                false => TapeFormat::Rim,
+            },
+            start: 0o200.into(),
+            switches: 0.into(),
+            pass: 0.into(),
+            passes: 1,
+            max_cycles: DEFAULT_CYCLES,
+        };
+        let mut pass = None;
+        for word in words {
+            let invalid = || format!("{tape}: invalid setting {word}");
+            let (key, value) = word.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "start" => diagnostic.start = octal(value).ok_or_else(invalid)?,
+                "sr" => diagnostic.switches = octal(value).ok_or_else(invalid)?,
+                "pass" => pass = Some(octal(value).ok_or_else(invalid)?),
+                "passes" => diagnostic.passes = value.parse().map_err(|_| invalid())?,
+                "cycles" => diagnostic.max_cycles = value.parse().map_err(|_| invalid())?,
+                "format" => {
+                    diagnostic.format = match value {
+                        "rim" => TapeFormat::Rim,
+                        "bin" => TapeFormat::Bin,
+                        _ => return Err(invalid()),
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        diagnostic.pass =
+            pass.ok_or_else(|| format!("{tape}: needs pass=<end of pass address>"))?;
+        Ok(diagnostic)
+    }
+
+    /// Loads the tape into a fresh machine, sets the switches and starts it.
+    pub fn run(&self) -> Result<Verdict, Error> {
+        let mut buf: [u12; 4096] = [0.into(); 4096];
+        match self.format {
+            TapeFormat::Rim => RimFormat::load_from_file(&self.tape, &mut buf)?,
+            TapeFormat::Bin => BinFormat::load_from_file(&self.tape, &mut buf)?,
+        };
+        let mut pdp5 = MachineState::default(buf);
+        pdp5.registers.hardware_registers.SR = self.switches;
+        pdp5.set_initial_start_address(self.start.into());
+        pdp5.breakpoints.add(Breakpoint::Execute {
+            address: self.pass,
+            condition: None,
+        });
+        let mut passes = 0;
+        while passes < self.passes {
+            let remaining = self.max_cycles.saturating_sub(pdp5.cycles);
+            let stop = pdp5.start_program(remaining);
+            let ac = pdp5.registers.hardware_registers.AC;
+            match stop {
+                StopReason::Breakpoint(_) => passes += 1,
+                StopReason::Halted => {
+                    let pc = pdp5.memory[PC_ADDRESS.into()] - 1.into();
+                    return Ok(Verdict::Failed { pc, ac });
+                }
+                StopReason::CycleLimit => {
+                    return Ok(Verdict::TimedOut {
+                        pc: pdp5.memory[PC_ADDRESS.into()],
+                        ac,
+                    });
+                }
+                StopReason::HistoryStart | StopReason::LastWrite(_) => {
+                    unreachable!("Diagnostics only run forwards.")
+                }
+                StopReason::Protected(_) => unreachable!("Diagnostics protect nothing."),
+            }
+        }
+        Ok(Verdict::Passed {
+            cycles: pdp5.cycles,
+        })
+    }
+}
+
+/// Everything in the directory's manifest, in the order it lists them.
+pub fn load_manifest(directory: &Path) -> Result<Vec<Diagnostic>, Error> {
+    let text = fs::read_to_string(directory.join(MANIFEST))?;
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            Diagnostic::parse(line, directory)
+                .map_err(|message| Error::new(ErrorKind::InvalidData, message))
+        })
+        .collect()
+}
+
+/// Runs every diagnostic in the directory. A tape that can't be read is an error
+/// rather than a failure, the CPU hasn't been tested.
+pub fn run_directory(directory: &Path) -> Result<Vec<DiagnosticResult>, Error> {
+    load_manifest(directory)?
+        .into_iter()
+        .map(|diagnostic| {
+            Ok(DiagnosticResult {
+                verdict: diagnostic.run()?,
+                name: diagnostic.name,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A RIM tape of the words, with leader and trailer.
+    fn rim(words: &[(u16, u16)]) -> Vec<u8> {
+        let mut tape = vec![0o200; 16];
+        for (address, word) in words {
+            tape.extend([
+                0o100 | (address >> 6) as u8,
+                (address & 0o77) as u8,
+                (word >> 6) as u8,
+                (word & 0o77) as u8,
+            ]);
+        }
+        tape.extend([0o200; 16]);
+        tape
+    }
+
+    fn directory(name: &str, manifest: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("pdp5_diagnostics_{name}_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        // Counts up from 1 and error halts if it ever sees 0 before wrapping.
+        let counter = rim(&[
+            (0o200, 0o7300), // CLA CLL
+            (0o201, 0o7001), // IAC
+            (0o202, 0o7450), // SNA
+            (0o203, 0o7402), // HLT, error
+            (0o204, 0o5200), // JMP 200, end of pass
+        ]);
+        fs::write(directory.join("counter.rim"), counter).unwrap();
+        // Error halts with the switches in the AC unless they are all down.
+        let switches = rim(&[
+            (0o200, 0o7604), // LAS
+            (0o201, 0o7440), // SZA
+            (0o202, 0o7402), // HLT, error
+            (0o203, 0o5200), // JMP 200, end of pass
+        ]);
+        fs::write(directory.join("switches.rim"), switches).unwrap();
+        // The counter again as a BIN tape.
+        let counter = BinFormat::punch(&[(0o200, &[0o7300, 0o7001, 0o7450, 0o7402, 0o5200])]);
+        fs::write(directory.join("counter.bin"), counter).unwrap();
+        fs::write(directory.join(MANIFEST), manifest).unwrap();
+        directory
+    }
+
+    #[test]
+    fn test_parse() {
+        let directory = Path::new("tapes");
+        let diagnostic = Diagnostic::parse(
+            "d1a.rim start=7600 sr=4000 pass=0241 passes=2 cycles=99",
+            directory,
+        )
+        .unwrap();
+        assert_eq!(diagnostic.name, "d1a");
+        assert_eq!(diagnostic.tape, directory.join("d1a.rim"));
+        assert_eq!(diagnostic.start, 0o7600.into());
+        assert_eq!(diagnostic.switches, 0o4000.into());
+        assert_eq!(diagnostic.pass, 0o241.into());
+        assert_eq!(diagnostic.passes, 2);
+        assert_eq!(diagnostic.max_cycles, 99);
+        assert_eq!(diagnostic.format, TapeFormat::Rim);
+        let bin = |line| Diagnostic::parse(line, directory).unwrap().format;
+        assert_eq!(bin("d1a.BIN pass=200"), TapeFormat::Bin);
+        assert_eq!(bin("d1a.pt pass=200 format=bin"), TapeFormat::Bin);
+        assert_eq!(bin("d1a.bin pass=200 format=rim"), TapeFormat::Rim);
+        assert!(Diagnostic::parse("d1a.rim pass=200 format=hex", directory).is_err());
+        assert!(Diagnostic::parse("d1a.rim", directory).is_err());
+        assert!(Diagnostic::parse("d1a.rim pass=9", directory).is_err());
+        assert!(Diagnostic::parse("d1a.rim pass=200 speed=1", directory).is_err());
+    }
+
+    #[test]
+    fn test_pass_and_fail() {
+        let path = directory(
+            "pass_and_fail",
+            "# A comment\n\
+             counter.rim pass=0204 passes=3\n\
+             switches.rim sr=0000 pass=0203 passes=5\n\
+             switches.rim sr=0017 pass=0203\n\
+             counter.rim pass=0300 cycles=1000\n",
+        );
+        let results = run_directory(&path).unwrap();
+        fs::remove_dir_all(&path).unwrap();
+        assert_eq!(results.len(), 4);
+        assert!(results[0].passed());
+        assert!(results[1].passed());
+        assert_eq!(
+            results[2].verdict,
+            Verdict::Failed {
+                pc: 0o202.into(),
+                ac: 0o17.into()
+            }
+        );
+        assert_eq!(
+            results[2].to_string(),
+            "switches: FAILED, halted at 0202 with AC 0017"
+        );
+        assert!(matches!(results[3].verdict, Verdict::TimedOut { .. }));
+    }
+
+    #[test]
+    fn test_missing_tape_is_an_error() {
+        let path = directory("missing", "nothing.rim pass=0200\n");
+        assert!(run_directory(&path).is_err());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bin_tapes() {
+        let path = directory("bin", "counter.bin pass=0204 passes=2\n");
+        let results = run_directory(&path).unwrap();
+        assert!(results[0].passed());
+        // Read as RIM it is an error rather than a crash or a failure.
+        fs::write(path.join(MANIFEST), "counter.bin pass=0204 format=rim\n").unwrap();
+        let error = run_directory(&path).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_long_rim_tape() {
+        // Every word of memory on the tape, about 16K of it.
+        let mut words: Vec<(u16, u16)> = (0..0o10000).map(|address| (address, 0o7000)).collect();
+        words[0o200..0o203].copy_from_slice(&[(0o200, 0o7001), (0o201, 0o7450), (0o202, 0o7402)]);
+        words[0o204] = (0o204, 0o5200);
+        let path = directory("long_rim", "long.rim pass=0204\n");
+        fs::write(path.join("long.rim"), rim(&words)).unwrap();
+        let results = run_directory(&path).unwrap();
+        fs::remove_dir_all(&path).unwrap();
+        assert!(results[0].passed());
+    }
+
+    /// Real MAINDEC tapes, when there is a directory of them to hand.
+    #[test]
+    #[ignore = "needs MAINDEC tapes and a diagnostics.txt in PDP5_MAINDEC_DIR"]
+    fn test_maindec_directory() {
+        let directory = std::env::var("PDP5_MAINDEC_DIR").expect("PDP5_MAINDEC_DIR isn't set.");
+        let results = run_directory(Path::new(&directory)).unwrap();
+        for result in &results {
+            println!("{result}");
+        }
+        assert!(results.iter().all(DiagnosticResult::passed));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::instruction::OpCode;
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+use crate::unsigned_integer_12::u12;
+
+// IOTs we know the names of, the rest come out as IOT 6xxx.
+const IOT_MNEMONICS: [(u16, &str); 24] = [
+    (0o6001, "ION"),
+    (0o6002, "IOF"),
+    (0o6011, "RSF"),
+    (0o6012, "RRB"),
+    (0o6014, "RFC"),
+    (0o6021, "PSF"),
+    (0o6022, "PCF"),
+    (0o6024, "PPC"),
+    (0o6026, "PLS"),
+    (0o6031, "KSF"),
+    (0o6032, "KCC"),
+    (0o6034, "KRS"),
+    (0o6036, "KRB"),
+    (0o6041, "TSF"),
+    (0o6042, "TCF"),
+    (0o6044, "TPC"),
+    (0o6046, "TLS"),
+    (0o6652, "LCB"),
+    (0o6654, "LLB"),
+    (0o6656, "LCB LLB"),
+    (0o6661, "LSF"),
+    (0o6662, "LCF"),
+    (0o6664, "LPR"),
+    (0o6666, "LCF LPR"),
+];
+
+const GROUP_1_MNEMONICS: [(u16, &str); 7] = [
+    (0o0200, "CLA"),
+    (0o0100, "CLL"),
+    (0o0040, "CMA"),
+    (0o0020, "CML"),
+    (0o0001, "IAC"),
+    (0o0010, "RAR"),
+    (0o0004, "RAL"),
+];
+const ROTATE_TWICE: u16 = 0o0002;
+
+/// Turn an instruction back into PAL style assembly, operands that have names shown
+/// by name. The address the instruction lives at is needed to work out current page addresses.
+pub fn disassemble_with_symbols(address: u12, instruction: u12, symbols: &Symbols) -> String {
+    let word = u16::from(instruction);
+    match OpCode::from_instruction(instruction) {
+        OpCode::Operate => disassemble_operate(word),
+        OpCode::Iot => match IOT_MNEMONICS.iter().find(|(code, _)| *code == word) {
+            Some((_, mnemonic)) => mnemonic.to_string(),
+            None => format!("IOT {word:04o}"),
+        },
+        opcode => {
+            let mnemonic = match opcode {
+                OpCode::And => "AND",
+                OpCode::Tad => "TAD",
+                OpCode::Isz => "ISZ",
+                OpCode::Dca => "DCA",
+                OpCode::Jms => "JMS",
+                _ => "JMP",
+            };
+            let offset = word & 0o177;
+            let target = match Memory::get_addressing_page_0(instruction) {
+                true => offset,
+                false => (u16::from(address) & 0o7600) | offset,
+            };
+            let target = symbols.operand(target.into());
+            match Memory::get_indirect_addressing(instruction) {
+                true => format!("{mnemonic} I {target}"),
+                false => format!("{mnemonic} {target}"),
+            }
+        }
+    }
+}
+
+fn disassemble_operate(word: u16) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    if word & 0o0400 == 0 {
+        for (bit, mnemonic) in GROUP_1_MNEMONICS {
+            if word & bit != 0 {
+                parts.push(match (mnemonic, word & ROTATE_TWICE != 0) {
+                    ("RAR", true) => "RTR",
+                    ("RAL", true) => "RTL",
+                    _ => mnemonic,
+                });
+            }
+        }
+        if word & ROTATE_TWICE != 0 && word & 0o0014 == 0 {
+            // Rotate twice with nothing to rotate.
+            return format!("OPR {word:04o}");
+        }
+    } else {
+        let reverse = word & 0o0010 != 0;
+        let skips = [
+            (0o0100, "SMA", "SPA"),
+            (0o0040, "SZA", "SNA"),
+            (0o0020, "SNL", "SZL"),
+        ];
+        for (bit, normal, reversed) in skips {
+            if word & bit != 0 {
+                parts.push(if reverse { reversed } else { normal });
+            }
+        }
+        if reverse && word & 0o0160 == 0 {
+            parts.push("SKP");
+        }
+        for (bit, mnemonic) in [(0o0200, "CLA"), (0o0004, "OSR"), (0o0002, "HLT")] {
+            if word & bit != 0 {
+                parts.push(mnemonic);
+            }
+        }
+    }
+    match parts.is_empty() {
+        true => "NOP".to_string(),
+        false => parts.join(" "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dis(address: u16, instruction: u16) -> String {
+        disassemble_with_symbols(address.into(), instruction.into(), &Symbols::default())
+    }
+
+    #[test]
+    fn test_memory_reference() {
+        assert_eq!(dis(0o7600, 0o1211), "TAD 7611");
+        assert_eq!(dis(0o7604, 0o3613), "DCA I 7613");
+        assert_eq!(dis(0o7604, 0o3413), "DCA I 0013");
+        assert_eq!(dis(0o0200, 0o5200), "JMP 0200");
+        assert_eq!(dis(0o0200, 0o4020), "JMS 0020");
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbols = Symbols::parse("COUNT=7611 PTR=0013").unwrap();
+        let dis = |address: u16, instruction: u16| {
+            disassemble_with_symbols(address.into(), instruction.into(), &symbols)
+        };
+        assert_eq!(dis(0o7600, 0o1211), "TAD COUNT");
+        assert_eq!(dis(0o7604, 0o3413), "DCA I PTR");
+        assert_eq!(dis(0o0200, 0o5200), "JMP 0200");
+    }
+
+    #[test]
+    fn test_operate() {
+        assert_eq!(dis(0, 0o7000), "NOP");
+        assert_eq!(dis(0, 0o7402), "HLT");
+        assert_eq!(dis(0, 0o7300), "CLA CLL");
+        assert_eq!(dis(0, 0o7041), "CMA IAC");
+        assert_eq!(dis(0, 0o7012), "RTR");
+        assert_eq!(dis(0, 0o7106), "CLL RTL");
+        assert_eq!(dis(0, 0o7410), "SKP");
+        assert_eq!(dis(0, 0o7450), "SNA");
+        assert_eq!(dis(0, 0o7640), "SZA CLA");
+        assert_eq!(dis(0, 0o7604), "CLA OSR");
+    }
+
+    #[test]
+    fn test_iot() {
+        assert_eq!(dis(0, 0o6001), "ION");
+        assert_eq!(dis(0, 0o6046), "TLS");
+        assert_eq!(dis(0, 0o6661), "LSF");
+        assert_eq!(dis(0, 0o6123), "IOT 6123");
+    }
+}
@@ -0,0 +1,117 @@
+// Whole programs off paper tape: load the tape, start at its documented address and run
+// to a HLT within a cycle budget, then look at what it left in memory and the registers.
+// Teleprinter output is caught so programs that print can be checked too.
+
+use crate::iot_bus::{IotDevice, IotResponse};
+use crate::rim_format_reader::RimFormat;
+use crate::scheduler::Events;
+use crate::unsigned_integer_12::u12;
+use crate::{MachineState, StopReason};
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+// Teleprinter output, device 04:
+//   6041 TSF  Skip if the flag is set.
+//   6042 TCF  Clear the flag.
+//   6044 TPC  Print the character in AC bits 5-11.
+// Ours is always ready again by the time the program looks.
+const TELEPRINTER_DEVICE: u8 = 0o04;
+
+struct Teleprinter {
+    printed: Rc<RefCell<String>>,
+}
+impl IotDevice for Teleprinter {
+    fn device_codes(&self) -> &[u8] {
+        &[TELEPRINTER_DEVICE]
+    }
+
+    fn pulse(&mut self, _device_code: u8, pulse: u8, ac: u12, _events: &mut Events) -> IotResponse {
+        match pulse {
+            1 => return IotResponse { ac, skip: true },
+            4 => {
+                let character = char::from((u16::from(ac) & 0o177) as u8);
+                self.printed.borrow_mut().push(character);
+            }
+            _ => {}
+        }
+        IotResponse::unchanged(ac)
+    }
+}
+
+struct Tape {
+    path: &'static str,
+    start: usize,
+    max_cycles: u64,
+}
+
+struct Outcome {
+    pdp5: MachineState,
+    stop: StopReason,
+    printed: String,
+}
+impl Outcome {
+    fn memory(&self, address: u16) -> u12 {
+        self.pdp5.memory[address.into()]
+    }
+}
+
+impl Tape {
+    fn run(&self) -> Outcome {
+        let mut buf: [u12; 4096] = [0.into(); 4096];
+        RimFormat::load_from_file(Path::new(self.path), &mut buf)
+            .unwrap_or_else(|error| panic!("Could not load {}: {error}", self.path));
+        let mut pdp5 = MachineState::default(buf);
+        let printed = Rc::new(RefCell::new(String::new()));
+        pdp5.iot_bus.attach(Box::new(Teleprinter {
+            printed: Rc::clone(&printed),
+        }));
+        pdp5.set_initial_start_address(self.start);
+        let stop = pdp5.start_program(self.max_cycles);
+        let printed = printed.borrow().clone();
+        Outcome {
+            pdp5,
+            stop,
+            printed,
+        }
+    }
+}
+
+#[test]
+fn test_siralom() {
+    // Prints the poem a character at a time until it reaches the 0 after it.
+    let outcome = Tape {
+        path: "example_code/siralom_tap/siralom.tap",
+        start: 0o200,
+        max_cycles: 100_000,
+    }
+    .run();
+    assert_eq!(outcome.stop, StopReason::Halted);
+    assert_eq!(outcome.memory(0), 0o204.into()); // After the HLT at 0203.
+    assert_eq!(outcome.memory(0o10), 0o2023.into()); // Pointing at the 0.
+    assert_eq!(outcome.pdp5.registers.hardware_registers.AC, 0.into());
+    let lines: Vec<&str> = outcome.printed.lines().collect();
+    assert_eq!(outcome.printed.len(), 0o2023 - 0o210);
+    assert_eq!(lines[0], "Volek syrolm thudothlon");
+    assert_eq!(lines.last(), Some(&"egembelu ullyetuk."));
+}
+
+#[test]
+fn test_binhalt_pm() {
+    // The README has it filling memory below 07600 with 07402 then halting, which is
+    // what it does on a PDP8. It starts storing at location 0 though, and on a PDP5 that
+    // is the PC, so the first 07402 sends the program off through the empty memory up
+    // to 07600 where it starts again, and it never gets as far as its HLT.
+    let outcome = Tape {
+        path: "example_code/binhalt-pm/binhalt-pm",
+        start: 0o7600,
+        max_cycles: 100_000,
+    }
+    .run();
+    assert_eq!(outcome.stop, StopReason::CycleLimit);
+    // Each time round it gets as far as its first store and no further.
+    assert_eq!(outcome.memory(0o7612), 0o200.into());
+    assert_eq!(outcome.memory(0o7613), 0.into());
+    assert!((1..0o7600).all(|address| outcome.memory(address) == 0.into()));
+    assert!(outcome.printed.is_empty());
+}
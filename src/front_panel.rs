@@ -0,0 +1,365 @@
+use crate::consts::*;
+use crate::pacing::{Pacer, Speed};
+use crate::unsigned_integer_12::u12;
+use crate::{MachineState, StopReason};
+use std::io::{Error, Read, Write};
+use std::process::Command;
+use std::sync::mpsc::{Receiver, TryRecvError, channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A full screen picture of the console drawn with ANSI escapes. The machine runs flat out
+// in slices between frames and the lamps are redrawn at a fixed rate however fast that is,
+// the same as the real lamps which only show wherever the machine happened to be.
+//
+// Keys:
+//   1 2 3 4 5 6 7 8 9 0 - =   Toggle switch register bits 0 to 11 (bit 0 on the left).
+//   z                         All switches down.
+//   l  Load Address   d  Deposit   e  Examine
+//   s  Start          c  Continue  h  Stop
+//   n  Single Step    i  Single Instruction
+//   q  Quit
+
+const FRAMES_PER_SECOND: u64 = 30;
+const SLICE_CYCLES: u64 = 1000; // Cycles between looks at the clock while running.
+const SWITCH_KEYS: &[u8; 12] = b"1234567890-=";
+const STATES: [&str; 6] = ["F", "D", "E1", "E2", "P", "B"];
+
+/// What the lamps show.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lamps {
+    pub pc: u12,
+    pub ma: u12,
+    pub mb: u12,
+    pub ac: u12,
+    pub link: u8,
+    pub ir: u8,
+    pub state: &'static str,
+    pub run: bool,
+    pub sr: u12,
+}
+impl Lamps {
+    pub fn capture(state: &MachineState) -> Lamps {
+        let registers = &state.registers.hardware_registers;
+        Lamps {
+            pc: state.memory[PC_ADDRESS.into()],
+            ma: registers.MA,
+            mb: registers.MB,
+            ac: registers.AC,
+            link: registers.L,
+            ir: registers.IR,
+            state: state.state.name(),
+            run: state.run,
+            sr: registers.SR,
+        }
+    }
+
+    /// The whole screen, each line cleared to the end so nothing is left from the last frame.
+    pub fn render(&self) -> String {
+        let lines = [
+            format!("PDP-5                          RUN {}", lamp(self.run)),
+            String::new(),
+            format!("PC     {}   {:04o}", word(self.pc), self.pc),
+            format!("MA     {}   {:04o}", word(self.ma), self.ma),
+            format!("MB     {}   {:04o}", word(self.mb), self.mb),
+            format!(
+                "L AC {} {}   {}{:04o}",
+                lamp(self.link == 1),
+                word(self.ac),
+                self.link,
+                self.ac
+            ),
+            format!("IR     {}", bits(self.ir as u16, 4)),
+            format!(
+                "STATE  {}",
+                STATES
+                    .iter()
+                    .map(|name| format!("{name} {}", lamp(*name == self.state)))
+                    .collect::<Vec<_>>()
+                    .join("  ")
+            ),
+            String::new(),
+            format!("SR     {}   {:04o}", word(self.sr), self.sr),
+            "       123 456 789 0-=".to_string(),
+            String::new(),
+            "l Load Address  d Deposit  e Examine  s Start  c Continue  h Stop".to_string(),
+            "n Single Step  i Single Instruction  z Clear switches  q Quit".to_string(),
+        ];
+        let mut screen = String::from("\x1b[H");
+        for line in lines {
+            screen.push_str(&line);
+            screen.push_str("\x1b[K\r\n");
+        }
+        screen
+    }
+}
+
+fn lamp(on: bool) -> char {
+    if on { '●' } else { '○' }
+}
+
+/// Most significant bit first, a space between each octal digit's worth.
+fn bits(value: u16, count: u16) -> String {
+    let mut text = String::new();
+    for bit in (0..count).rev() {
+        text.push(lamp(value & (1 << bit) != 0));
+        if bit % 3 == 0 && bit > 0 {
+            text.push(' ');
+        }
+    }
+    text
+}
+
+fn word(value: u12) -> String {
+    bits(value.into(), 12)
+}
+
+pub struct FrontPanel {
+    pub pdp5: MachineState,
+    pacer: Pacer,
+    running: bool,
+    quit: bool,
+}
+impl FrontPanel {
+    pub fn new(pdp5: MachineState, speed: Speed) -> FrontPanel {
+        FrontPanel {
+            pdp5,
+            pacer: Pacer::new(speed),
+            running: false,
+            quit: false,
+        }
+    }
+
+    /// A key from the keyboard.
+    pub fn press(&mut self, key: u8) {
+        if let Some(bit) = SWITCH_KEYS.iter().position(|switch| *switch == key) {
+            let sr = u16::from(self.pdp5.registers.hardware_registers.SR);
+            self.pdp5.set_switches((sr ^ (0o4000 >> bit)).into());
+            return;
+        }
+        // The other keys do nothing while the machine runs, bar Stop.
+        match key.to_ascii_lowercase() {
+            b'h' => self.stop(),
+            b'q' => self.quit = true,
+            _ if self.running => {}
+            b'z' => self.pdp5.set_switches(0.into()),
+            b'l' => self.pdp5.load_address(),
+            b'd' => self.pdp5.deposit(),
+            b'e' => {
+                self.pdp5.examine();
+            }
+            b's' => {
+                self.pdp5.start(0);
+                self.set_running();
+            }
+            b'c' => {
+                self.pdp5.continue_program(0);
+                self.set_running();
+            }
+            b'n' => self.pdp5.single_step(),
+            b'i' => self.pdp5.single_instruction(),
+            _ => {}
+        }
+    }
+
+    fn set_running(&mut self) {
+        self.pacer.reset(self.pdp5.simulated_ns());
+        self.running = true;
+    }
+
+    /// Finish the instruction in progress so the lamps show where the program got to.
+    fn stop(&mut self) {
+        if self.running && !self.pdp5.at_instruction_boundary() {
+            self.pdp5.single_instruction();
+        }
+        self.pdp5.stop();
+        self.running = false;
+    }
+
+    /// Run the machine, if it is running, until the frame time is up. Any time the
+    /// pacer wants us to wait is spent waiting for the next frame instead.
+    pub fn run_for(&mut self, frame: Duration) {
+        let start = Instant::now();
+        while self.running && start.elapsed() < frame {
+            let ahead = self.pacer.ahead(self.pdp5.simulated_ns());
+            if !ahead.is_zero() {
+                thread::sleep(ahead.min(frame.saturating_sub(start.elapsed())));
+                continue;
+            }
+            match self.pdp5.continue_program(SLICE_CYCLES) {
+                StopReason::CycleLimit => {}
+                _ => self.running = false,
+            }
+        }
+    }
+
+    /// Draw frames until q, or the keys run out.
+    pub fn run(&mut self, keys: Receiver<u8>, output: &mut dyn Write) -> Result<(), Error> {
+        let frame = Duration::from_nanos(1_000_000_000 / FRAMES_PER_SECOND);
+        write!(output, "\x1b[2J\x1b[?25l")?;
+        while !self.quit {
+            let start = Instant::now();
+            loop {
+                match keys.try_recv() {
+                    Ok(key) => self.press(key),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.quit = true;
+                        break;
+                    }
+                }
+            }
+            self.run_for(frame);
+            write!(output, "{}", Lamps::capture(&self.pdp5).render())?;
+            output.flush()?;
+            if let Some(rest) = frame.checked_sub(start.elapsed()) {
+                thread::sleep(rest);
+            }
+        }
+        write!(output, "\x1b[?25h\r\n")?;
+        output.flush()
+    }
+
+    /// On the terminal, with the keyboard put into raw mode for the duration.
+    pub fn run_on_terminal(&mut self) -> Result<(), Error> {
+        stty(&["raw", "-echo"])?;
+        let (sender, keys) = channel();
+        thread::spawn(move || {
+            for byte in std::io::stdin().lock().bytes() {
+                let Ok(byte) = byte else { break };
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        let result = self.run(keys, &mut std::io::stdout());
+        stty(&["sane"])?;
+        result
+    }
+}
+
+/// No terminal handling in std, so it is done the way a shell script would.
+fn stty(arguments: &[&str]) -> Result<(), Error> {
+    Command::new("stty")
+        .args(arguments)
+        .stdin(std::process::Stdio::inherit())
+        .status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breakpoints::{Breakpoint, Watch};
+
+    fn panel() -> FrontPanel {
+        FrontPanel::new(MachineState::default([0.into(); 4096]), Speed::Unthrottled)
+    }
+
+    fn press(panel: &mut FrontPanel, keys: &str) {
+        for key in keys.bytes() {
+            panel.press(key);
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let lamps = Lamps {
+            pc: 0o200.into(),
+            ma: 0o7777.into(),
+            mb: 0o1234.into(),
+            ac: 0o4001.into(),
+            link: 1,
+            ir: 0o12,
+            state: "E1",
+            run: false,
+            sr: 0.into(),
+        };
+        let screen = lamps.render();
+        let lines: Vec<&str> = screen.split("\x1b[K\r\n").collect();
+        assert_eq!(lines[0], "\x1b[HPDP-5                          RUN ○");
+        assert_eq!(lines[2], "PC     ○○○ ○●○ ○○○ ○○○   0200");
+        assert_eq!(lines[3], "MA     ●●● ●●● ●●● ●●●   7777");
+        assert_eq!(lines[5], "L AC ● ●○○ ○○○ ○○○ ○○●   14001");
+        assert_eq!(lines[6], "IR     ● ○●○");
+        assert_eq!(lines[7], "STATE  F ○  D ○  E1 ●  E2 ○  P ○  B ○");
+    }
+
+    #[test]
+    fn test_switches_and_deposit() {
+        let mut panel = panel();
+        // 0200 on the switches, Load Address, then 7402 and Deposit.
+        press(&mut panel, "5l");
+        assert_eq!(panel.pdp5.memory[PC_ADDRESS.into()], 0o200.into());
+        press(&mut panel, "z1234-d");
+        assert_eq!(panel.pdp5.memory[0o200.into()], 0o7402.into());
+        press(&mut panel, "z5le");
+        assert_eq!(Lamps::capture(&panel.pdp5).mb, 0o7402.into());
+    }
+
+    #[test]
+    fn test_runs_between_frames() {
+        let mut panel = panel();
+        panel
+            .pdp5
+            .toggle_in(0o200.into(), &[0o2300, 0o5200, 0o7402]); // ISZ 300, JMP 200, HLT
+        press(&mut panel, "z5ls");
+        assert!(Lamps::capture(&panel.pdp5).run);
+        // Keys other than Stop are ignored while running.
+        press(&mut panel, "l");
+        panel.run_for(Duration::from_secs(10));
+        let lamps = Lamps::capture(&panel.pdp5);
+        assert!(!lamps.run);
+        assert_eq!(lamps.pc, 0o203.into());
+        assert_eq!(panel.pdp5.memory[0o300.into()], 0.into());
+    }
+
+    #[test]
+    fn test_halt_on_a_slice_boundary() {
+        let mut panel = panel();
+        // TAD then NOPs so the HLT's F cycle is the last of a slice and its P the first of the next.
+        let mut program = vec![0o1200];
+        program.extend([0o7000; 498]);
+        program.push(0o7402);
+        panel.pdp5.toggle_in(0o200.into(), &program);
+        press(&mut panel, "z5ls");
+        panel.run_for(Duration::from_secs(10));
+        let lamps = Lamps::capture(&panel.pdp5);
+        assert!(!lamps.run);
+        assert_eq!(lamps.pc, (0o200 + 500).into());
+        assert_eq!(panel.pdp5.cycles, SLICE_CYCLES + 1);
+    }
+
+    #[test]
+    fn test_watchpoint_on_a_slice_boundary() {
+        let mut panel = panel();
+        // The DCA's E1 is the last cycle of a slice.
+        let mut program = vec![0o7000; 499];
+        program.push(0o3100); // DCA 100
+        program.push(0o5200); // JMP 200
+        panel.pdp5.toggle_in(0o200.into(), &program);
+        panel.pdp5.breakpoints.add(Breakpoint::Watch {
+            watch: Watch::Write,
+            first: 0o100.into(),
+            last: 0o100.into(),
+        });
+        press(&mut panel, "z5ls");
+        panel.run_for(Duration::from_secs(10));
+        assert!(!Lamps::capture(&panel.pdp5).run);
+        assert_eq!(panel.pdp5.cycles, SLICE_CYCLES + 1);
+    }
+
+    #[test]
+    fn test_stop_finishes_the_instruction() {
+        let mut panel = panel();
+        panel.pdp5.toggle_in(0o200.into(), &[0o5200]); // JMP 200
+        press(&mut panel, "z5ls");
+        panel.pdp5.single_step();
+        panel.pdp5.run = true;
+        press(&mut panel, "h");
+        let lamps = Lamps::capture(&panel.pdp5);
+        assert!(!lamps.run);
+        assert_eq!(lamps.state, "F");
+        assert_eq!(lamps.pc, 0o200.into());
+    }
+}
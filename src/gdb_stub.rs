@@ -0,0 +1,613 @@
+use crate::breakpoints::{Breakpoint, BreakpointHit, Watch};
+use crate::consts::*;
+use crate::unsigned_integer_12::u12;
+use crate::{MachineState, Register, StopReason};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::TcpListener;
+
+// GDB remote serial protocol, enough of it to examine, deposit, step, continue and
+// break. Packets are $data#checksum, the checksum being the sum of the data bytes mod 256,
+// and each one is acknowledged with + (or - to have it sent again) unless no ack mode is on.
+//
+// Registers are sent as 16 bit little endian values in the order of REGISTERS. Memory is
+// byte addressed for GDB's sake, each 12 bit word is two bytes little endian so word n is
+// at byte 2n. The PC is given as a byte address too so it lines up with memory and breakpoints.
+//
+// Going backwards (bs and bc) uses the machine's history of the last HISTORY instructions.
+//
+// Running is synchronous, a continue only comes back at a breakpoint, a halt or the cycle limit,
+// so ^C from GDB is ignored.
+
+const REGISTERS: [Register; 7] = [
+    Register::Ac,
+    Register::Link,
+    Register::Mb,
+    Register::Ma,
+    Register::Ir,
+    Register::Sr,
+    Register::Pc,
+];
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.pdp5.core">
+    <reg name="ac" bitsize="16" type="uint16"/>
+    <reg name="l" bitsize="16" type="uint16"/>
+    <reg name="mb" bitsize="16" type="uint16"/>
+    <reg name="ma" bitsize="16" type="uint16"/>
+    <reg name="ir" bitsize="16" type="uint16"/>
+    <reg name="sr" bitsize="16" type="uint16"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>"#;
+
+// About 10 seconds of PDP5 time before a continue gives up and reports back.
+const MAX_CYCLES: u64 = 10_000_000_000 / CYCLE_TIME_NS as u64;
+
+const MEMORY_BYTES: usize = 2 * 4096;
+
+// Instructions kept for reverse step and continue.
+const HISTORY: usize = 100_000;
+
+// Stop replies.
+const SIGTRAP: &str = "S05";
+const SIGXCPU: &str = "S18"; // Used for running out of cycles.
+const HISTORY_START: &str = "T05replaylog:begin;";
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn hex_word(value: u16) -> String {
+    format!("{:02x}{:02x}", value & 0xff, value >> 8)
+}
+
+/// Only hex digits, from_str_radix would take a sign too and slicing wants ASCII.
+fn hex_digits(text: &str) -> bool {
+    text.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    match hex_digits(text) {
+        true => usize::from_str_radix(text, 16).ok(),
+        false => None,
+    }
+}
+
+/// A 16 bit little endian register value.
+fn parse_hex_word(text: &str) -> Option<u16> {
+    if text.len() != 4 || !hex_digits(text) {
+        return None;
+    }
+    let low = u16::from_str_radix(&text[0..2], 16).ok()?;
+    let high = u16::from_str_radix(&text[2..4], 16).ok()?;
+    Some(low | (high << 8))
+}
+
+/// Splits "addr,length" as used by m, M and Z.
+fn parse_address_length(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+/// Serves one GDB connection at a time for a MachineState.
+pub struct GdbStub {
+    pdp5: MachineState,
+    breakpoints: HashMap<(char, usize, usize), usize>, // Z type, address and length to our breakpoint number.
+    no_ack: bool,
+}
+impl GdbStub {
+    pub fn new(mut pdp5: MachineState) -> GdbStub {
+        pdp5.enable_history(HISTORY);
+        GdbStub {
+            pdp5,
+            breakpoints: HashMap::new(),
+            no_ack: false,
+        }
+    }
+
+    /// Wait for GDB on address, e.g localhost:1234, and serve it until it detaches.
+    pub fn serve_tcp(&mut self, address: &str) -> Result<(), Error> {
+        self.serve_listener(TcpListener::bind(address)?)
+    }
+
+    /// The same on a listener that is already bound.
+    pub fn serve_listener(&mut self, listener: TcpListener) -> Result<(), Error> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let mut output = stream.try_clone()?;
+        self.serve(&mut BufReader::new(stream), &mut output)
+    }
+
+    /// Read packets and answer them until a detach, kill or the end of input.
+    pub fn serve(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), Error> {
+        let mut bytes = input.bytes();
+        let mut next = || -> Result<Option<u8>, Error> { bytes.next().transpose() };
+        while let Some(byte) = next()? {
+            // Acks, nacks and ^C between packets.
+            if byte != b'$' {
+                continue;
+            }
+            let mut data = Vec::new();
+            loop {
+                match next()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(()),
+                }
+            }
+            let mut sent = [0u8; 2];
+            for digit in sent.iter_mut() {
+                *digit = next()?.ok_or(Error::from(ErrorKind::UnexpectedEof))?;
+            }
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let valid = std::str::from_utf8(&sent)
+                .ok()
+                .and_then(|sent| u8::from_str_radix(sent, 16).ok())
+                == Some(checksum(&data));
+            if !self.no_ack {
+                output.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if !valid {
+                output.flush()?;
+                continue;
+            }
+            let (reply, carry_on) = self.handle(&data);
+            write!(output, "${reply}#{:02x}", checksum(&reply))?;
+            output.flush()?;
+            if !carry_on {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// The reply to a single packet, and whether to keep going afterwards.
+    pub fn handle(&mut self, packet: &str) -> (String, bool) {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => SIGTRAP.to_string(),
+            "g" => REGISTERS
+                .iter()
+                .map(|register| hex_word(self.read_register(*register)))
+                .collect(),
+            "G" => self.write_registers(arguments),
+            "p" => match parse_hex(arguments).and_then(|number| REGISTERS.get(number)) {
+                Some(register) => hex_word(self.read_register(*register)),
+                None => "E01".to_string(),
+            },
+            "P" => self.write_one_register(arguments),
+            "m" => self.read_memory(arguments),
+            "M" => self.write_memory(arguments),
+            "s" => self.step(),
+            "c" => self.resume(),
+            "b" if arguments == "s" => match self.pdp5.step_back() {
+                Some(_) => SIGTRAP.to_string(),
+                None => HISTORY_START.to_string(),
+            },
+            "b" if arguments == "c" => stop_reply(self.pdp5.reverse_continue()),
+            "Z" => self.insert_breakpoint(arguments),
+            "z" => self.remove_breakpoint(arguments),
+            "H" => "OK".to_string(),
+            "k" => return (String::new(), false),
+            "D" => return ("OK".to_string(), false),
+            "q" => self.query(arguments),
+            "Q" if arguments == "StartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            // An empty reply is GDB's "not supported".
+            _ => String::new(),
+        };
+        (reply, true)
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return "PacketSize=1000;QStartNoAckMode+;qXfer:features:read+;ReverseStep+;ReverseContinue+".to_string();
+        }
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            // Sent in pieces, m for more to come and l for the last one.
+            let Some((offset, length)) = parse_address_length(range) else {
+                return "E01".to_string();
+            };
+            let rest = TARGET_XML.get(offset..).unwrap_or("");
+            return match rest.len() > length {
+                true => format!("m{}", &rest[..length]),
+                false => format!("l{rest}"),
+            };
+        }
+        match query {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn read_register(&self, register: Register) -> u16 {
+        match register {
+            Register::Pc => self.pdp5.read_register(register) * 2,
+            _ => self.pdp5.read_register(register),
+        }
+    }
+
+    fn write_register(&mut self, register: Register, value: u16) {
+        let value = match register {
+            Register::Pc => value / 2,
+            _ => value,
+        };
+        self.pdp5.write_register(register, value.into());
+    }
+
+    fn write_registers(&mut self, values: &str) -> String {
+        if values.len() != 4 * REGISTERS.len() || !hex_digits(values) {
+            return "E01".to_string();
+        }
+        let mut parsed = Vec::new();
+        for index in 0..REGISTERS.len() {
+            match parse_hex_word(&values[4 * index..4 * index + 4]) {
+                Some(value) => parsed.push(value),
+                None => return "E01".to_string(),
+            }
+        }
+        for (register, value) in REGISTERS.iter().zip(parsed) {
+            // Rewriting the PC starts the machine over from it, so only do it if it changed.
+            if *register == Register::Pc && value == self.read_register(Register::Pc) {
+                continue;
+            }
+            self.write_register(*register, value);
+        }
+        "OK".to_string()
+    }
+
+    fn write_one_register(&mut self, argument: &str) -> String {
+        let Some((number, value)) = argument.split_once('=') else {
+            return "E01".to_string();
+        };
+        match (
+            parse_hex(number).and_then(|number| REGISTERS.get(number)),
+            parse_hex_word(value),
+        ) {
+            (Some(register), Some(value)) => {
+                self.write_register(*register, value);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_byte(&self, address: usize) -> u8 {
+        let word = u16::from(self.pdp5.memory[(address / 2).into()]);
+        match address % 2 {
+            0 => (word & 0xff) as u8,
+            _ => (word >> 8) as u8,
+        }
+    }
+
+    fn read_memory(&self, argument: &str) -> String {
+        let range = parse_address_length(argument).and_then(|(address, length)| {
+            let end = address.checked_add(length)?;
+            (end <= MEMORY_BYTES).then_some(address..end)
+        });
+        match range {
+            Some(range) => range
+                .map(|address| format!("{:02x}", self.read_byte(address)))
+                .collect(),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, argument: &str) -> String {
+        let Some((range, data)) = argument.split_once(':') else {
+            return "E01".to_string();
+        };
+        let Some((address, length)) = parse_address_length(range) else {
+            return "E01".to_string();
+        };
+        let end = address.checked_add(length);
+        if end.is_none_or(|end| end > MEMORY_BYTES)
+            || data.len() != length.saturating_mul(2)
+            || !hex_digits(data)
+        {
+            return "E01".to_string();
+        }
+        for offset in 0..length {
+            let Ok(byte) = u16::from_str_radix(&data[2 * offset..2 * offset + 2], 16) else {
+                return "E01".to_string();
+            };
+            let byte_address = address + offset;
+            let location: u12 = (byte_address / 2).into();
+            let word = u16::from(self.pdp5.memory[location]);
+            // The top four bits of the high byte don't exist.
+            self.pdp5.memory[location] = match byte_address % 2 {
+                0 => (word & 0xff00) | byte,
+                _ => (word & 0x00ff) | (byte << 8),
+            }
+            .into();
+        }
+        "OK".to_string()
+    }
+
+    fn step(&mut self) -> String {
+        self.pdp5.run = true;
+        self.pdp5.step_instruction();
+        self.pdp5.run = false;
+        SIGTRAP.to_string()
+    }
+
+    fn resume(&mut self) -> String {
+        let stop = self.pdp5.start_program(MAX_CYCLES);
+        self.pdp5.run = false;
+        stop_reply(stop)
+    }
+
+    /// Z0 and Z1 are both execution breakpoints, Z2 write, Z3 read and Z4 access watchpoints.
+    fn breakpoint_for(kind: char, address: usize, length: usize) -> Option<Breakpoint> {
+        let first: u12 = (address / 2).into();
+        let last: u12 = ((address + length.max(1) - 1).min(MEMORY_BYTES - 1) / 2).into();
+        let watch = match kind {
+            '0' | '1' => {
+                return Some(Breakpoint::Execute {
+                    address: first,
+                    condition: None,
+                });
+            }
+            '2' => Watch::Write,
+            '3' => Watch::Read,
+            '4' => Watch::ReadWrite,
+            _ => return None,
+        };
+        Some(Breakpoint::Watch { watch, first, last })
+    }
+
+    fn parse_breakpoint(argument: &str) -> Option<(char, usize, usize)> {
+        let (kind, rest) = argument.split_once(',')?;
+        let (address, length) = parse_address_length(rest)?;
+        Some((kind.chars().next()?, address, length))
+    }
+
+    fn insert_breakpoint(&mut self, argument: &str) -> String {
+        let Some(key) = GdbStub::parse_breakpoint(argument).filter(|key| key.1 < MEMORY_BYTES)
+        else {
+            return "E01".to_string();
+        };
+        // An empty reply tells GDB we don't do that kind at all, so only for unknown kinds.
+        let Some(breakpoint) = GdbStub::breakpoint_for(key.0, key.1, key.2) else {
+            return String::new();
+        };
+        if !self.breakpoints.contains_key(&key) {
+            let id = self.pdp5.breakpoints.add(breakpoint);
+            self.breakpoints.insert(key, id);
+        }
+        "OK".to_string()
+    }
+
+    fn remove_breakpoint(&mut self, argument: &str) -> String {
+        let Some(key) = GdbStub::parse_breakpoint(argument) else {
+            return "E01".to_string();
+        };
+        if let Some(id) = self.breakpoints.remove(&key) {
+            self.pdp5.breakpoints.remove(id);
+        }
+        "OK".to_string()
+    }
+}
+
+/// The reply to a c or bc once we have stopped.
+fn stop_reply(stop: StopReason) -> String {
+    match stop {
+        StopReason::Halted | StopReason::LastWrite(_) | StopReason::Protected(_) => {
+            SIGTRAP.to_string()
+        }
+        StopReason::CycleLimit => SIGXCPU.to_string(),
+        StopReason::Breakpoint(hit) => breakpoint_reply(&hit),
+        StopReason::HistoryStart => HISTORY_START.to_string(),
+    }
+}
+
+/// T05 with the watched byte address for watchpoints, GDB works out the rest from the PC.
+fn breakpoint_reply(hit: &BreakpointHit) -> String {
+    match (hit.breakpoint, hit.access) {
+        (Breakpoint::Watch { watch, .. }, Some(access)) => {
+            let name = match watch {
+                Watch::Write => "watch",
+                Watch::Read => "rwatch",
+                Watch::ReadWrite => "awatch",
+            };
+            format!("T05{name}:{:x};", u16::from(access.address) * 2)
+        }
+        _ => SIGTRAP.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    fn packet(data: &str) -> String {
+        format!("${data}#{:02x}", checksum(data))
+    }
+
+    fn stub(program: &[(u16, u16)]) -> GdbStub {
+        GdbStub::new(MachineState::with_program(program))
+    }
+
+    /// Plays the part of GDB, sends each packet and reads back the ack and reply.
+    struct Client<R: BufRead, W: Write> {
+        input: R,
+        output: W,
+    }
+    impl<R: BufRead, W: Write> Client<R, W> {
+        fn send(&mut self, data: &str) -> String {
+            self.output.write_all(packet(data).as_bytes()).unwrap();
+            self.output.flush().unwrap();
+            let mut ack = [0u8; 1];
+            self.input.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            let mut reply = Vec::new();
+            self.input.read_until(b'#', &mut reply).unwrap();
+            let mut sum = [0u8; 2];
+            self.input.read_exact(&mut sum).unwrap();
+            let reply = String::from_utf8(reply).unwrap();
+            let reply = reply.strip_prefix('$').unwrap().strip_suffix('#').unwrap();
+            assert_eq!(
+                std::str::from_utf8(&sum).unwrap(),
+                format!("{:02x}", checksum(reply))
+            );
+            reply.to_string()
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut stub = stub(&[(0o200, 0o7001)]);
+        stub.pdp5.registers.hardware_registers.AC = 0o1234.into();
+        // AC 01234 is 0x29c, the MB has the start address, the PC 0200 is byte 0x100.
+        assert_eq!(stub.handle("g").0, "9c02000080000000000000000001");
+        assert_eq!(stub.handle("p6").0, "0001");
+        assert_eq!(stub.handle("P1=0100").0, "OK");
+        assert_eq!(stub.pdp5.registers.hardware_registers.L, 1);
+        // IAC at 0200, byte 0x100.
+        assert_eq!(stub.handle("m100,2").0, "010e");
+        assert_eq!(stub.handle("M102,2:0f0f").0, "OK");
+        assert_eq!(stub.pdp5.memory[0o201.into()], 0o7417.into());
+        assert_eq!(stub.handle("m2000,1").0, "E01");
+        assert_eq!(stub.handle("vMustReplyEmpty").0, "");
+    }
+
+    #[test]
+    fn test_malformed_packets() {
+        let mut stub = stub(&[(0o200, 0o7001)]);
+        // Not ASCII, where the slicing would land in the middle of a character.
+        assert_eq!(stub.handle("M0,2:a\u{e9}b").0, "E01");
+        assert_eq!(stub.handle("P0=a\u{e9}b").0, "E01");
+        assert_eq!(stub.handle(&format!("G{}\u{e9}", "0".repeat(26))).0, "E01");
+        // Signs aren't hex digits.
+        assert_eq!(stub.handle("P0=+1+1").0, "E01");
+        assert_eq!(stub.handle("M0,1:+1").0, "E01");
+        assert_eq!(stub.handle("m+0,2").0, "E01");
+        // Ranges off the end of the address space.
+        assert_eq!(stub.handle("mffffffffffffffff,2").0, "E01");
+        assert_eq!(stub.handle("Mffffffffffffffff,2:0000").0, "E01");
+        assert_eq!(stub.handle("M0,ffffffffffffffff:00").0, "E01");
+        assert_eq!(stub.pdp5.memory[0.into()], 0o200.into());
+        assert_eq!(stub.pdp5.registers.hardware_registers.AC, 0.into());
+    }
+
+    #[test]
+    fn test_step_continue_and_breakpoints() {
+        let mut stub = stub(&[
+            (0o200, 0o7001), // IAC
+            (0o201, 0o3300), // DCA 300
+            (0o202, 0o7001), // IAC
+            (0o203, 0o7402), // HLT
+        ]);
+        let mut script = String::new();
+        for data in [
+            "qSupported:multiprocess+",
+            "?",
+            "s",
+            "p6",
+            "Z0,104,2", // 0202
+            "Z2,180,2", // Write to 0300
+            "c",
+            "p6",
+            "c",
+            "z2,180,2",
+            "c",
+            "k",
+        ] {
+            script.push_str(&packet(data));
+            script.push('+');
+        }
+        let mut output = Vec::new();
+        stub.serve(&mut script.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let replies: Vec<&str> = output
+            .split('$')
+            .skip(1)
+            .map(|reply| reply.split('#').next().unwrap())
+            .collect();
+        assert_eq!(
+            replies,
+            vec![
+                "PacketSize=1000;QStartNoAckMode+;qXfer:features:read+;ReverseStep+;ReverseContinue+",
+                "S05",
+                "S05",
+                "0201", // 0201 as a byte address is 0x102.
+                "OK",
+                "OK",
+                "T05watch:180;",
+                "0401",
+                "S05",
+                "OK",
+                "S05",
+                "",
+            ]
+        );
+        assert_eq!(stub.pdp5.memory[0o300.into()], 1.into());
+        // Halted with the PC past the HLT.
+        assert_eq!(stub.pdp5.memory[PC_ADDRESS.into()], 0o204.into());
+        assert_eq!(stub.pdp5.registers.hardware_registers.AC, 1.into());
+    }
+
+    #[test]
+    fn test_reverse_step_and_continue() {
+        let mut stub = stub(&[
+            (0o200, 0o7001), // IAC
+            (0o201, 0o7001), // IAC
+            (0o202, 0o7001), // IAC
+            (0o203, 0o7402), // HLT
+        ]);
+        assert_eq!(stub.handle("c").0, "S05");
+        assert_eq!(stub.handle("bs").0, "S05");
+        assert_eq!(stub.handle("p6").0, "0601"); // Back before the HLT at 0203.
+        assert_eq!(stub.handle("Z0,102,2").0, "OK");
+        assert_eq!(stub.handle("bc").0, "S05");
+        assert_eq!(stub.handle("p0").0, "0100");
+        assert_eq!(stub.handle("bc").0, "T05replaylog:begin;");
+        assert_eq!(stub.handle("p0").0, "0000");
+    }
+
+    #[test]
+    fn test_bad_breakpoints() {
+        let mut stub = stub(&[]);
+        assert_eq!(stub.handle("Z0,1ffe,2").0, "OK"); // 7777, the last word.
+        // Past the end of memory is an error, not something we can't do.
+        assert_eq!(stub.handle("Z0,2000,2").0, "E01");
+        assert_eq!(stub.handle("Z2,ffff,2").0, "E01");
+        assert_eq!(stub.handle("Z0,nowhere").0, "E01");
+        // Only kinds we don't have get the empty reply.
+        assert_eq!(stub.handle("Z9,102,2").0, "");
+    }
+
+    #[test]
+    fn test_bad_checksum_is_nacked() {
+        let mut stub = stub(&[]);
+        let mut output = Vec::new();
+        stub.serve(&mut "$g#00".as_bytes(), &mut output).unwrap();
+        assert_eq!(output, b"-");
+    }
+
+    #[test]
+    fn test_tcp() {
+        let mut stub = stub(&[(0o200, 0o7001), (0o201, 0o7402)]);
+        // Any free port, the client's connection waits on the listener until the stub takes it.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let stream = TcpStream::connect(address).unwrap();
+            let mut client = Client {
+                input: BufReader::new(stream.try_clone().unwrap()),
+                output: stream,
+            };
+            vec![client.send("c"), client.send("p0"), client.send("D")]
+        });
+        stub.serve_listener(listener).unwrap();
+        assert_eq!(client.join().unwrap(), vec!["S05", "0100", "OK"]);
+        assert_eq!(stub.pdp5.registers.hardware_registers.AC, 1.into());
+    }
+}
@@ -1,16 +1,55 @@
-use crate::{consts::*, Registers};
+use crate::Registers;
+use crate::iot_bus::IotBus;
 use crate::memory::Memory;
 use crate::unsigned_integer_12::u12;
 
-enum InstructionEvent{
+pub enum InstructionEvent {
     Nothing,
     SkipNextInstruction,
+    InterruptsOn,
+    InterruptsOff,
 }
 
-trait MemoryReferenceInstruction{
-    fn execute(&self, registers: &mut Registers, memory: &mut Memory);
+/// Bits 0-2 of every instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    And,
+    Tad,
+    Isz,
+    Dca,
+    Jms,
+    Jmp,
+    Iot,
+    Operate,
+}
+impl OpCode {
+    pub fn from_instruction(instruction: u12) -> OpCode {
+        OpCode::from_bits(instruction.field(0, 2) as u8)
+    }
+
+    /// The IR holds the opcode and the indirect bit.
+    pub fn from_instruction_register(ir: u8) -> OpCode {
+        OpCode::from_bits(ir >> 1)
+    }
+
+    fn from_bits(bits: u8) -> OpCode {
+        match bits & 0b111 {
+            0 => OpCode::And,
+            1 => OpCode::Tad,
+            2 => OpCode::Isz,
+            3 => OpCode::Dca,
+            4 => OpCode::Jms,
+            5 => OpCode::Jmp,
+            6 => OpCode::Iot,
+            _ => OpCode::Operate,
+        }
+    }
 }
 
+/// Executed during the Execute cycle once the effective address Y is in the MA.
+pub trait MemoryReferenceInstruction {
+    fn execute(&self, registers: &mut Registers, memory: &mut Memory) -> InstructionEvent;
+}
 
 ///Logical AND. The AND operation is performed
 /// between the C(Y) and the C(AC). The result
@@ -19,13 +58,13 @@ trait MemoryReferenceInstruction{
 /// bits are compared independently. This in-
 /// struction, often called extract or mask, can
 /// be considered as a bit-by-bit multiplication.
-pub struct InstrAnd(u12);
-impl MemoryReferenceInstruction for InstrAnd{
-    fn execute(&self, registers: &mut Registers, memory: &mut Memory){
-        let accumulator = registers.hardware_registers.AC;
-        let y = memory.load(self.0);
-        let result = y & accumulator;
-        registers.hardware_registers.AC = result;
+pub struct InstrAnd;
+impl MemoryReferenceInstruction for InstrAnd {
+    fn execute(&self, registers: &mut Registers, memory: &mut Memory) -> InstructionEvent {
+        let registers = &mut registers.hardware_registers;
+        registers.MB = memory.read(registers.MA);
+        registers.AC = registers.MB & registers.AC;
+        InstructionEvent::Nothing
     }
 }
 
@@ -37,17 +76,18 @@ impl MemoryReferenceInstruction for InstrAnd{
 /// mented. This feature is useful in multiple pre-
 /// cision arithmetic.
 /// C(Y) + C(AC) = > C(A
-pub struct InstrTad(u12);
-impl MemoryReferenceInstruction for InstrTad{
-    fn execute(&self, registers: &mut Registers, memory: &mut Memory) {
-        let accumulator = registers.hardware_registers.AC;
-        let y = memory.load(self.0);
-        println!("WARNING: Tad may not be correct, I just used + because lazy, TODO!");
-        // In particular still need to handle updating the link register. 
-        let result = y + accumulator;
-        registers.hardware_registers.AC = result;
-
-
+pub struct InstrTad;
+impl MemoryReferenceInstruction for InstrTad {
+    fn execute(&self, registers: &mut Registers, memory: &mut Memory) -> InstructionEvent {
+        let registers = &mut registers.hardware_registers;
+        registers.MB = memory.read(registers.MA);
+        let (result, carry) = registers.AC.overflowing_add(registers.MB);
+        // Carry out of AC bit 0 complements the link.
+        if carry {
+            registers.L ^= 1;
+        }
+        registers.AC = result;
+        InstructionEvent::Nothing
     }
 }
 // Index and skip if zero. The C(Y) are incre-
@@ -57,12 +97,15 @@ impl MemoryReferenceInstruction for InstrTad{
 // C(Y) != 0, the program proceeds to the next
 // instruction. The C(AC) are unaffected.
 
-pub struct InstrIsz(u12);
-impl MemoryReferenceInstruction for InstrIsz{
-    fn execute(&self, _registers: &mut Registers, memory: &mut Memory) {
-        let y = memory.load_and_increment(self.0);
-        if y == 0.into(){
-            memory.increment_pc_counter();
+pub struct InstrIsz;
+impl MemoryReferenceInstruction for InstrIsz {
+    fn execute(&self, registers: &mut Registers, memory: &mut Memory) -> InstructionEvent {
+        let registers = &mut registers.hardware_registers;
+        memory.cycle(registers.MA, &mut registers.MB, |word| word + 1.into());
+        if registers.MB == 0.into() {
+            InstructionEvent::SkipNextInstruction
+        } else {
+            InstructionEvent::Nothing
         }
     }
 }
@@ -70,10 +113,157 @@ impl MemoryReferenceInstruction for InstrIsz{
 /// Deposit and clear AC. The C(AC) are deposited
 /// in core memory location Y and the AC is then
 /// cleared. The previous C(Y) are lost.
-pub struct InstrDca(u12);
-impl MemoryReferenceInstruction for InstrDca{
-    fn execute(&self, registers: &mut Registers, memory: &mut Memory) {
-        memory.set_value(self.0, registers.hardware_registers.AC);
-        registers.hardware_registers.AC = 0.into();
+pub struct InstrDca;
+impl MemoryReferenceInstruction for InstrDca {
+    fn execute(&self, registers: &mut Registers, memory: &mut Memory) -> InstructionEvent {
+        let registers = &mut registers.hardware_registers;
+        registers.MB = registers.AC;
+        memory.write(registers.MA, registers.MB);
+        registers.AC = 0.into();
+        InstructionEvent::Nothing
     }
-}
\ No newline at end of file
+}
+
+// Operate microinstructions by bit number, bit 3 selects the group.
+const GROUP_2: u8 = 3;
+const CLA: u8 = 4; // Same bit in both groups.
+// Group 1
+const CLL: u8 = 5;
+const CMA: u8 = 6;
+const CML: u8 = 7;
+const RAR: u8 = 8;
+const RAL: u8 = 9;
+const ROTATE_TWICE: u8 = 10;
+const IAC: u8 = 11;
+// Group 2
+const SMA: u8 = 5;
+const SZA: u8 = 6;
+const SNL: u8 = 7;
+const REVERSE_SKIP_SENSE: u8 = 8;
+const OSR: u8 = 9;
+const HLT: u8 = 10;
+
+/// Operate instructions do not reference memory, the individual bits
+/// are microinstructions which are combined and happen in a fixed order.
+/// Group 1: CLA CLL, then CMA CML, then IAC, then RAR RAL (twice if bit 10 is set).
+/// Group 2: SMA SZA SNL (or SPA SNA SZL if bit 8 is set) tested on the original AC,
+/// then CLA, then OSR, then HLT.
+pub struct InstrOperate(pub u12);
+impl InstrOperate {
+    pub fn execute(&self, registers: &mut Registers) -> InstructionEvent {
+        let instruction = self.0;
+        let registers = &mut registers.hardware_registers;
+        if !instruction.bit(GROUP_2) {
+            if instruction.bit(CLA) {
+                registers.AC = u12::ZERO;
+            }
+            if instruction.bit(CLL) {
+                registers.L = 0;
+            }
+            if instruction.bit(CMA) {
+                registers.AC = !registers.AC;
+            }
+            if instruction.bit(CML) {
+                registers.L ^= 1;
+            }
+            if instruction.bit(IAC) {
+                let (result, carry) = registers.AC.overflowing_add(1.into());
+                if carry {
+                    registers.L ^= 1;
+                }
+                registers.AC = result;
+            }
+            let rotations = if instruction.bit(ROTATE_TWICE) { 2 } else { 1 };
+            for _ in 0..rotations {
+                // Rotate the 13 bits of L and AC together.
+                if instruction.bit(RAR) {
+                    (registers.AC, registers.L) = registers.AC.rotate_right_through_link(registers.L);
+                }
+                if instruction.bit(RAL) {
+                    (registers.AC, registers.L) = registers.AC.rotate_left_through_link(registers.L);
+                }
+            }
+            InstructionEvent::Nothing
+        } else {
+            let ac = registers.AC;
+            let mut condition = false;
+            if instruction.bit(SMA) {
+                condition |= ac.is_negative();
+            }
+            if instruction.bit(SZA) {
+                condition |= ac == u12::ZERO;
+            }
+            if instruction.bit(SNL) {
+                condition |= registers.L == 1;
+            }
+            // With the sense reversed we skip only if none of the conditions hold.
+            // So SKP, with no conditions selected, always skips.
+            let skip = if instruction.bit(REVERSE_SKIP_SENSE) {
+                !condition
+            } else {
+                condition
+            };
+            if instruction.bit(CLA) {
+                registers.AC = u12::ZERO;
+            }
+            if instruction.bit(OSR) {
+                registers.AC = registers.AC | registers.SR;
+            }
+            match skip {
+                true => InstructionEvent::SkipNextInstruction,
+                false => InstructionEvent::Nothing,
+            }
+        }
+    }
+
+    /// HLT is the only group 2 microinstruction the processor has to act on itself.
+    pub fn halts(&self) -> bool {
+        self.0.bit(GROUP_2) && self.0.bit(HLT)
+    }
+
+    /// Group 2 with a condition to test, so it might or might not skip. SKP always does.
+    pub fn skips_conditionally(&self) -> bool {
+        self.0.bit(GROUP_2) && (self.0.bit(SMA) || self.0.bit(SZA) || self.0.bit(SNL))
+    }
+}
+
+// Device 00 is the program interrupt itself rather than something on the bus.
+const INTERRUPT_DEVICE: u16 = 0o00;
+const ION: u16 = 0o1;
+const IOF: u16 = 0o2;
+
+/// In-out transfer. The IOP pulses selected by bits 9-11 are sent to
+/// the device selected by bits 3-8.
+pub struct InstrIot(pub u12);
+impl InstrIot {
+    pub fn execute(
+        &self,
+        registers: &mut Registers,
+        iot_bus: &mut IotBus,
+        memory: &mut Memory,
+    ) -> InstructionEvent {
+        if self.0.field(3, 8) == INTERRUPT_DEVICE {
+            return match self.0.field(9, 11) {
+                ION => InstructionEvent::InterruptsOn,
+                IOF => InstructionEvent::InterruptsOff,
+                _ => InstructionEvent::Nothing,
+            };
+        }
+        let registers = &mut registers.hardware_registers;
+        // The memory extension control and parity are part of memory rather than the bus.
+        let response = match memory.iot(self.0, registers.AC) {
+            Some(response) => response,
+            None => iot_bus.execute(self.0, registers.AC),
+        };
+        registers.AC = response.ac;
+        match response.skip {
+            true => InstructionEvent::SkipNextInstruction,
+            false => InstructionEvent::Nothing,
+        }
+    }
+
+    /// Devices test their flags on IOP 1, so that is the pulse that skips.
+    pub fn skips_conditionally(&self) -> bool {
+        self.0.field(3, 8) != INTERRUPT_DEVICE && self.0.bit(11)
+    }
+}
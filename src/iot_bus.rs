@@ -0,0 +1,246 @@
+use crate::data_break::{BreakRequest, BreakTransfer};
+use crate::scheduler::{Events, Scheduler};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::unsigned_integer_12::u12;
+use std::io::{Error, ErrorKind};
+
+// An IOT instruction is 6DDP in octal. Bits 3-8 select the device (DD) and bits 9-11
+// are the IOP pulses (P). The pulses are issued one after another, IOP1 then IOP2 then IOP4,
+// so e.g 6666 on the line printer clears the flag before it prints.
+const DEVICE_SELECT_MASK: u16 = 0o0770;
+const IOP1: u8 = 0o1;
+const IOP2: u8 = 0o2;
+const IOP4: u8 = 0o4;
+
+/// What a device hands back to the processor after an IOP pulse.
+/// The AC is passed through unchanged unless the device loads or clears it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IotResponse {
+    pub ac: u12,
+    pub skip: bool,
+}
+impl IotResponse {
+    pub fn unchanged(ac: u12) -> IotResponse {
+        IotResponse { ac, skip: false }
+    }
+}
+
+/// A peripheral attached to the IOT bus.
+pub trait IotDevice {
+    /// The device select codes (bits 3-8 of the IOT instruction) this device responds to.
+    fn device_codes(&self) -> &[u8];
+
+    /// A single IOP pulse (1, 2 or 4) for one of our device codes. Anything that takes
+    /// time, printing, reading a character etc, schedules an event for when it is done.
+    fn pulse(&mut self, device_code: u8, pulse: u8, ac: u12, events: &mut Events) -> IotResponse;
+
+    /// An event we scheduled has come due.
+    fn event(&mut self, _token: u32, _events: &mut Events) {}
+
+    /// Device flags are ORed together onto the program interrupt request line.
+    fn interrupt_request(&self) -> bool {
+        false
+    }
+
+    /// Devices too fast to move every word through the AC with IOTs ask for a data break
+    /// instead, the request stays up until it has been granted.
+    fn data_break_request(&self) -> Option<BreakRequest> {
+        None
+    }
+
+    /// The break cycles for our request have happened.
+    fn data_break_granted(&mut self, _transfer: BreakTransfer, _events: &mut Events) {}
+
+    /// Whatever the device needs to carry on where it left off after a snapshot is restored,
+    /// flags, positions in the tape and so on. Devices with nothing to keep needn't bother.
+    fn save_state(&self, _writer: &mut SnapshotWriter) {}
+
+    /// Read back what save_state wrote.
+    fn restore_state(&mut self, _reader: &mut SnapshotReader) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub struct IotBus {
+    devices: Vec<Box<dyn IotDevice>>,
+    scheduler: Scheduler,
+}
+impl IotBus {
+    pub fn default() -> IotBus {
+        IotBus {
+            devices: Vec::new(),
+            scheduler: Scheduler::default(),
+        }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn IotDevice>) {
+        self.devices.push(device);
+    }
+
+    /// Issue the IOP pulses of an IOT instruction to whichever devices are selected.
+    /// If nothing is listening on the device code the instruction does nothing.
+    pub fn execute(&mut self, instruction: u12, ac: u12) -> IotResponse {
+        let instruction = u16::from(instruction);
+        let device_code = ((instruction & DEVICE_SELECT_MASK) >> 3) as u8;
+        let mut response = IotResponse::unchanged(ac);
+        for pulse in [IOP1, IOP2, IOP4] {
+            if instruction & pulse as u16 == 0 {
+                continue;
+            }
+            for (index, device) in self
+                .devices
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, device)| device.device_codes().contains(&device_code))
+            {
+                let mut events = Events::new(&mut self.scheduler, index);
+                let result = device.pulse(device_code, pulse, response.ac, &mut events);
+                response.ac = result.ac;
+                response.skip |= result.skip;
+            }
+        }
+        response
+    }
+
+    /// Simulated time has moved on by elapsed_ns, anything due goes off.
+    pub fn tick(&mut self, elapsed_ns: u64) {
+        self.scheduler.advance(elapsed_ns);
+        while let Some((device, token)) = self.scheduler.pop_due() {
+            let mut events = Events::new(&mut self.scheduler, device);
+            self.devices[device].event(token, &mut events);
+        }
+    }
+
+    pub fn interrupt_request(&self) -> bool {
+        self.devices.iter().any(|device| device.interrupt_request())
+    }
+
+    /// The highest priority data break request along with which device made it.
+    /// The lowest priority number wins, ties go to whichever device was attached first.
+    pub fn data_break_request(&self) -> Option<(usize, BreakRequest)> {
+        self.devices
+            .iter()
+            .enumerate()
+            .filter_map(|(index, device)| {
+                device.data_break_request().map(|request| (index, request))
+            })
+            .min_by_key(|(_, request)| request.priority)
+    }
+
+    pub fn data_break_granted(&mut self, device: usize, transfer: BreakTransfer) {
+        let mut events = Events::new(&mut self.scheduler, device);
+        self.devices[device].data_break_granted(transfer, &mut events);
+    }
+
+    /// Each device's codes go in with its state so a restore can tell it has the same devices.
+    pub fn save(&self, writer: &mut SnapshotWriter) {
+        self.scheduler.save(writer);
+        writer.u16(self.devices.len() as u16);
+        for device in &self.devices {
+            let codes = device.device_codes();
+            writer.u8(codes.len() as u8);
+            for code in codes {
+                writer.u8(*code);
+            }
+            let mut state = SnapshotWriter::default();
+            device.save_state(&mut state);
+            writer.bytes(&state.into_bytes());
+        }
+    }
+
+    /// Makes sure the devices in a snapshot are the ones attached, without touching them.
+    pub fn check_snapshot(&self, reader: &mut SnapshotReader) -> Result<(), Error> {
+        let mismatch = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                "Snapshot was taken with different devices attached.",
+            )
+        };
+        if !Scheduler::load(reader)?.only_for_devices(self.devices.len()) {
+            return Err(mismatch());
+        }
+        if reader.u16()? as usize != self.devices.len() {
+            return Err(mismatch());
+        }
+        for device in &self.devices {
+            let count = reader.u8()?;
+            let mut codes = Vec::new();
+            for _ in 0..count {
+                codes.push(reader.u8()?);
+            }
+            if codes != device.device_codes() {
+                return Err(mismatch());
+            }
+            reader.bytes()?;
+        }
+        Ok(())
+    }
+
+    /// Only after check_snapshot has been happy with it.
+    pub fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), Error> {
+        self.scheduler = Scheduler::load(reader)?;
+        reader.u16()?;
+        for device in self.devices.iter_mut() {
+            let count = reader.u8()?;
+            for _ in 0..count {
+                reader.u8()?;
+            }
+            device.restore_state(&mut SnapshotReader::new(reader.bytes()?))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records every pulse it receives, skips on IOP1 and loads 0o1234 into the AC on IOP4.
+    struct Recorder {
+        pulses: std::rc::Rc<std::cell::RefCell<Vec<(u8, u8)>>>,
+    }
+    impl IotDevice for Recorder {
+        fn device_codes(&self) -> &[u8] {
+            &[0o12]
+        }
+        fn pulse(
+            &mut self,
+            device_code: u8,
+            pulse: u8,
+            ac: u12,
+            _events: &mut Events,
+        ) -> IotResponse {
+            self.pulses.borrow_mut().push((device_code, pulse));
+            match pulse {
+                IOP1 => IotResponse { ac, skip: true },
+                IOP4 => IotResponse::unchanged(0o1234.into()),
+                _ => IotResponse::unchanged(ac),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pulses_are_issued_in_order() {
+        let pulses = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut bus = IotBus::default();
+        bus.attach(Box::new(Recorder {
+            pulses: pulses.clone(),
+        }));
+        let result = bus.execute(0o6127.into(), 5.into());
+        assert_eq!(*pulses.borrow(), vec![(0o12, 1), (0o12, 2), (0o12, 4)]);
+        assert!(result.skip);
+        assert_eq!(result.ac, 0o1234.into());
+    }
+
+    #[test]
+    fn test_unselected_device_does_nothing() {
+        let pulses = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut bus = IotBus::default();
+        bus.attach(Box::new(Recorder {
+            pulses: pulses.clone(),
+        }));
+        let result = bus.execute(0o6137.into(), 5.into());
+        assert!(pulses.borrow().is_empty());
+        assert_eq!(result, IotResponse::unchanged(5.into()));
+    }
+}
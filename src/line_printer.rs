@@ -0,0 +1,417 @@
+use crate::iot_bus::{IotDevice, IotResponse};
+use crate::scheduler::Events;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::unsigned_integer_12::u12;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+
+// Modelled on the Type 645 line printer. The printer has a line buffer which is loaded
+// one character at a time from the AC, and a print command which prints the buffer and
+// advances the paper. Instead of paper the output goes to a text file on the host.
+//
+// Device 65, buffer:
+//   6652 LCB  Clear the printing buffer.
+//   6654 LLB  Load the printing buffer with the character in the AC.
+// Device 66, printer:
+//   6661 LSF  Skip if the printer flag is set (printer ready).
+//   6662 LCF  Clear the printer flag.
+//   6664 LPR  Print the buffer, clear it and advance the paper as selected by AC bits 9-11.
+const BUFFER_DEVICE: u8 = 0o65;
+const PRINTER_DEVICE: u8 = 0o66;
+const DEVICE_CODES: [u8; 2] = [BUFFER_DEVICE, PRINTER_DEVICE];
+const PRINTED: u32 = 0; // Event token for the end of a print, the flag comes back on.
+
+const FORMAT_MASK: u16 = 0o7; // AC bits 9-11 select the paper advance.
+const FORMAT_OVERPRINT: u16 = 6; // Return the carriage but don't move the paper.
+const FORMAT_TOP_OF_FORM: u16 = 7; // Advance to the top of the next page.
+// Anything else advances (format + 1) lines, so 0 is single spacing, 1 double etc.
+
+/// How the 6 or 7 bits loaded into the buffer become host characters.
+pub enum CharacterSet {
+    /// DEC 6 bit code from AC bits 6-11, 00 is space, 41 is A, 77 is underscore(left arrow).
+    SixBit,
+    /// 7 bit ASCII from AC bits 5-11.
+    Ascii,
+    /// Our own table indexed by the 6 bit code in AC bits 6-11.
+    Custom(Box<[char; 64]>),
+}
+impl CharacterSet {
+    /// sixbit or ascii.
+    pub fn parse(text: &str) -> Option<CharacterSet> {
+        match text.to_ascii_lowercase().as_str() {
+            "sixbit" => Some(CharacterSet::SixBit),
+            "ascii" => Some(CharacterSet::Ascii),
+            _ => None,
+        }
+    }
+
+    /// A table of the 64 characters for codes 00 to 77 in order.
+    pub fn custom(table: &str) -> Result<CharacterSet, String> {
+        let chars: Vec<char> = table.chars().collect();
+        match <[char; 64]>::try_from(chars) {
+            Ok(table) => Ok(CharacterSet::Custom(Box::new(table))),
+            Err(chars) => Err(format!("Expected 64 characters, got {}", chars.len())),
+        }
+    }
+
+    fn translate(&self, ac: u12) -> char {
+        let ac = u16::from(ac);
+        match self {
+            CharacterSet::SixBit => char::from((ac & 0o77) as u8 + 0o40),
+            CharacterSet::Ascii => char::from((ac & 0o177) as u8),
+            CharacterSet::Custom(table) => table[(ac & 0o77) as usize],
+        }
+    }
+}
+
+/// What to write to the host file when the paper is advanced to the top of form.
+pub enum FormFeed {
+    /// A single form feed character.
+    Character,
+    /// However many blank lines it takes to reach the end of the page.
+    BlankLines,
+}
+impl FormFeed {
+    /// character or lines.
+    pub fn parse(text: &str) -> Option<FormFeed> {
+        match text.to_ascii_lowercase().as_str() {
+            "character" => Some(FormFeed::Character),
+            "lines" => Some(FormFeed::BlankLines),
+            _ => None,
+        }
+    }
+}
+
+pub struct LinePrinterConfig {
+    pub character_set: CharacterSet,
+    pub columns: usize, // Characters loaded after the buffer is full are dropped.
+    pub page_length: usize, // Lines per page.
+    pub form_feed: FormFeed,
+    pub print_time_ns: u64, // Time to print the buffer, the flag is clear while printing.
+    pub line_advance_time_ns: u64, // Extra time per line the paper is advanced.
+}
+impl LinePrinterConfig {
+    /// 120 columns, 66 lines a page and 300 lines a minute when single spacing.
+    pub fn default() -> LinePrinterConfig {
+        LinePrinterConfig {
+            character_set: CharacterSet::SixBit,
+            columns: 120,
+            page_length: 66,
+            form_feed: FormFeed::Character,
+            print_time_ns: 180_000_000,
+            line_advance_time_ns: 20_000_000,
+        }
+    }
+}
+
+pub struct LinePrinter {
+    output: Box<dyn Write>,
+    config: LinePrinterConfig,
+    buffer: String,
+    buffer_length: usize, // In characters, not bytes.
+    flag: bool,
+    line: usize, // Line on the current page the paper is at.
+}
+impl LinePrinter {
+    /// The printer powers up ready, i.e with its flag set.
+    pub fn new(output: Box<dyn Write>, config: LinePrinterConfig) -> LinePrinter {
+        assert!(
+            config.page_length > 0,
+            "A page must have at least one line."
+        );
+        LinePrinter {
+            output,
+            config,
+            buffer: String::new(),
+            buffer_length: 0,
+            flag: true,
+            line: 0,
+        }
+    }
+
+    pub fn to_file(path: &Path, config: LinePrinterConfig) -> Result<LinePrinter, Error> {
+        let file = File::create(path)?;
+        Ok(LinePrinter::new(Box::new(BufWriter::new(file)), config))
+    }
+
+    fn clear_buffer(&mut self) {
+        self.buffer.clear();
+        self.buffer_length = 0;
+    }
+
+    fn load_buffer(&mut self, ac: u12) {
+        if self.buffer_length < self.config.columns {
+            self.buffer.push(self.config.character_set.translate(ac));
+            self.buffer_length += 1;
+        }
+    }
+
+    /// Returns the number of lines the paper moved.
+    fn advance_paper(&mut self, format: u16) -> usize {
+        let page_length = self.config.page_length;
+        let (text, lines) = match format {
+            FORMAT_OVERPRINT => ("\r".to_string(), 0),
+            FORMAT_TOP_OF_FORM => {
+                let lines = (page_length - self.line) % page_length;
+                match (&self.config.form_feed, lines) {
+                    (_, 0) => (String::new(), 0),
+                    (FormFeed::Character, _) => ("\x0c".to_string(), lines),
+                    (FormFeed::BlankLines, _) => ("\n".repeat(lines), lines),
+                }
+            }
+            _ => {
+                let lines = format as usize + 1;
+                ("\n".repeat(lines), lines)
+            }
+        };
+        self.line = (self.line + lines) % page_length;
+        self.output
+            .write_all(text.as_bytes())
+            .expect("Line printer could not write to its output.");
+        lines
+    }
+
+    fn print(&mut self, ac: u12, events: &mut Events) {
+        let line = std::mem::take(&mut self.buffer);
+        self.buffer_length = 0;
+        self.output
+            .write_all(line.as_bytes())
+            .expect("Line printer could not write to its output.");
+        let lines = self.advance_paper(u16::from(ac) & FORMAT_MASK);
+        // Flush every line so long running jobs can be watched as they print.
+        self.output
+            .flush()
+            .expect("Line printer could not flush its output.");
+        let busy_ns = self.config.print_time_ns + lines as u64 * self.config.line_advance_time_ns;
+        self.flag = busy_ns == 0;
+        // Printing again before the last one finished, the flag waits for this one.
+        events.cancel(PRINTED);
+        if busy_ns > 0 {
+            events.after(busy_ns, PRINTED);
+        }
+    }
+}
+
+impl IotDevice for LinePrinter {
+    fn device_codes(&self) -> &[u8] {
+        &DEVICE_CODES
+    }
+
+    fn pulse(&mut self, device_code: u8, pulse: u8, ac: u12, events: &mut Events) -> IotResponse {
+        match (device_code, pulse) {
+            (BUFFER_DEVICE, 2) => self.clear_buffer(),
+            (BUFFER_DEVICE, 4) => self.load_buffer(ac),
+            (PRINTER_DEVICE, 1) => {
+                return IotResponse {
+                    ac,
+                    skip: self.flag,
+                };
+            }
+            (PRINTER_DEVICE, 2) => self.flag = false,
+            (PRINTER_DEVICE, 4) => self.print(ac, events),
+            _ => {}
+        }
+        IotResponse::unchanged(ac)
+    }
+
+    fn event(&mut self, token: u32, _events: &mut Events) {
+        if token == PRINTED {
+            self.flag = true;
+        }
+    }
+
+    fn interrupt_request(&self) -> bool {
+        self.flag
+    }
+
+    /// What has been printed is in the output file already, so just the buffer and where we are.
+    /// A print in progress is an event, the scheduler looks after that.
+    fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.string(&self.buffer);
+        writer.bool(self.flag);
+        writer.u32(self.line as u32);
+    }
+
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), Error> {
+        let buffer = reader.string()?;
+        let flag = reader.bool()?;
+        let line = reader.u32()? as usize;
+        if buffer.chars().count() > self.config.columns || line >= self.config.page_length {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Line printer snapshot doesn't fit this printer.",
+            ));
+        }
+        self.buffer_length = buffer.chars().count();
+        self.buffer = buffer;
+        self.flag = flag;
+        self.line = line;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iot_bus::IotBus;
+    use crate::scheduler::Scheduler;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn output_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "pdp5_line_printer_{name}_{}.txt",
+            std::process::id()
+        ))
+    }
+
+    /// Straight to the printer, events go nowhere.
+    fn pulse(printer: &mut LinePrinter, device_code: u8, pulse: u8, ac: u16) {
+        let mut scheduler = Scheduler::default();
+        printer.pulse(
+            device_code,
+            pulse,
+            ac.into(),
+            &mut Events::new(&mut scheduler, 0),
+        );
+    }
+
+    fn load(printer: &mut LinePrinter, text: &str) {
+        for c in text.chars() {
+            // SixBit is ASCII minus 040.
+            pulse(printer, BUFFER_DEVICE, 4, c as u16 - 0o40);
+        }
+    }
+
+    #[test]
+    fn test_print_single_line_six_bit() {
+        let path = output_path("single_line");
+        let mut printer = LinePrinter::to_file(&path, LinePrinterConfig::default()).unwrap();
+        load(&mut printer, "HELLO, WORLD");
+        pulse(&mut printer, PRINTER_DEVICE, 4, 0);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "HELLO, WORLD\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_ascii_and_columns() {
+        let path = output_path("ascii_columns");
+        let mut config = LinePrinterConfig::default();
+        config.character_set = CharacterSet::Ascii;
+        config.columns = 3;
+        let mut printer = LinePrinter::to_file(&path, config).unwrap();
+        for c in "abcdef".chars() {
+            pulse(&mut printer, BUFFER_DEVICE, 4, c as u16);
+        }
+        // Double spacing.
+        pulse(&mut printer, PRINTER_DEVICE, 4, 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "abc\n\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_top_of_form() {
+        let path = output_path("top_of_form");
+        let mut config = LinePrinterConfig::default();
+        config.page_length = 4;
+        config.form_feed = FormFeed::BlankLines;
+        let mut printer = LinePrinter::to_file(&path, config).unwrap();
+        load(&mut printer, "A");
+        pulse(&mut printer, PRINTER_DEVICE, 4, 0);
+        load(&mut printer, "B");
+        pulse(&mut printer, PRINTER_DEVICE, 4, FORMAT_TOP_OF_FORM);
+        // Already at the top of the page so the paper doesn't move.
+        pulse(&mut printer, PRINTER_DEVICE, 4, FORMAT_TOP_OF_FORM);
+        load(&mut printer, "C");
+        pulse(&mut printer, PRINTER_DEVICE, 4, 0);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "A\nB\n\n\nC\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_flag_timing_and_skip_through_bus() {
+        let path = output_path("flag_timing");
+        let config = LinePrinterConfig::default();
+        let print_time = config.print_time_ns + config.line_advance_time_ns;
+        let mut bus = IotBus::default();
+        bus.attach(Box::new(LinePrinter::to_file(&path, config).unwrap()));
+
+        // Ready on power up.
+        assert!(bus.execute(0o6661.into(), 0.into()).skip);
+        assert!(bus.interrupt_request());
+
+        // Clear buffer and load an 'X', then clear flag and print.
+        bus.execute(0o6656.into(), 0o70.into());
+        bus.execute(0o6666.into(), 0.into());
+        assert!(!bus.execute(0o6661.into(), 0.into()).skip);
+        assert!(!bus.interrupt_request());
+
+        bus.tick(print_time - 1);
+        assert!(!bus.execute(0o6661.into(), 0.into()).skip);
+        bus.tick(1);
+        assert!(bus.execute(0o6661.into(), 0.into()).skip);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "X\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_print_again_while_busy() {
+        let path = output_path("busy");
+        let config = LinePrinterConfig::default();
+        let print_time = config.print_time_ns + config.line_advance_time_ns;
+        let mut bus = IotBus::default();
+        bus.attach(Box::new(LinePrinter::to_file(&path, config).unwrap()));
+
+        // Print without waiting for the flag, half way through the first.
+        bus.execute(0o6664.into(), 0.into());
+        bus.tick(print_time / 2);
+        bus.execute(0o6664.into(), 0.into());
+        bus.tick(print_time / 2);
+        assert!(!bus.execute(0o6661.into(), 0.into()).skip);
+        bus.tick(print_time / 2);
+        assert!(bus.execute(0o6661.into(), 0.into()).skip);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "\n\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_config() {
+        assert!(matches!(
+            CharacterSet::parse("ASCII"),
+            Some(CharacterSet::Ascii)
+        ));
+        assert!(CharacterSet::parse("ebcdic").is_none());
+        let table: String = ('a'..='z').cycle().take(64).collect();
+        let custom = CharacterSet::custom(&table).unwrap();
+        assert_eq!(custom.translate(0o33.into()), 'b');
+        assert!(CharacterSet::custom("abc").is_err());
+        assert!(matches!(
+            FormFeed::parse("lines"),
+            Some(FormFeed::BlankLines)
+        ));
+        assert!(FormFeed::parse("paper").is_none());
+    }
+
+    #[test]
+    fn test_snapshot_state() {
+        let path = output_path("snapshot");
+        let mut printer = LinePrinter::to_file(&path, LinePrinterConfig::default()).unwrap();
+        load(&mut printer, "AB");
+        pulse(&mut printer, PRINTER_DEVICE, 4, 0);
+        load(&mut printer, "CD");
+        let mut writer = SnapshotWriter::default();
+        printer.save_state(&mut writer);
+        let state = writer.into_bytes();
+
+        let mut restored = LinePrinter::to_file(&path, LinePrinterConfig::default()).unwrap();
+        restored
+            .restore_state(&mut SnapshotReader::new(&state))
+            .unwrap();
+        assert_eq!(restored.buffer, "CD");
+        assert_eq!(restored.buffer_length, 2);
+        assert_eq!(restored.line, 1);
+        assert!(!restored.interrupt_request());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,18 +1,69 @@
+mod bin_format_reader;
+mod breakpoints;
+#[cfg(test)]
+mod conformance;
+mod console;
 mod consts;
+mod coverage;
+mod data_break;
+mod debugger;
+mod diagnostics;
+mod disassembler;
+#[cfg(test)]
+mod end_to_end;
+mod front_panel;
+mod gdb_stub;
 mod instruction;
+mod iot_bus;
+mod line_printer;
 mod memory;
+mod memory_extension;
+mod memory_parity;
+mod pacing;
+mod profiler;
+mod reverse;
 mod rim_format_reader;
+mod scheduler;
+mod snapshot;
+mod symbols;
+mod trace;
 mod unsigned_integer_12;
+mod write_protect;
 
 use std::fs;
 use std::io::Error;
 use std::path::Path;
 
+use crate::breakpoints::{BreakpointHit, Breakpoints, watched_accesses};
 use crate::consts::*;
-use crate::memory::Memory;
+use crate::coverage::{Coverage, Listing};
+use crate::data_break::Break;
+use crate::debugger::Debugger;
+use crate::diagnostics::{DiagnosticResult, run_directory};
+use crate::disassembler::disassemble_with_symbols;
+use crate::front_panel::FrontPanel;
+use crate::gdb_stub::GdbStub;
+use crate::instruction::{
+    InstrAnd, InstrDca, InstrIot, InstrIsz, InstrOperate, InstrTad, InstructionEvent,
+    MemoryReferenceInstruction, OpCode,
+};
+use crate::iot_bus::IotBus;
+use crate::line_printer::{CharacterSet, FormFeed, LinePrinter, LinePrinterConfig};
+use crate::memory::{Field, Memory};
+use crate::memory::{MemoryAccess, MemoryAccessKind};
+use crate::memory_parity::Fault;
+use crate::pacing::{Pacer, Speed};
+use crate::profiler::Profiler;
+use crate::reverse::{History, UndoRecord};
 use crate::rim_format_reader::RimFormat;
+use crate::snapshot::Snapshot;
+use crate::symbols::Symbols;
+use crate::trace::{CycleEnd, CycleStart, TraceFormat, TraceGranularity, Tracer, TracerConfig};
 use crate::unsigned_integer_12::u12;
+use crate::write_protect::{BlockedWrite, ProtectedRange};
 
+#[allow(non_snake_case)] // Named as in the handbook.
+#[derive(Clone, Copy)]
 struct HWRegisters {
     AC: u12, // 12 bit Accumulator
     L: u8,   // 1 bit Link, carry register for accumulator, to simplify 2's complement arithmetic.
@@ -29,12 +80,60 @@ impl HWRegisters {
             L: 0,
             MB: 0.into(),
             MA: 0.into(),
-            IR: 0.into(),
+            IR: 0,
             SR: 0.into(),
         }
     }
 }
 
+/// The registers as the console and debuggers see them, the PC being location 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Ac,
+    Link,
+    Mb,
+    Ma,
+    Ir,
+    Sr,
+    Pc,
+}
+impl Register {
+    /// Takes the upper case name.
+    pub fn parse(name: &str) -> Option<Register> {
+        match name {
+            "AC" => Some(Register::Ac),
+            "L" => Some(Register::Link),
+            "MB" => Some(Register::Mb),
+            "MA" => Some(Register::Ma),
+            "IR" => Some(Register::Ir),
+            "SR" => Some(Register::Sr),
+            "PC" => Some(Register::Pc),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::Ac => "AC",
+            Register::Link => "L",
+            Register::Mb => "MB",
+            Register::Ma => "MA",
+            Register::Ir => "IR",
+            Register::Sr => "SR",
+            Register::Pc => "PC",
+        }
+    }
+
+    /// Octal digits it takes to show the whole register.
+    pub fn digits(&self) -> usize {
+        match self {
+            Register::Link => 1,
+            Register::Ir => 2,
+            _ => 4,
+        }
+    }
+}
+
 /// The following are all not real registers but rather are special locations in memory.
 /// Corresponding to Memory Address 0, 1 and 2 respectively.
 struct PseudoRegisters {
@@ -49,34 +148,11 @@ struct PseudoRegisters {
     // Therefore this is just a named wrapper for PseudoRegister functions
 }
 impl PseudoRegisters {
-//     fn load_pc(state: &mut MachineState, skip: bool) {
-//         state.registers.hardware_registers.MB = state.memory[PC_ADDRESS.into()];
-//         match instruction {
-//             Some(instruction) => {
-//                 if instruction.get_opcode() == OpCode::OPERATE {
-//                     todo!(
-//                         "If it's a group two micro instruction with the skip flag set
-//             We need to increment by 2 else increment by 1."
-//                     );
-//                     // Increment MB by 1
-//                     state.registers.hardware_registers.MB += 1.into();
-//                 } else {
-//                     // Increment MB by 1
-//                     state.registers.hardware_registers.MB += 1.into();
-//                 }
-//             }
-//             None => {
-//                 // Increment MB by 1, Start of program so no initial instruction loaded yet.
-//                 state.registers.hardware_registers.MB += 1.into();
-//             }
-//         }
-
-//         // Write MB back into PC
-//         state.memory[PC_ADDRESS.into()] = state.registers.hardware_registers.MB;
-    // }
-
+    /// From the console, so it isn't a memory access anything is watching for. It goes
+    /// by way of the MB like the console's own memory cycle does.
     fn set_pc(state: &mut MachineState, value: u12) {
-        state.memory[PC_ADDRESS.into()] = value;
+        state.registers.hardware_registers.MB = value;
+        state.memory[PC_ADDRESS.into()] = state.registers.hardware_registers.MB;
     }
 }
 
@@ -91,15 +167,18 @@ impl Registers {
     }
 }
 
-// struct Instructions {
-//     mr: MemoryReferenceInstructions, // Memory reference instructions store or retrieve data from core memory,
-//     aug: AugmentedInstructions,      // While augmented do not.
-// }
-// struct MemoryReferenceInstructions {}
-struct AugmentedInstructions {}
+/// What the next P cycle does to the program counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProgramCounterUpdate {
+    Increment,
+    Skip,      // Increment by 2.
+    Jump(u12), // Write the effective address of a JMP or JMS into location 0.
+    Hold,      // Rewrite location 0 as is, used when starting at whatever is already in the PC.
+}
 
-/// State machine for the computer state
-/// StateProgramCounter -> StateFetch
+// State machine for the computer state
+// StateFetch -> (Defer) -> (Execute_1 -> (Execute_2)) -> StateProgramCounter -> StateFetch
+// with Break cycles stolen by devices in between any of them.
 
 /// PROGRAM COUNTER (P): This state reads the contents of the program
 /// counter from core memory location 0 into the MB,
@@ -116,31 +195,176 @@ impl StateProgramCounter {
     fn default() -> StateProgramCounter {
         StateProgramCounter {}
     }
-    fn execute(state: &mut MachineState) -> StateFetch {
-        // Set MB to contents of address 0
-        state.registers.hardware_registers.MB = state.memory[0.into()];
-        // Increment MB by 1
-        state.registers.hardware_registers.MB += 1.into();
-        // Write MB back into PC
-        state.memory[0.into()] = state.registers.hardware_registers.MB;
-        StateFetch {}
+    fn execute(state: &mut MachineState) -> CycleState {
+        let registers = &mut state.registers.hardware_registers;
+        let pc_update = std::mem::replace(&mut state.pc_update, ProgramCounterUpdate::Increment);
+        // A JMP or JMS goes to the field a CIF asked for, then interrupts can come in again.
+        if let (ProgramCounterUpdate::Jump(_), Some(extension)) =
+            (pc_update, state.memory.extension_mut())
+        {
+            extension.jump();
+        }
+        // Program interrupt, the address of the next instruction goes in location 1
+        // and the program carries on from location 2 with interrupts off.
+        let held_off = state
+            .memory
+            .extension()
+            .is_some_and(|extension| extension.interrupt_inhibit);
+        let requested = state.iot_bus.interrupt_request() || state.memory.interrupt_request();
+        let interrupt = state.interrupts.enabled && !held_off && requested;
+        let mut interrupted_at = None;
+        state.memory.select(Field::Zero);
+        // Read location 0 into the MB, update it and write it back. It is whatever is in
+        // location 0 now that gets updated, so a program storing there has jumped.
+        state.memory.machine_cycle(|memory| {
+            memory.cycle(PC_ADDRESS.into(), &mut registers.MB, |pc| {
+                let next = match pc_update {
+                    ProgramCounterUpdate::Increment => pc + 1.into(),
+                    ProgramCounterUpdate::Skip => pc + 2.into(),
+                    ProgramCounterUpdate::Jump(address) => address,
+                    ProgramCounterUpdate::Hold => pc,
+                };
+                if !interrupt {
+                    return next;
+                }
+                interrupted_at = Some(next);
+                INTERRUPT_SERVICE_ADDRESS.into()
+            })
+        });
+        if let Some(next) = interrupted_at {
+            state.memory.machine_cycle(|memory| {
+                memory.write(INTERRUPT_RETURN_ADDRESS.into(), next);
+            });
+            state.interrupts.enabled = false;
+            if let Some(extension) = state.memory.extension_mut() {
+                extension.interrupt();
+            }
+        }
+        // ION takes effect after the instruction following it so JMP I 1 gets to return.
+        if state.interrupts.enable_pending {
+            state.interrupts.enabled = true;
+            state.interrupts.enable_pending = false;
+        }
+        CycleState::F(StateFetch {})
     }
 }
 
 /// FETCH (F): During this state an instruction word is read from the core
 /// memory location specified by the contents of the program counter.
+/// Operate instructions are done entirely within the Fetch cycle, as is a direct JMP.
 struct StateFetch;
 impl StateFetch {
-    fn execute(state: &mut MachineState) {
-        // Gonna assume that we've loaded MB from address 0 in the ProgramCounter state
-        // Unsure if this is always true
-        let instruction = state.memory[state.registers.hardware_registers.MB];
+    fn execute(state: &mut MachineState) -> CycleState {
+        let registers = &mut state.registers.hardware_registers;
+        // P left the PC in the MB.
+        registers.MA = registers.MB;
+        state.memory.select(Field::Instruction);
+        registers.MB = state.memory.read(registers.MA);
+        let instruction = registers.MB;
+        registers.IR = (u16::from(instruction) >> 8) as u8;
+        state.halting = false;
+        match OpCode::from_instruction(instruction) {
+            OpCode::Operate => {
+                let operate = InstrOperate(instruction);
+                let event = operate.execute(&mut state.registers);
+                if operate.halts() {
+                    state.run = false;
+                    state.halting = true;
+                }
+                state.handle_event(event);
+                CycleState::PC(StateProgramCounter {})
+            }
+            OpCode::Iot => CycleState::E1(Execute_1 {}),
+            opcode => {
+                let zero_page = Memory::get_addressing_page_0(instruction);
+                registers.MA = state
+                    .memory
+                    .get_absolute_address(instruction, false, zero_page);
+                if Memory::get_indirect_addressing(instruction) {
+                    CycleState::D(Defer {})
+                } else if opcode == OpCode::Jmp {
+                    state.pc_update = ProgramCounterUpdate::Jump(registers.MA);
+                    CycleState::PC(StateProgramCounter {})
+                } else {
+                    CycleState::E1(Execute_1 {})
+                }
+            }
+        }
     }
 }
+
+/// DEFER (D): The address part of the instruction points at the location holding
+/// the effective address. Reading it from locations 10-17 autoindexes.
+struct Defer;
+impl Defer {
+    fn execute(state: &mut MachineState) -> CycleState {
+        let registers = &mut state.registers.hardware_registers;
+        // The pointer is in the instruction field along with the instruction.
+        state.memory.select(Field::Instruction);
+        registers.MB = state.memory.autoindex(registers.MA);
+        registers.MA = registers.MB;
+        if OpCode::from_instruction_register(registers.IR) == OpCode::Jmp {
+            state.pc_update = ProgramCounterUpdate::Jump(registers.MA);
+            CycleState::PC(StateProgramCounter {})
+        } else {
+            CycleState::E1(Execute_1 {})
+        }
+    }
+}
+
+/// EXECUTE (E1): The memory reference instruction is done on the effective address in the MA,
+/// or the IOT pulses go out to the device. A JMS reads the PC here to get its return address.
+#[allow(non_camel_case_types)]
 struct Execute_1;
+impl Execute_1 {
+    fn execute(state: &mut MachineState) -> CycleState {
+        // The IR's low bit is the indirect bit, indirect operands are in the data field.
+        let deferred = state.registers.hardware_registers.IR & 1 != 0;
+        state.memory.select(match deferred {
+            true => Field::Data,
+            false => Field::Instruction,
+        });
+        let event = match OpCode::from_instruction_register(state.registers.hardware_registers.IR) {
+            OpCode::And => InstrAnd.execute(&mut state.registers, &mut state.memory),
+            OpCode::Tad => InstrTad.execute(&mut state.registers, &mut state.memory),
+            OpCode::Isz => InstrIsz.execute(&mut state.registers, &mut state.memory),
+            OpCode::Dca => InstrDca.execute(&mut state.registers, &mut state.memory),
+            OpCode::Jms => {
+                let registers = &mut state.registers.hardware_registers;
+                state.memory.select(Field::Zero);
+                registers.MB = state.memory.read(PC_ADDRESS.into()) + 1.into();
+                return CycleState::E2(Execute_2 {});
+            }
+            OpCode::Iot => {
+                // Nothing has overwritten the MB since the Fetch.
+                let instruction = state.registers.hardware_registers.MB;
+                InstrIot(instruction).execute(
+                    &mut state.registers,
+                    &mut state.iot_bus,
+                    &mut state.memory,
+                )
+            }
+            OpCode::Jmp | OpCode::Operate => unreachable!("No execute cycle for JMP or operate."),
+        };
+        state.handle_event(event);
+        CycleState::PC(StateProgramCounter {})
+    }
+}
+
+/// EXECUTE (E2): Second execute cycle of a JMS, the return address is stored in Y
+/// and the P cycle then continues from Y + 1.
+#[allow(non_camel_case_types)]
 struct Execute_2;
-struct Defer;
-struct Break;
+impl Execute_2 {
+    fn execute(state: &mut MachineState) -> CycleState {
+        let registers = &mut state.registers.hardware_registers;
+        // Into the field the subroutine is in, which is where the IB says we are going.
+        state.memory.select(Field::Buffer);
+        state.memory.write(registers.MA, registers.MB);
+        state.pc_update = ProgramCounterUpdate::Jump(registers.MA + 1.into());
+        CycleState::PC(StateProgramCounter {})
+    }
+}
 
 enum CycleState {
     PC(StateProgramCounter),
@@ -154,12 +378,76 @@ impl CycleState {
     pub fn default() -> CycleState {
         CycleState::PC(StateProgramCounter::default())
     }
+
+    /// Short name as shown on the console lamps.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CycleState::PC(_) => "P",
+            CycleState::F(_) => "F",
+            CycleState::E1(_) => "E1",
+            CycleState::E2(_) => "E2",
+            CycleState::D(_) => "D",
+            CycleState::B(_) => "B",
+        }
+    }
+}
+
+/// Why the machine stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Halted,                    // HLT instruction.
+    CycleLimit,                // Still running when the cycle budget ran out.
+    Breakpoint(BreakpointHit), // Before the instruction at a breakpoint, or after a watched access.
+    HistoryStart,              // Going backwards and there is nothing earlier to undo.
+    LastWrite(MemoryAccess),   // Going backwards, just before the instruction that did the write.
+    Protected(BlockedWrite),   // After an instruction wrote to a protected range that traps.
+}
+
+/// What one instruction did, from MachineState::step_instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionRecord {
+    pub pc: u12,
+    pub instruction: u12,
+    pub opcode: OpCode,
+    pub address: Option<u12>, // Effective address, memory reference instructions only.
+    pub deferred: bool,       // Went through a Defer cycle.
+    pub cycles: u64,          // Including any breaks along the way.
+    pub skipped: bool,        // The next instruction was skipped.
+    pub writes: Vec<(u12, u12)>, // Address and word, in the order written, breaks included.
+    pub device: Option<u8>,   // IOT instructions only.
+}
+impl InstructionRecord {
+    pub fn disassembly(&self, symbols: &Symbols) -> String {
+        disassemble_with_symbols(self.pc, self.instruction, symbols)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Interrupts {
+    enabled: bool,
+    enable_pending: bool, // Set by ION, becomes enabled at the end of the next instruction.
 }
 
 struct MachineState {
     registers: Registers,
     memory: Memory,
     state: CycleState,
+    iot_bus: IotBus,
+    pc_update: ProgramCounterUpdate,
+    interrupts: Interrupts,
+    run: bool,     // Cleared by HLT, the machine stops at the end of the instruction.
+    halting: bool, // The instruction in progress is a HLT.
+    cycles: u64,   // Major cycles done since power up, including breaks.
+    breakpoints: Breakpoints,
+    watchpoint_hit: Option<BreakpointHit>, // Waiting for the instruction to finish.
+    breakpoint_stop: Option<u12>, // Where the last breakpoint stopped us before an instruction.
+    tracer: Option<Tracer>,
+    history: Option<History>, // For going backwards, only kept when asked for.
+    console_advance: bool,    // The next console Examine or Deposit moves on to the next address.
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    symbols: Symbols, // Names for addresses, only used to show them.
+    scheduled_faults: Vec<(u64, Fault)>, // To inject when the cycle count gets there.
 }
 
 impl MachineState {
@@ -169,11 +457,51 @@ impl MachineState {
         let memory = Memory::default(buf);
         MachineState {
             registers: Registers::default(),
-            memory: memory,
+            memory,
             state: CycleState::default(),
+            iot_bus: IotBus::default(),
+            pc_update: ProgramCounterUpdate::Hold,
+            interrupts: Interrupts {
+                enabled: false,
+                enable_pending: false,
+            },
+            run: false,
+            halting: false,
+            cycles: 0,
+            breakpoints: Breakpoints::default(),
+            watchpoint_hit: None,
+            breakpoint_stop: None,
+            tracer: None,
+            history: None,
+            profiler: None,
+            coverage: None,
+            symbols: Symbols::default(),
+            console_advance: false,
+            scheduled_faults: Vec::new(),
         }
     }
 
+    /// The same with fields of 4K, buf going in field 0. More than one field
+    /// brings the memory extension control with it.
+    #[cfg(test)]
+    pub fn with_fields(buf: [u12; 4096], fields: u8) -> MachineState {
+        let mut state = MachineState::default(buf);
+        state.memory = Memory::with_fields(buf, fields);
+        state
+    }
+
+    /// Zeroed with a test program's address and word pairs put in, ready to start
+    /// at 0200 where the programs all go.
+    #[cfg(test)]
+    pub fn with_program(program: &[(u16, u16)]) -> MachineState {
+        let mut state = MachineState::default([0.into(); 4096]);
+        for (address, word) in program {
+            state.memory[(*address).into()] = (*word).into();
+        }
+        state.set_initial_start_address(0o200);
+        state
+    }
+
     /// PDP5 used rope core memory which is non-volatile
     /// So writing to memory is equivalent to flashing
     /// This is a utility function for testing
@@ -182,12 +510,14 @@ impl MachineState {
     /// This takes a 4096 sized array of u16
     /// which get converted into u12s. Again to make it easier
     /// for testing since u12 is kinda abnormal nowadays.
-    pub fn flash(&mut self, bytes: [u16; 4096]) -> () {
-        for i in 0..bytes.len() {
-            self.memory[i.into()] = bytes[i].into();
+    #[allow(dead_code)]
+    pub fn flash(&mut self, bytes: [u16; 4096]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.memory[i.into()] = (*byte).into();
         }
     }
 
+    #[allow(dead_code)]
     pub fn flash_from_file(&mut self, path: &Path) -> Result<(), Error> {
         let data: Vec<u8> = fs::read(path)?;
         assert!(data.len() <= 4095, "Data is larger than available memory.");
@@ -197,38 +527,354 @@ impl MachineState {
         Ok(())
     }
 
+    /// The next instruction is fetched from address, like the console's Start
+    /// the address goes into both the PC and the MB.
     pub fn set_initial_start_address(&mut self, address: usize) {
         PseudoRegisters::set_pc(self, address.into());
+        self.pc_update = ProgramCounterUpdate::Increment;
+        self.state = CycleState::F(StateFetch {});
+    }
+
+    pub fn read_register(&self, register: Register) -> u16 {
+        let registers = &self.registers.hardware_registers;
+        match register {
+            Register::Ac => registers.AC.into(),
+            Register::Link => registers.L as u16,
+            Register::Mb => registers.MB.into(),
+            Register::Ma => registers.MA.into(),
+            Register::Ir => registers.IR as u16,
+            Register::Sr => registers.SR.into(),
+            Register::Pc => self.memory[PC_ADDRESS.into()].into(),
+        }
     }
 
-    /// IOT pulses are at 1 microsecond interval
-    /// And presumably approximately 1 microsecond long
-    /// Allowing 3 pulses a cycle.
-    pub fn step_event(&mut self) {}
+    pub fn write_register(&mut self, register: Register, value: u12) {
+        let registers = &mut self.registers.hardware_registers;
+        match register {
+            Register::Ac => registers.AC = value,
+            Register::Link => registers.L = (u16::from(value) & 1) as u8,
+            Register::Mb => registers.MB = value,
+            Register::Ma => registers.MA = value,
+            Register::Ir => registers.IR = (u16::from(value) & 0o17) as u8,
+            Register::Sr => registers.SR = value,
+            // Like Load Address and Start, the next instruction comes from here.
+            Register::Pc => self.set_initial_start_address(value.into()),
+        }
+    }
+
+    fn handle_event(&mut self, event: InstructionEvent) {
+        match event {
+            InstructionEvent::Nothing => {}
+            InstructionEvent::SkipNextInstruction => self.pc_update = ProgramCounterUpdate::Skip,
+            InstructionEvent::InterruptsOn => self.interrupts.enable_pending = true,
+            InstructionEvent::InterruptsOff => {
+                self.interrupts.enabled = false;
+                self.interrupts.enable_pending = false;
+            }
+        }
+    }
 
     // An instruction can take multiple cycles.
     // This allows us to inspect the state to ensure
     // things happen at the right time.
-    pub fn step_cycle(&mut self) {}
-    // // Each instruction can be made up with multiple cycles.
-    // pub fn step_instruction(&mut self) {
-    //     let current_instr = PseudoRegisters::load_pc(state, instruction);
-    // }
-    pub fn start_program(&mut self) {
-        let mut count = 0;
+    /// One major cycle, handing back the memory accesses it made if they are being logged.
+    pub fn step_cycle(&mut self) -> Vec<MemoryAccess> {
+        if self.at_instruction_boundary()
+            && self
+                .history
+                .as_ref()
+                .is_some_and(|history| !history.recording())
+        {
+            let record = UndoRecord::capture(self);
+            if let Some(history) = &mut self.history {
+                history.begin(record);
+            }
+        }
+        if !self.scheduled_faults.is_empty() {
+            self.inject_due_faults();
+        }
+        Break::grant(self);
+        // The instruction this cycle is for, the PC only moves on at the end of its P cycle.
+        let pc = self.memory[PC_ADDRESS.into()];
+        if let Some(profiler) = &mut self.profiler
+            && matches!(self.state, CycleState::F(_))
+        {
+            profiler.note_fetch(pc, self.memory.instruction_at(pc));
+        }
+        if let Some(coverage) = &mut self.coverage {
+            match self.state {
+                CycleState::F(_) => coverage.note_fetch(pc, self.memory.instruction_at(pc)),
+                CycleState::PC(_) => {
+                    coverage.note_outcome(pc, self.pc_update == ProgramCounterUpdate::Skip)
+                }
+                _ => {}
+            }
+        }
+        if let Some(tracer) = &mut self.tracer {
+            let registers = &self.registers.hardware_registers;
+            tracer.begin_cycle(CycleStart {
+                fetch: matches!(self.state, CycleState::F(_)),
+                pc,
+                instruction: self.memory.instruction_at(pc),
+                ac: registers.AC,
+                link: registers.L,
+            });
+        }
+        let cycle = self.state.name();
+        let current = std::mem::replace(&mut self.state, CycleState::default());
+        let program_counter = matches!(current, CycleState::PC(_));
+        self.state = match current {
+            CycleState::PC(_) => StateProgramCounter::execute(self),
+            CycleState::F(_) => StateFetch::execute(self),
+            CycleState::D(_) => Defer::execute(self),
+            CycleState::E1(_) => Execute_1::execute(self),
+            CycleState::E2(_) => Execute_2::execute(self),
+            CycleState::B(cycle) => cycle.execute(self),
+        };
+        self.cycles += 1;
+        self.iot_bus.tick(CYCLE_TIME_NS as u64);
+        let instruction_done = self.at_instruction_boundary();
+        if let Some(tracer) = &mut self.tracer {
+            let registers = &self.registers.hardware_registers;
+            tracer.end_cycle(CycleEnd {
+                cycle,
+                program_counter,
+                ma: registers.MA,
+                ac: registers.AC,
+                link: registers.L,
+                accesses: self.memory.accesses(),
+                instruction_done,
+                halted: !self.run,
+                symbols: &self.symbols,
+            });
+        }
+        if let Some(history) = &mut self.history {
+            history.note(self.memory.accesses(), program_counter);
+            if instruction_done {
+                history.end();
+            }
+        }
+        let accesses = self.memory.take_accesses();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.note_cycle(pc, &accesses, program_counter);
+        }
+        if self.watchpoint_hit.is_none() && !accesses.is_empty() {
+            let watched = watched_accesses(&accesses, program_counter);
+            self.watchpoint_hit = self.breakpoints.check_accesses(&watched, pc);
+        }
+        accesses
+    }
+
+    /// Traces everything from the next cycle on.
+    pub fn attach_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+        self.update_access_logging();
+    }
+
+    pub fn detach_tracer(&mut self) -> Option<Tracer> {
+        let tracer = self.tracer.take();
+        self.update_access_logging();
+        tracer
+    }
+
+    /// Memory accesses are only logged when a watchpoint or the tracer wants them.
+    fn update_access_logging(&mut self) {
+        let wanted = self.breakpoints.has_watchpoints()
+            || self.tracer.is_some()
+            || self.history.is_some()
+            || self.profiler.is_some();
+        self.memory.set_access_logging(wanted);
+    }
+
+    /// Inject a fault into memory once the machine has done cycle cycles, now if it has.
+    pub fn inject_fault_at(&mut self, cycle: u64, fault: Fault) {
+        match cycle <= self.cycles {
+            true => self.memory.inject(fault),
+            false => self.scheduled_faults.push((cycle, fault)),
+        }
+    }
+
+    fn inject_due_faults(&mut self) {
+        let cycles = self.cycles;
+        let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.scheduled_faults)
+            .into_iter()
+            .partition(|(cycle, _)| *cycle <= cycles);
+        self.scheduled_faults = waiting;
+        for (_, fault) in due {
+            self.memory.inject(fault);
+        }
+    }
+
+    /// Start counting from scratch.
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::default());
+        self.update_access_logging();
+    }
+
+    /// Stop counting, handing back what was counted.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        let profiler = self.profiler.take();
+        self.update_access_logging();
+        profiler
+    }
+
+    /// Start noting what runs, whatever is in memory now being the program.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(&self.memory));
+    }
+
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    /// Keep enough to undo the last limit instructions, forgetting anything already kept.
+    pub fn enable_history(&mut self, limit: usize) {
+        self.history = Some(History::new(limit));
+        self.update_access_logging();
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+        self.update_access_logging();
+    }
+
+    /// Back to the start of the instruction in progress, or the one before if we are between
+    /// instructions. None if there is no history left.
+    pub fn step_back(&mut self) -> Option<UndoRecord> {
+        let record = self.history.as_mut()?.pop()?;
+        record.restore(self);
+        Some(record)
+    }
+
+    /// Go backwards until a breakpoint, stopping before the instruction at an execution
+    /// breakpoint or before the instruction that set off a watchpoint.
+    pub fn reverse_continue(&mut self) -> StopReason {
         loop {
-            // PseudoRegisters::load_pc(self, None);
-            let mb = self.registers.hardware_registers.MB;
-            let current_instr = self.memory[mb];
-            println!(
-                "Current Instruction: {:?} at Address: {mb:#05x}",
-                current_instr
-            );
-            count += 1;
-            if count >= 2000{
+            let Some(record) = self.step_back() else {
+                return StopReason::HistoryStart;
+            };
+            let hit = self.breakpoints.check_instruction(self).or_else(|| {
+                self.breakpoints
+                    .check_accesses(record.watched(), record.pc())
+            });
+            if let Some(hit) = hit {
+                // So carrying on forwards doesn't stop here again straight away.
+                self.breakpoint_stop = Some(record.pc());
+                return StopReason::Breakpoint(hit);
+            }
+        }
+    }
+
+    /// Go backwards to just before the last instruction (or data break) that wrote to address.
+    pub fn reverse_to_write(&mut self, address: u12) -> StopReason {
+        loop {
+            let Some(record) = self.step_back() else {
+                return StopReason::HistoryStart;
+            };
+            let write =
+                record.accesses().iter().rev().find(|access| {
+                    access.kind == MemoryAccessKind::Write && access.address == address
+                });
+            if let Some(write) = write {
+                return StopReason::LastWrite(*write);
+            }
+        }
+    }
+
+    /// Between instructions, i.e the next cycle fetches.
+    fn at_instruction_boundary(&self) -> bool {
+        matches!(self.state, CycleState::F(_))
+    }
+
+    /// Finish the instruction in progress, or do the next one if we are between instructions.
+    /// The PC in location 0 only moves on in the P cycle at the end, so part way through
+    /// it still says which instruction this is.
+    pub fn step_instruction(&mut self) -> InstructionRecord {
+        let pc = self.memory[PC_ADDRESS.into()];
+        let instruction = self.memory.instruction_at(pc);
+        let opcode = OpCode::from_instruction(instruction);
+        let mut record = InstructionRecord {
+            pc,
+            instruction,
+            opcode,
+            address: None,
+            deferred: false,
+            cycles: 0,
+            skipped: false,
+            writes: Vec::new(),
+            device: (opcode == OpCode::Iot).then_some(((u16::from(instruction) >> 3) & 0o77) as u8),
+        };
+        self.memory.set_access_logging(true);
+        loop {
+            let program_counter = matches!(self.state, CycleState::PC(_));
+            match self.state {
+                CycleState::D(_) => record.deferred = true,
+                CycleState::PC(_) => record.skipped |= self.pc_update == ProgramCounterUpdate::Skip,
+                _ => {}
+            }
+            let accesses = self.step_cycle();
+            record.cycles += 1;
+            // Only what the instruction wrote, not the P cycle moving the PC on.
+            if !program_counter {
+                record.writes.extend(
+                    accesses
+                        .iter()
+                        .filter(|access| access.kind == MemoryAccessKind::Write)
+                        .map(|access| (access.address, access.value)),
+                );
+            }
+            if self.at_instruction_boundary() {
                 break;
             }
         }
+        self.update_access_logging();
+        // The P cycle leaves the MA alone so it still has the effective address.
+        if !matches!(opcode, OpCode::Iot | OpCode::Operate) {
+            record.address = Some(self.registers.hardware_registers.MA);
+        }
+        record
+    }
+
+    /// Runs until a HLT, finishing the instruction that halted, a breakpoint, or until max_cycles
+    /// have gone by. If a breakpoint stopped us where we start from it is passed over so we can carry on.
+    /// Starting part way through an instruction, say where the last run ran out of cycles, a HLT,
+    /// watchpoint or trapped write the instruction has already done still stops it at the end.
+    pub fn start_program(&mut self, max_cycles: u64) -> StopReason {
+        self.run = !self.halting;
+        self.update_access_logging();
+        if self.at_instruction_boundary() {
+            self.run = true;
+            self.watchpoint_hit = None;
+            self.memory.take_trapped_write();
+        }
+        let mut resume_from = self.breakpoint_stop.take();
+        for _ in 0..max_cycles {
+            if self.at_instruction_boundary() {
+                let pc = self.memory[PC_ADDRESS.into()];
+                if resume_from.take() != Some(pc)
+                    && let Some(hit) = self.breakpoints.check_instruction(self)
+                {
+                    self.run = false;
+                    self.breakpoint_stop = Some(pc);
+                    return StopReason::Breakpoint(hit);
+                }
+            }
+            self.step_cycle();
+            if self.at_instruction_boundary() {
+                if let Some(hit) = self.watchpoint_hit.take() {
+                    self.run = false;
+                    return StopReason::Breakpoint(hit);
+                }
+                if let Some(write) = self.memory.take_trapped_write() {
+                    self.run = false;
+                    return StopReason::Protected(write);
+                }
+                if !self.run {
+                    return StopReason::Halted;
+                }
+            }
+        }
+        StopReason::CycleLimit
     }
 }
 
@@ -238,12 +884,445 @@ fn main() {
     let data = RimFormat::load_from_file(path, &mut buf).expect("Dont expect an IO error");
 
     let mut pdp5 = MachineState::default(*data);
-    print!("{:?}", pdp5.memory);
+
+    let mut debug = false;
+    let mut panel = false;
+    let mut speed = Speed::Unthrottled;
+    let mut gdb: Option<String> = None; // Address to listen on, or - for stdin and stdout.
+    let mut trace: Option<String> = None; // File to write an instruction trace to.
+    let mut restore: Option<String> = None; // Snapshot to carry on from.
+    let mut diagnostics: Option<String> = None; // Directory of diagnostic tapes to run.
+    let mut profile: Option<String> = None; // File to write the profile to when it stops.
+    let mut coverage: Option<String> = None; // File to write the coverage report to.
+    let mut lcov: Option<String> = None; // File to write coverage to for lcov and friends.
+    let mut listing: Option<String> = None; // Assembler listing to show coverage against.
+    let mut symbols: Option<String> = None; // Symbol table to show addresses by name.
+    let mut parity = false;
+    let mut protected: Vec<ProtectedRange> = Vec::new();
+    let mut faults: Vec<(u64, Fault)> = Vec::new(); // Faults and the cycle to inject them.
+    let mut trace_config = TracerConfig::default();
+    let mut line_printer: Option<String> = None; // Host file to spool printer output to.
+    let mut printer_config = LinePrinterConfig::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug" => debug = true,
+            "--panel" => panel = true,
+            "--speed" => {
+                // A multiple of real PDP5 speed, or max.
+                let text = args
+                    .next()
+                    .expect("--speed needs a multiple like 1, or max.");
+                speed = Speed::parse(&text).expect("--speed needs a multiple like 1, or max.");
+            }
+            "--gdb" => {
+                let address = args
+                    .next()
+                    .expect("--gdb needs an address like localhost:1234, or - for stdio.");
+                gdb = Some(address);
+            }
+            "--line-printer" => {
+                line_printer = Some(
+                    args.next()
+                        .expect("--line-printer needs a file to print to."),
+                )
+            }
+            "--line-printer-charset" => {
+                // sixbit, ascii, or a file of the 64 characters for codes 00 to 77.
+                let text = args
+                    .next()
+                    .expect("--line-printer-charset needs sixbit, ascii or a file.");
+                printer_config.character_set = CharacterSet::parse(&text).unwrap_or_else(|| {
+                    let table = fs::read_to_string(&text)
+                        .expect("Could not read the line printer character set.");
+                    CharacterSet::custom(table.trim_end_matches(['\r', '\n']))
+                        .expect("Invalid line printer character set.")
+                });
+            }
+            "--line-printer-columns" => {
+                printer_config.columns = args
+                    .next()
+                    .and_then(|text| text.parse().ok())
+                    .expect("--line-printer-columns needs a number of columns.");
+            }
+            "--line-printer-page" => {
+                printer_config.page_length = args
+                    .next()
+                    .and_then(|text| text.parse().ok())
+                    .filter(|&lines| lines > 0)
+                    .expect("--line-printer-page needs a number of lines a page.");
+            }
+            "--line-printer-form-feed" => {
+                // What top of form writes, a form feed character or blank lines.
+                printer_config.form_feed = args
+                    .next()
+                    .and_then(|text| FormFeed::parse(&text))
+                    .expect("--line-printer-form-feed needs character or lines.");
+            }
+            "--line-printer-print-ms" => {
+                let ms: u64 = args
+                    .next()
+                    .and_then(|text| text.parse().ok())
+                    .expect("--line-printer-print-ms needs a time in milliseconds.");
+                printer_config.print_time_ns = ms * 1_000_000;
+            }
+            "--line-printer-advance-ms" => {
+                // Extra for each line the paper moves.
+                let ms: u64 = args
+                    .next()
+                    .and_then(|text| text.parse().ok())
+                    .expect("--line-printer-advance-ms needs a time in milliseconds.");
+                printer_config.line_advance_time_ns = ms * 1_000_000;
+            }
+            "--fields" => {
+                // Fields of 4K with the memory extension control, 1 to 8.
+                let fields = args
+                    .next()
+                    .and_then(|text| text.parse().ok())
+                    .filter(|fields| (1..=8).contains(fields))
+                    .expect("--fields needs a number of 4K fields from 1 to 8.");
+                pdp5.memory = Memory::with_fields(*data, fields);
+            }
+            "--parity" => parity = true,
+            "--protect" => {
+                // first-last or rim, then :ignore, :trap or :log.
+                let text = args
+                    .next()
+                    .expect("--protect needs a range like 7756-7777 or rim.");
+                protected.push(ProtectedRange::parse(&text).expect("Invalid protected range."));
+            }
+            "--fault" => {
+                // kind:address:value, and @cycle to wait until then.
+                let text = args
+                    .next()
+                    .expect("--fault needs a fault like flip:0300:0004.");
+                let (text, cycle) = text.split_once('@').unwrap_or((&text, "0"));
+                let cycle = cycle.parse().expect("--fault needs a cycle count after @.");
+                faults.push((cycle, Fault::parse(text).expect("Invalid fault.")));
+            }
+            "--diagnostics" => {
+                diagnostics = Some(
+                    args.next()
+                        .expect("--diagnostics needs a directory of tapes."),
+                )
+            }
+            "--coverage" => {
+                coverage = Some(args.next().expect("--coverage needs a file to write to."))
+            }
+            "--lcov" => lcov = Some(args.next().expect("--lcov needs a file to write to.")),
+            "--listing" => listing = Some(args.next().expect("--listing needs a listing file.")),
+            "--profile" => {
+                profile = Some(args.next().expect("--profile needs a file to write to."))
+            }
+            "--symbols" => symbols = Some(args.next().expect("--symbols needs a symbol file.")),
+            "--restore" => restore = Some(args.next().expect("--restore needs a snapshot file.")),
+            "--trace" => trace = Some(args.next().expect("--trace needs a file to write to.")),
+            "--trace-json" => trace_config.format = TraceFormat::JsonLines,
+            "--trace-cycles" => trace_config.granularity = TraceGranularity::Cycle,
+            "--trace-range" => {
+                // Octal first-last, can be given more than once.
+                let range = args
+                    .next()
+                    .expect("--trace-range needs a range like 200-277.");
+                let parse = |text: &str| {
+                    u16::from_str_radix(text, 8).expect("--trace-range addresses are octal.")
+                };
+                let (first, last) = range.split_once('-').unwrap_or((&range, &range));
+                trace_config
+                    .ranges
+                    .push((parse(first).into(), parse(last).into()));
+            }
+            "--trace-ring" => {
+                let size = args
+                    .next()
+                    .expect("--trace-ring needs a number of entries.");
+                trace_config.ring = Some(size.parse().expect("--trace-ring needs a number."));
+            }
+            _ => panic!("Unknown argument: {arg}"),
+        }
+    }
+
+    // Each diagnostic gets a machine of its own.
+    if let Some(directory) = diagnostics {
+        let results = run_directory(Path::new(&directory)).expect("Could not run the diagnostics.");
+        for result in &results {
+            println!("{result}");
+        }
+        if !results.iter().all(DiagnosticResult::passed) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(path) = symbols {
+        pdp5.symbols =
+            Symbols::load_from_file(Path::new(&path)).expect("Could not load the symbols.");
+    }
+
+    if let Some(path) = line_printer {
+        let printer = LinePrinter::to_file(Path::new(&path), printer_config)
+            .expect("Could not create the line printer output file.");
+        pdp5.iot_bus.attach(Box::new(printer));
+    }
+
+    if let Some(path) = trace {
+        let tracer = Tracer::to_file(Path::new(&path), trace_config)
+            .expect("Could not create the trace file.");
+        pdp5.attach_tracer(tracer);
+    }
+
+    if profile.is_some() {
+        pdp5.enable_profiler();
+    }
+
+    // Before the restore, a snapshot from a machine with parity needs it.
+    if parity {
+        pdp5.memory.enable_parity();
+    }
+
+    for range in protected {
+        pdp5.memory.protect(range);
+    }
 
     // Program start address, can be anything really but must be loaded into PC prior to start.
     let start_address = 0o07600;
 
     pdp5.set_initial_start_address(start_address);
 
-    pdp5.start_program();
+    // After the devices are attached, the snapshot needs the same ones.
+    if let Some(path) = restore {
+        Snapshot::restore_from_file(&mut pdp5, Path::new(&path))
+            .expect("Could not restore the snapshot.");
+    }
+
+    // After the restore, a restored machine's cycle count carries on from the snapshot's.
+    for (cycle, fault) in faults {
+        pdp5.inject_fault_at(cycle, fault);
+    }
+
+    // After the restore, so the program is what we are carrying on with.
+    if coverage.is_some() || lcov.is_some() {
+        pdp5.enable_coverage();
+    }
+
+    if debug {
+        let mut debugger = Debugger::new(pdp5);
+        debugger
+            .run(&mut std::io::stdin().lock(), &mut std::io::stdout())
+            .expect("Lost the console.");
+        return;
+    }
+
+    if panel {
+        FrontPanel::new(pdp5, speed)
+            .run_on_terminal()
+            .expect("Lost the terminal.");
+        return;
+    }
+
+    if let Some(address) = gdb {
+        let mut stub = GdbStub::new(pdp5);
+        match address.as_str() {
+            "-" => stub.serve(&mut std::io::stdin().lock(), &mut std::io::stdout()),
+            _ => stub.serve_tcp(&address),
+        }
+        .expect("Lost the connection to GDB.");
+        return;
+    }
+
+    // About 10 seconds of PDP5 time.
+    let max_cycles = 10_000_000_000 / CYCLE_TIME_NS as u64;
+    let stop = pdp5.run_paced(&mut Pacer::new(speed), max_cycles);
+    print!("{:?}", pdp5.memory);
+    match stop {
+        StopReason::Halted => {
+            let pc = pdp5.memory[PC_ADDRESS.into()];
+            println!("Halted, PC: {}", pdp5.symbols.describe(pc, 5));
+        }
+        StopReason::CycleLimit => println!("Still running after {max_cycles} cycles."),
+        StopReason::Protected(write) => println!("{}.", write.describe(&pdp5.symbols)),
+        StopReason::Breakpoint(_) | StopReason::HistoryStart | StopReason::LastWrite(_) => {
+            unreachable!("Only the debuggers stop at breakpoints or go backwards.")
+        }
+    }
+    if let Some((field, address)) = pdp5.memory.parity().and_then(|parity| parity.error()) {
+        println!("Parity error flag still set, first error at {field}:{address:04o}.");
+    }
+    for write in pdp5.memory.logged_writes() {
+        println!("{}.", write.describe(&pdp5.symbols));
+    }
+    // Whatever the ring buffer has if we didn't halt.
+    if let Some(mut tracer) = pdp5.detach_tracer() {
+        tracer.finish(&pdp5.symbols);
+    }
+    if let (Some(path), Some(profiler)) = (profile, pdp5.take_profiler()) {
+        fs::write(path, profiler.report(&pdp5.memory, &pdp5.symbols, 20))
+            .expect("Could not write the profile.");
+    }
+    if let Some(results) = pdp5.take_coverage() {
+        // Without a listing the lines are addresses in the tape.
+        let source = listing.clone().unwrap_or(path.display().to_string());
+        let listing = listing.map(|path| {
+            Listing::load_from_file(Path::new(&path)).expect("Could not read the listing.")
+        });
+        if let Some(path) = coverage {
+            fs::write(
+                path,
+                results.report(&pdp5.memory, &pdp5.symbols, listing.as_ref()),
+            )
+            .expect("Could not write the coverage report.");
+        }
+        if let Some(path) = lcov {
+            fs::write(path, results.lcov(&pdp5.memory, &source, listing.as_ref()))
+                .expect("Could not write the lcov file.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breakpoints::{Breakpoint, Watch};
+    use crate::iot_bus::{IotDevice, IotResponse};
+    use crate::scheduler::Events;
+
+    #[test]
+    fn test_step_instruction_records() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o1650), // TAD I 250
+            (0o201, 0o3301), // DCA 301
+            (0o202, 0o2302), // ISZ 302
+            (0o203, 0o7402), // HLT, skipped
+            (0o204, 0o6041), // IOT 04
+            (0o205, 0o5200), // JMP 200
+            (0o250, 0o0300),
+            (0o300, 0o0042),
+            (0o302, 0o7777),
+        ]);
+        let tad = pdp5.step_instruction();
+        assert_eq!(tad.pc, 0o200.into());
+        assert_eq!(tad.opcode, OpCode::Tad);
+        assert_eq!(tad.disassembly(&pdp5.symbols), "TAD I 0250");
+        pdp5.symbols.add("POINTER", 0o250.into());
+        assert_eq!(tad.disassembly(&pdp5.symbols), "TAD I POINTER");
+        assert_eq!(tad.address, Some(0o300.into()));
+        assert!(tad.deferred);
+        assert_eq!(tad.cycles, 4);
+        assert!(tad.writes.is_empty());
+
+        let dca = pdp5.step_instruction();
+        assert_eq!(dca.address, Some(0o301.into()));
+        assert!(!dca.deferred);
+        assert_eq!(dca.cycles, 3);
+        assert_eq!(dca.writes, vec![(0o301.into(), 0o42.into())]);
+
+        let isz = pdp5.step_instruction();
+        assert!(isz.skipped);
+        assert_eq!(isz.writes, vec![(0o302.into(), 0.into())]);
+
+        let iot = pdp5.step_instruction();
+        assert_eq!(iot.pc, 0o204.into());
+        assert_eq!(iot.device, Some(0o04));
+        assert_eq!(iot.address, None);
+
+        let jmp = pdp5.step_instruction();
+        assert_eq!(jmp.address, Some(0o200.into()));
+        assert_eq!(jmp.cycles, 2);
+        assert_eq!(jmp.device, None);
+
+        // Part way through an instruction the record is for the rest of it.
+        pdp5.step_cycle();
+        let rest = pdp5.step_instruction();
+        assert_eq!(rest.pc, 0o200.into());
+        assert_eq!(rest.cycles, 3);
+        assert!(rest.deferred);
+        assert!(!pdp5.memory.logging_accesses());
+    }
+
+    #[test]
+    fn test_jms_and_return() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o4210), // JMS 210
+            (0o201, 0o7402), // HLT
+            (0o210, 0o0000), // Return address goes here
+            (0o211, 0o7001), // IAC
+            (0o212, 0o5610), // JMP I 210
+        ]);
+        assert_eq!(pdp5.start_program(100), StopReason::Halted);
+        assert_eq!(pdp5.memory[0o210.into()], 0o201.into());
+        assert_eq!(pdp5.registers.hardware_registers.AC, 1.into());
+        // P cycle after the HLT has happened so the PC is past it.
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o202.into());
+    }
+
+    /// Raises an interrupt request until it gets a 6xx2.
+    struct Interrupter {
+        flag: bool,
+    }
+    impl IotDevice for Interrupter {
+        fn device_codes(&self) -> &[u8] {
+            &[0o40]
+        }
+        fn pulse(
+            &mut self,
+            _device_code: u8,
+            pulse: u8,
+            ac: u12,
+            _events: &mut Events,
+        ) -> IotResponse {
+            if pulse == 2 {
+                self.flag = false;
+            }
+            IotResponse::unchanged(ac)
+        }
+        fn interrupt_request(&self) -> bool {
+            self.flag
+        }
+    }
+
+    #[test]
+    fn test_program_interrupt() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o002, 0o6402), // Clear the device flag
+            (0o003, 0o7001), // IAC
+            (0o004, 0o6001), // ION
+            (0o005, 0o5401), // JMP I 1
+            (0o200, 0o6001), // ION
+            (0o201, 0o7000), // NOP, interrupt happens after this
+            (0o202, 0o7402), // HLT
+        ]);
+        pdp5.iot_bus.attach(Box::new(Interrupter { flag: true }));
+        assert_eq!(pdp5.start_program(100), StopReason::Halted);
+        assert_eq!(pdp5.memory[INTERRUPT_RETURN_ADDRESS.into()], 0o202.into());
+        assert_eq!(pdp5.registers.hardware_registers.AC, 1.into());
+        assert!(pdp5.interrupts.enabled);
+    }
+
+    #[test]
+    fn test_watchpoint_sees_the_interrupt() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o002, 0o6402), // Clear the device flag
+            (0o003, 0o6001), // ION
+            (0o004, 0o5401), // JMP I 1
+            (0o200, 0o6001), // ION
+            (0o201, 0o7000), // NOP, interrupt happens after this
+            (0o202, 0o7402), // HLT
+        ]);
+        pdp5.iot_bus.attach(Box::new(Interrupter { flag: true }));
+        pdp5.breakpoints.add(Breakpoint::Watch {
+            watch: Watch::Write,
+            first: INTERRUPT_RETURN_ADDRESS.into(),
+            last: INTERRUPT_RETURN_ADDRESS.into(),
+        });
+        let StopReason::Breakpoint(hit) = pdp5.start_program(100) else {
+            panic!("Expected the watchpoint on location 1");
+        };
+        // Reported at the instruction that was interrupted, not where the interrupt went.
+        assert_eq!(hit.pc, 0o201.into());
+        let access = hit.access.unwrap();
+        assert_eq!(access.address, INTERRUPT_RETURN_ADDRESS.into());
+        assert_eq!(access.value, 0o202.into());
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o2.into());
+        assert_eq!(pdp5.start_program(100), StopReason::Halted);
+    }
 }
@@ -1,22 +1,61 @@
+use crate::iot_bus::IotResponse;
+use crate::memory_extension::MemoryExtension;
+use crate::memory_parity::{Fault, MemoryParity};
 use crate::unsigned_integer_12::u12;
+use crate::write_protect::{BlockedWrite, ProtectedRange, Protection};
 use std::{
     fmt::Debug,
     ops::{Index, IndexMut},
 };
+/// Whether the processor read or wrote a location.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccessKind {
+    Read,
+    Write,
+}
+
+/// One memory cycle done by the processor or a data break.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: MemoryAccessKind,
+    pub field: u8,
+    pub address: u12,
+    pub value: u12,    // The word read or written.
+    pub previous: u12, // What was there before, the same as value for a read.
+}
+
+/// Which field the processor's reads and writes go to. Without a memory extension
+/// they are all field 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Zero,        // The PC and interrupt return address in locations 0 and 1, and data breaks.
+    Instruction, // Instructions, direct operands and pointers.
+    Data,        // Operands of indirect AND, TAD, ISZ and DCA.
+    Buffer,      // Where a JMS is going, it stores its return address there.
+}
+
 pub struct Memory {
-    memory: [u12; 4096], // 4096 words of 12 bits
-                         // We represent each 12 bit word as 16 bits
-                         // Since Rust does not support 12 bit primitives.
+    memory: Vec<u12>, // 4096 words of 12 bits for each field
+    // We represent each 12 bit word as 16 bits
+    // Since Rust does not support 12 bit primitives.
+    extension: Option<MemoryExtension>, // Only with more than one field.
+    field: u8,                          // Selected for the processor's accesses.
+    parity: Option<MemoryParity>,
+    faults: Vec<Fault>, // Stuck bits and failing reads, flips are done when injected.
+    protected: Vec<ProtectedRange>,
+    logged_writes: Vec<BlockedWrite>, // Stopped by ranges that log them.
+    trapped_write: Option<BlockedWrite>, // Stopped by a range that traps, until taken.
+    machine_cycle: bool, // The P cycle's own PC and interrupt stores, protection leaves alone.
+    log_accesses: bool,
+    accesses: Vec<MemoryAccess>, // Since the last clear_accesses, only kept when logging.
 }
 impl Debug for Memory {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Memory:")?;
         let mut count = 0;
         const CHUNK_SIZE: usize = 32;
-        for value in self.memory.chunks(CHUNK_SIZE) {
-            if count % 1 == 0 {
-                write!(f, "\nAddress: {:#03x}, Data: ", count)?;
-            }
+        for value in self.memory[..4096].chunks(CHUNK_SIZE) {
+            write!(f, "\nAddress: {:#03x}, Data: ", count)?;
             let _: Vec<_> = value
                 .iter()
                 .map(|val| -> Result<(), std::fmt::Error> {
@@ -26,7 +65,7 @@ impl Debug for Memory {
                 .collect();
             count += CHUNK_SIZE;
         }
-        writeln!(f, "")?;
+        writeln!(f)?;
         Ok(())
     }
 }
@@ -44,7 +83,336 @@ impl IndexMut<u12> for Memory {
 
 impl Memory {
     pub fn default(buf: [u12; 4096]) -> Memory {
-        Memory { memory: buf }
+        Memory {
+            memory: buf.to_vec(),
+            extension: None,
+            field: 0,
+            parity: None,
+            faults: Vec::new(),
+            protected: Vec::new(),
+            logged_writes: Vec::new(),
+            trapped_write: None,
+            machine_cycle: false,
+            log_accesses: false,
+            accesses: Vec::new(),
+        }
+    }
+
+    /// 4K words a field, with buf in field 0 and the rest zeroed. More than one field
+    /// comes with the memory extension control to switch between them.
+    pub fn with_fields(buf: [u12; 4096], fields: u8) -> Memory {
+        assert!(
+            (1..=8).contains(&fields),
+            "Between 1 and 8 fields of memory."
+        );
+        let mut memory = Memory::default(buf);
+        memory.memory.resize(usize::from(fields) * 4096, u12::ZERO);
+        memory.extension = (fields > 1).then(MemoryExtension::default);
+        memory
+    }
+
+    pub fn fields(&self) -> u8 {
+        (self.memory.len() / 4096) as u8
+    }
+
+    pub fn extension(&self) -> Option<&MemoryExtension> {
+        self.extension.as_ref()
+    }
+
+    pub fn extension_mut(&mut self) -> Option<&mut MemoryExtension> {
+        self.extension.as_mut()
+    }
+
+    /// Add the parity option, everything in memory now is taken to have the right parity.
+    pub fn enable_parity(&mut self) {
+        self.parity = Some(MemoryParity::new(self.memory.len()));
+    }
+
+    pub fn parity(&self) -> Option<&MemoryParity> {
+        self.parity.as_ref()
+    }
+
+    pub fn parity_mut(&mut self) -> Option<&mut MemoryParity> {
+        self.parity.as_mut()
+    }
+
+    /// Flips happen now, other faults last until clear_faults.
+    pub fn inject(&mut self, fault: Fault) {
+        match fault {
+            Fault::Flip {
+                field,
+                address,
+                bits,
+            } => {
+                if let Some(slot) = self.slot(field, address) {
+                    self.memory[slot] = self.memory[slot] ^ bits;
+                    if let Some(parity) = &mut self.parity {
+                        parity.flip(slot, bits);
+                    }
+                }
+            }
+            _ => self.faults.push(fault),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn faults(&self) -> &[Fault] {
+        &self.faults
+    }
+
+    #[cfg(test)]
+    pub fn clear_faults(&mut self) {
+        self.faults.clear();
+    }
+
+    /// Where ranges overlap the first one protecting an address says what happens.
+    pub fn protect(&mut self, range: ProtectedRange) {
+        self.protected.push(range);
+    }
+
+    pub fn protected(&self) -> &[ProtectedRange] {
+        &self.protected
+    }
+
+    pub fn clear_protection(&mut self) {
+        self.protected.clear();
+    }
+
+    pub fn logged_writes(&self) -> &[BlockedWrite] {
+        &self.logged_writes
+    }
+
+    pub fn clear_logged_writes(&mut self) {
+        self.logged_writes.clear();
+    }
+
+    /// Accesses the machine makes for itself rather than for the program, the P cycle's
+    /// update of the PC in location 0 and an interrupt's store into location 1, which
+    /// have to happen whatever is protected or the PC would be out of step with the machine.
+    pub fn machine_cycle<R>(&mut self, cycle: impl FnOnce(&mut Memory) -> R) -> R {
+        self.machine_cycle = true;
+        let result = cycle(self);
+        self.machine_cycle = false;
+        result
+    }
+
+    /// The first write a trapping range stopped since this was last asked.
+    pub fn take_trapped_write(&mut self) -> Option<BlockedWrite> {
+        self.trapped_write.take()
+    }
+
+    /// Whether a protected range stops the write, noting it if the range wants.
+    fn write_protected(&mut self, address: u12, value: u12) -> bool {
+        let field = self.field;
+        let Some(range) = self
+            .protected
+            .iter()
+            .find(|range| range.contains(field, address))
+        else {
+            return false;
+        };
+        let write = BlockedWrite {
+            pc: self.memory[0],
+            field,
+            address,
+            value,
+        };
+        match range.protection {
+            Protection::Ignore => {}
+            Protection::Trap => {
+                self.trapped_write.get_or_insert(write);
+            }
+            Protection::Log => self.logged_writes.push(write),
+        }
+        true
+    }
+
+    /// IOTs for the memory options, the extension control's and parity's.
+    pub fn iot(&mut self, instruction: u12, ac: u12) -> Option<IotResponse> {
+        if let Some(ac) = self
+            .extension
+            .as_mut()
+            .and_then(|extension| extension.iot(instruction, ac))
+        {
+            return Some(IotResponse::unchanged(ac));
+        }
+        self.parity
+            .as_mut()
+            .and_then(|parity| parity.iot(instruction, ac))
+    }
+
+    /// The parity error flag is on the program interrupt request line.
+    pub fn interrupt_request(&self) -> bool {
+        self.parity
+            .as_ref()
+            .is_some_and(|parity| parity.error().is_some())
+    }
+
+    /// Fields that aren't fitted read as 0 and writes to them are lost.
+    fn slot(&self, field: u8, address: u12) -> Option<usize> {
+        (field < self.fields()).then(|| usize::from(field) * 4096 + usize::from(address))
+    }
+
+    /// A word in any field, like indexing this doesn't count as an access.
+    pub fn get(&self, field: u8, address: u12) -> u12 {
+        self.slot(field, address)
+            .map_or(u12::ZERO, |slot| self.memory[slot])
+    }
+
+    /// Like a deposit from the console, it goes in with the right parity.
+    pub fn set(&mut self, field: u8, address: u12, value: u12) {
+        if let Some(slot) = self.slot(field, address) {
+            self.memory[slot] = value;
+            if let Some(parity) = &mut self.parity {
+                parity.write(slot, value, value);
+            }
+        }
+    }
+
+    /// The word the core gives back, with any stuck bits, checking its parity.
+    fn load(&mut self, address: u12) -> u12 {
+        let Some(slot) = self.slot(self.field, address) else {
+            return u12::ZERO;
+        };
+        let stored = self.memory[slot];
+        if self.faults.is_empty() && self.parity.is_none() {
+            return stored;
+        }
+        let value = self.stuck(address, stored);
+        let mut failed = false;
+        for fault in self.faults.iter_mut() {
+            if fault.is_at(self.field, address)
+                && let Fault::FailReads { count, .. } = fault
+                && *count > 0
+            {
+                *count -= 1;
+                failed = true;
+            }
+        }
+        if let Some(parity) = &mut self.parity {
+            parity.read(slot, stored, value, failed, (self.field, address));
+        }
+        value
+    }
+
+    /// Into the core, less any stuck bits, with parity made from what we were given.
+    fn store(&mut self, address: u12, value: u12) {
+        let Some(slot) = self.slot(self.field, address) else {
+            return;
+        };
+        let stored = self.stuck(address, value);
+        self.memory[slot] = stored;
+        if let Some(parity) = &mut self.parity {
+            parity.write(slot, value, stored);
+        }
+    }
+
+    fn stuck(&self, address: u12, value: u12) -> u12 {
+        self.faults
+            .iter()
+            .filter(|fault| fault.is_at(self.field, address))
+            .fold(value, |value, fault| match *fault {
+                Fault::Stick {
+                    bits, value: true, ..
+                } => value | bits,
+                Fault::Stick {
+                    bits, value: false, ..
+                } => value & !bits,
+                _ => value,
+            })
+    }
+
+    /// Point the processor's reads and writes at a field, each major cycle picks
+    /// the one it needs before it touches memory.
+    pub fn select(&mut self, field: Field) {
+        self.field = match (&self.extension, field) {
+            (None, _) | (_, Field::Zero) => 0,
+            (Some(extension), Field::Instruction) => extension.instruction_field,
+            (Some(extension), Field::Data) => extension.data_field,
+            (Some(extension), Field::Buffer) => extension.instruction_buffer,
+        };
+    }
+
+    /// What the processor would fetch from address, in the instruction field.
+    pub fn instruction_at(&self, address: u12) -> u12 {
+        let field = self
+            .extension
+            .map_or(0, |extension| extension.instruction_field);
+        self.get(field, address)
+    }
+
+    /// Every word the processor reads or writes comes through here so it can be logged.
+    /// A write stopped by protection didn't happen, so it isn't logged either.
+    fn access(&mut self, kind: MemoryAccessKind, address: u12, value: u12) {
+        if kind == MemoryAccessKind::Write
+            && !self.protected.is_empty()
+            && !self.machine_cycle
+            && self.write_protected(address, value)
+        {
+            return;
+        }
+        if self.log_accesses {
+            self.accesses.push(MemoryAccess {
+                kind,
+                field: self.field,
+                address,
+                value,
+                previous: self.get(self.field, address),
+            });
+        }
+        if kind == MemoryAccessKind::Write {
+            self.store(address, value);
+        }
+    }
+
+    /// Read a word as the processor does, from the selected field. Indexing is for the
+    /// console and anything else that should not show up as a memory access, and is field 0.
+    pub fn read(&mut self, address: u12) -> u12 {
+        let value = self.load(address);
+        self.access(MemoryAccessKind::Read, address, value);
+        value
+    }
+
+    /// Write a word as the processor does.
+    pub fn write(&mut self, address: u12, value: u12) {
+        self.access(MemoryAccessKind::Write, address, value);
+    }
+
+    /// A whole memory cycle the way the core does it, the word is read into the MB,
+    /// modify changes it and the MB is written back. If modify leaves it alone it is
+    /// just a read. ISZ and the P cycle's update of the PC in location 0 are done like this,
+    /// so whatever the program has stored there is what gets incremented.
+    pub fn cycle(&mut self, address: u12, mb: &mut u12, modify: impl FnOnce(u12) -> u12) {
+        *mb = self.read(address);
+        let value = modify(*mb);
+        if value != *mb {
+            self.write(address, value);
+            *mb = value;
+        }
+    }
+
+    /// Keep a log of reads and writes, off by default since most of the time nobody is looking.
+    pub fn set_access_logging(&mut self, on: bool) {
+        self.log_accesses = on;
+        self.accesses.clear();
+    }
+
+    #[cfg(test)]
+    pub fn logging_accesses(&self) -> bool {
+        self.log_accesses
+    }
+
+    pub fn accesses(&self) -> &[MemoryAccess] {
+        &self.accesses
+    }
+
+    #[cfg(test)]
+    pub fn clear_accesses(&mut self) {
+        self.accesses.clear();
+    }
+
+    pub fn take_accesses(&mut self) -> Vec<MemoryAccess> {
+        std::mem::take(&mut self.accesses)
     }
 
     fn get_current_page(&self) -> u12 {
@@ -61,13 +429,13 @@ impl Memory {
         cur_page * 128.into()
     }
 
-
-    /// Ensures that address content is autoindexed if page 0 from location 10 to 17.
-    fn autoindex(&mut self, address: u12) -> u12 {
-        let result = self.memory[address];
-        if address >= 10.into() && address <= 17.into() {
-            // Increments after load for autoindex locations.
-            self.memory[address] += 1.into();
+    /// Ensures that address content is autoindexed if page 0 from location 10 to 17(octal).
+    pub fn autoindex(&mut self, address: u12) -> u12 {
+        let mut result = self.read(address);
+        if address >= 0o10.into() && address <= 0o17.into() {
+            // Autoindex locations are incremented first and the new value is the address.
+            result += 1.into();
+            self.write(address, result);
         }
         result
     }
@@ -90,50 +458,14 @@ impl Memory {
             }
         }
     }
-    fn get_indirect_addressing(instruction: u12) -> bool {
+    pub fn get_indirect_addressing(instruction: u12) -> bool {
         let indirect_mask: u12 = 0b0001_0000_0000.into();
-        if (indirect_mask & instruction) > 0.into() {
-            true
-        } else {
-            false
-        }
+        (indirect_mask & instruction) > 0.into()
     }
-    fn get_addressing_page_0(instruction: u12) -> bool {
+    /// Bit 4 set means the current page, clear means page 0.
+    pub fn get_addressing_page_0(instruction: u12) -> bool {
         let page_0_or_current_page_mask: u12 = 0b0000_1000_0000.into();
-        if (page_0_or_current_page_mask & instruction) > 0.into() {
-            true
-        } else {
-            false
-        }
-    }
-
-    // Increment is for e.g Isz not related to auto indexing
-    // Not sure what expected behaviour for Isz that touches autoincrement even is.
-    pub fn load_and_increment(&mut self, instruction: u12) -> u12 {
-        let indirect = Memory::get_indirect_addressing(instruction);
-        let zero_page = Memory::get_addressing_page_0(instruction);
-        let address = self.get_absolute_address(instruction, indirect, zero_page);
-        // Unsure if autoincrement and ISZ increment can both happen to make a 2 jump or if they are exclusive.
-        // Increments contents before load for ISZ instruction
-        self.memory[address] += 1.into();
-        self.autoindex(address)
-    }
-
-    pub fn load(&mut self, instruction: u12) -> u12 {
-        let indirect = Memory::get_indirect_addressing(instruction);
-        let zero_page = Memory::get_addressing_page_0(instruction);
-        let address = self.get_absolute_address(instruction, indirect, zero_page);
-        self.autoindex(address)
-    }
-
-    /// Used in e.g dca Y, we set the contents of a given indirect/direct/zero_page address to the accumulator.
-    pub fn set_value(&mut self, instruction: u12, value: u12) {
-        let abs_address = self.load(instruction);
-        self.memory[abs_address] = value;
-    }
-
-    pub fn increment_pc_counter(&mut self) {
-        self.memory[0] += 1.into();
+        (page_0_or_current_page_mask & instruction) == 0.into()
     }
 }
 
@@ -198,6 +530,27 @@ mod tests {
         assert_eq!(mem.get_current_page(), 31.into())
     }
 
+    #[test]
+    fn test_cycle() {
+        let mut mem = Memory::default([0.into(); 4096]);
+        mem.memory[0o100] = 0o7777.into();
+        mem.set_access_logging(true);
+        let mut mb = 0.into();
+        mem.cycle(0o100.into(), &mut mb, |word| word + 1.into());
+        assert_eq!(mb, 0.into());
+        assert_eq!(mem[0o100.into()], 0.into());
+        let kinds: Vec<MemoryAccessKind> =
+            mem.accesses().iter().map(|access| access.kind).collect();
+        assert_eq!(kinds, vec![MemoryAccessKind::Read, MemoryAccessKind::Write]);
+        assert_eq!(mem.accesses()[1].previous, 0o7777.into());
+
+        // Nothing changed, nothing written.
+        mem.clear_accesses();
+        mem.cycle(0o100.into(), &mut mb, |word| word);
+        assert_eq!(mem.accesses().len(), 1);
+        assert_eq!(mem.accesses()[0].kind, MemoryAccessKind::Read);
+    }
+
     #[test]
     fn test_load_indirect_direct_zero_page_and_not_zero_page() {
         let buf: [u12; 4096] = [0.into(); 4096];
@@ -205,7 +558,7 @@ mod tests {
         // PC counter is 0th memory address
         mem.memory[0] = 4095.into();
         mem.memory[10] = 100.into();
-        mem.memory[100] = 102.into();
+        mem.memory[101] = 103.into();
         mem.memory[31 * 128] = 200.into();
         mem.memory[31 * 128 + 10] = 10.into();
 
@@ -221,18 +574,19 @@ mod tests {
         assert_eq!(address, (128 * 31).into());
         assert_eq!(mem.autoindex(address), 200.into());
 
-        // In 0 page at Address 10 it's 100, and at absolute address 100 it's 102.
+        // In 0 page at Address 10 it's 100, autoindexed to 101, and at absolute address 101 it's 103.
         let address = mem.get_absolute_address(10.into(), true, true);
         println!("Address: {address:?}");
-        assert_eq!(address, 100.into());
-        assert_eq!(mem.autoindex(address), 102.into());
+        assert_eq!(address, 101.into());
+        assert_eq!(mem.autoindex(address), 103.into());
 
         // Current page is 31. So Adddress 10 is 31*128 + 10, which is 10 so absolute adddress 10.
         // At absolute address 10 we set the value to 100.
-        // BUT, 10 is an autoindex and we already loaded 10 in the previous line so it was autoincremented by 1.
+        // BUT, 10 is an autoindex and we already loaded 10 in the previous line so it was autoincremented by 1,
+        // and it is incremented again before it is used.
         let address = mem.get_absolute_address(10.into(), true, false);
         println!("Address: {address:?}");
         assert_eq!(address, 10.into());
-        assert_eq!(mem.autoindex(address), 101.into());
+        assert_eq!(mem.autoindex(address), 102.into());
     }
 }
@@ -0,0 +1,308 @@
+use crate::unsigned_integer_12::u12;
+
+// Memory extension control, for machines with more than 4K. Memory is up to 8 fields of
+// 4K and the processor's 12 bit addresses are within the field picked by these registers:
+//
+//   IF  Instruction field. Instructions, direct operands and pointers come from here.
+//   DF  Data field. Operands of indirect AND, TAD, ISZ and DCA come from here.
+//   IB  Instruction buffer. Where the next JMP or JMS goes, it moves into the IF then.
+//   SF  Save field. The IF and DF from before the last program interrupt.
+//
+// The PC and the interrupt return address stay in locations 0 and 1 of field 0, where
+// the console sees them, and data breaks are into field 0.
+//
+// It takes devices 20 to 27, with the field in bits 6-8 of the IOT:
+//   62N1 CDF  DF = N.
+//   62N2 CIF  IB = N, interrupts held off until the JMP or JMS that makes it the IF.
+//   6214 RDF  AC bits 6-8 |= DF.
+//   6224 RIF  AC bits 6-8 |= IF.
+//   6234 RIB  AC bits 6-11 |= SF, the old IF then the old DF.
+//   6244 RMF  IB and DF back from SF, to return from an interrupt with the next JMP.
+// A program interrupt saves IF and DF in SF then clears IF, IB and DF so the service
+// routine runs in field 0.
+
+const FIRST_DEVICE: u16 = 0o20;
+const LAST_DEVICE: u16 = 0o27;
+const CDF: u16 = 0o1;
+const CIF: u16 = 0o2;
+const READ: u16 = 0o4; // With N saying what, 1 RDF, 2 RIF, 3 RIB, 4 RMF.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryExtension {
+    pub instruction_field: u8,
+    pub data_field: u8,
+    pub instruction_buffer: u8,
+    pub save_field: u8, // IF in bits 3-5, DF in bits 0-2.
+    pub interrupt_inhibit: bool,
+}
+impl MemoryExtension {
+    pub fn default() -> MemoryExtension {
+        MemoryExtension {
+            instruction_field: 0,
+            data_field: 0,
+            instruction_buffer: 0,
+            save_field: 0,
+            interrupt_inhibit: false,
+        }
+    }
+
+    /// Handles an IOT if it is for us, returning the new AC.
+    pub fn iot(&mut self, instruction: u12, ac: u12) -> Option<u12> {
+        let device = instruction.field(3, 8);
+        if !(FIRST_DEVICE..=LAST_DEVICE).contains(&device) {
+            return None;
+        }
+        let field = instruction.field(6, 8) as u8;
+        let pulse = instruction.field(9, 11);
+        if pulse == READ {
+            let ac = u16::from(ac);
+            return Some(
+                match field {
+                    1 => ac | u16::from(self.data_field) << 3,
+                    2 => ac | u16::from(self.instruction_field) << 3,
+                    3 => ac | u16::from(self.save_field),
+                    4 => {
+                        self.instruction_buffer = self.save_field >> 3;
+                        self.data_field = self.save_field & 0o7;
+                        self.interrupt_inhibit = true;
+                        ac
+                    }
+                    _ => ac,
+                }
+                .into(),
+            );
+        }
+        if pulse & CDF != 0 {
+            self.data_field = field;
+        }
+        if pulse & CIF != 0 {
+            self.instruction_buffer = field;
+            self.interrupt_inhibit = true;
+        }
+        Some(ac)
+    }
+
+    /// A JMP or JMS, into the field the IB says.
+    pub fn jump(&mut self) {
+        self.instruction_field = self.instruction_buffer;
+        self.interrupt_inhibit = false;
+    }
+
+    /// A program interrupt, the service routine runs in field 0.
+    pub fn interrupt(&mut self) {
+        self.save_field = self.instruction_field << 3 | self.data_field;
+        self.instruction_field = 0;
+        self.instruction_buffer = 0;
+        self.data_field = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MachineState;
+    use crate::consts::*;
+    use crate::iot_bus::{IotDevice, IotResponse};
+    use crate::scheduler::Events;
+
+    fn machine(fields: u8, program: &[(u8, u16, u16)]) -> MachineState {
+        let mut pdp5 = MachineState::with_fields([0.into(); 4096], fields);
+        for (field, address, word) in program {
+            pdp5.memory.set(*field, (*address).into(), (*word).into());
+        }
+        pdp5.set_initial_start_address(0o200);
+        pdp5
+    }
+
+    fn extension(pdp5: &MachineState) -> MemoryExtension {
+        *pdp5.memory.extension().unwrap()
+    }
+
+    #[test]
+    fn test_iots() {
+        let mut extension = MemoryExtension::default();
+        assert_eq!(extension.iot(0o6041.into(), 0.into()), None);
+        assert_eq!(extension.iot(0o6231.into(), 0.into()), Some(0.into())); // CDF 3
+        assert_eq!(extension.data_field, 3);
+        assert_eq!(extension.iot(0o6252.into(), 0.into()), Some(0.into())); // CIF 5
+        assert_eq!(extension.instruction_buffer, 5);
+        assert_eq!(extension.instruction_field, 0);
+        assert!(extension.interrupt_inhibit);
+        assert_eq!(extension.iot(0o6214.into(), 0o7.into()), Some(0o37.into())); // RDF
+        extension.jump();
+        assert_eq!(extension.instruction_field, 5);
+        assert!(!extension.interrupt_inhibit);
+        assert_eq!(extension.iot(0o6224.into(), 0.into()), Some(0o50.into())); // RIF
+        extension.interrupt();
+        assert_eq!(extension.save_field, 0o53);
+        assert_eq!(extension.iot(0o6234.into(), 0.into()), Some(0o53.into())); // RIB
+        assert_eq!(extension.instruction_field, 0);
+        assert_eq!(extension.data_field, 0);
+        extension.iot(0o6244.into(), 0.into()); // RMF
+        assert_eq!(extension.instruction_buffer, 5);
+        assert_eq!(extension.data_field, 3);
+        assert_eq!(extension.instruction_field, 0);
+        extension.iot(0o6273.into(), 0.into()); // CDF CIF 7
+        assert_eq!(extension.data_field, 7);
+        assert_eq!(extension.instruction_buffer, 7);
+    }
+
+    #[test]
+    fn test_four_k_is_unchanged() {
+        // Without the extension 62N1 goes out on the bus like any other IOT.
+        let mut pdp5 = machine(1, &[(0, 0o200, 0o6211), (0, 0o201, 0o7402)]);
+        assert!(pdp5.memory.extension().is_none());
+        assert_eq!(pdp5.memory.fields(), 1);
+        pdp5.start_program(100);
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o202.into());
+    }
+
+    #[test]
+    fn test_data_field() {
+        let mut pdp5 = machine(
+            2,
+            &[
+                (0, 0o200, 0o6211), // CDF 1
+                (0, 0o201, 0o1650), // TAD I 250, from field 1
+                (0, 0o202, 0o1250), // TAD 250, direct so from field 0
+                (0, 0o203, 0o3651), // DCA I 251, into field 1
+                (0, 0o204, 0o2652), // ISZ I 252, in field 1
+                (0, 0o205, 0o7402), // HLT
+                (0, 0o206, 0o7402), // HLT
+                (0, 0o250, 0o0300), // Pointers are in the instruction field.
+                (0, 0o251, 0o0301),
+                (0, 0o252, 0o0302),
+                (0, 0o300, 0o0007),
+                (1, 0o300, 0o0040),
+                (1, 0o302, 0o7777),
+            ],
+        );
+        pdp5.start_program(100);
+        assert_eq!(pdp5.memory.get(1, 0o301.into()), 0o340.into());
+        assert_eq!(pdp5.memory.get(0, 0o301.into()), 0.into());
+        assert_eq!(pdp5.memory.get(1, 0o302.into()), 0.into());
+        // The ISZ skipped the HLT.
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o207.into());
+    }
+
+    #[test]
+    fn test_autoindex_is_in_the_instruction_field() {
+        let mut pdp5 = machine(
+            2,
+            &[
+                (0, 0o200, 0o6211), // CDF 1
+                (0, 0o201, 0o1410), // TAD I 10
+                (0, 0o202, 0o7402), // HLT
+                (0, 0o010, 0o0377),
+                (1, 0o400, 0o1234),
+            ],
+        );
+        pdp5.start_program(100);
+        assert_eq!(pdp5.memory[0o10.into()], 0o400.into());
+        assert_eq!(pdp5.memory.get(1, 0o10.into()), 0.into());
+        assert_eq!(pdp5.registers.hardware_registers.AC, 0o1234.into());
+    }
+
+    #[test]
+    fn test_jump_to_another_field() {
+        let mut pdp5 = machine(
+            4,
+            &[
+                (0, 0o200, 0o6232), // CIF 3
+                (0, 0o201, 0o7001), // IAC, still in field 0
+                (0, 0o202, 0o4250), // JMS 250, into field 3
+                (3, 0o250, 0o0000),
+                (3, 0o251, 0o6224), // RIF
+                (3, 0o252, 0o6202), // CIF 0
+                (3, 0o253, 0o5650), // JMP I 250, back to field 0
+                (0, 0o203, 0o7402), // HLT
+            ],
+        );
+        pdp5.start_program(100);
+        assert_eq!(pdp5.registers.hardware_registers.AC, 0o31.into());
+        assert_eq!(pdp5.memory.get(3, 0o250.into()), 0o203.into());
+        assert_eq!(pdp5.memory.get(0, 0o250.into()), 0.into());
+        assert_eq!(extension(&pdp5).instruction_field, 0);
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o204.into());
+    }
+
+    /// Wants an interrupt until it is told to stop with 6402.
+    struct Interrupter {
+        wanted: bool,
+    }
+    impl IotDevice for Interrupter {
+        fn device_codes(&self) -> &[u8] {
+            &[0o40]
+        }
+        fn pulse(
+            &mut self,
+            _device_code: u8,
+            pulse: u8,
+            ac: u12,
+            _events: &mut Events,
+        ) -> IotResponse {
+            if pulse == 2 {
+                self.wanted = false;
+            }
+            IotResponse::unchanged(ac)
+        }
+        fn interrupt_request(&self) -> bool {
+            self.wanted
+        }
+    }
+
+    #[test]
+    fn test_interrupt_saves_the_fields() {
+        let mut pdp5 = machine(
+            2,
+            &[
+                // In field 0.
+                (0, 0o200, 0o6212), // CIF 1
+                (0, 0o201, 0o6211), // CDF 1
+                (0, 0o202, 0o5203), // JMP 203, into field 1
+                // Service routine, field 0.
+                (0, 0o002, 0o6402), // Stop asking.
+                (0, 0o003, 0o3101), // DCA 101, the AC
+                (0, 0o004, 0o6234), // RIB
+                (0, 0o005, 0o3100), // DCA 100, in field 0
+                (0, 0o006, 0o1101), // TAD 101
+                (0, 0o007, 0o6244), // RMF
+                (0, 0o010, 0o6001), // ION
+                (0, 0o011, 0o5401), // JMP I 1
+                // In field 1, interrupts come on here.
+                (1, 0o203, 0o6001), // ION
+                (1, 0o204, 0o7001), // IAC, interrupted after this
+                (1, 0o205, 0o7001), // IAC
+                (1, 0o206, 0o7402), // HLT
+            ],
+        );
+        pdp5.iot_bus.attach(Box::new(Interrupter { wanted: true }));
+        pdp5.start_program(200);
+        assert_eq!(pdp5.memory[0o100.into()], 0o11.into());
+        assert_eq!(pdp5.registers.hardware_registers.AC, 2.into());
+        assert_eq!(pdp5.memory[INTERRUPT_RETURN_ADDRESS.into()], 0o205.into());
+        assert_eq!(extension(&pdp5).instruction_field, 1);
+        assert_eq!(extension(&pdp5).data_field, 1);
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o207.into());
+    }
+
+    #[test]
+    fn test_cif_holds_off_interrupts() {
+        let mut pdp5 = machine(
+            2,
+            &[
+                (0, 0o200, 0o6001), // ION
+                (0, 0o201, 0o6212), // CIF 1
+                (0, 0o202, 0o7000), // NOP, no interrupt yet
+                (0, 0o203, 0o5204), // JMP 204, into field 1
+                (1, 0o204, 0o7402), // HLT
+                (0, 0o002, 0o7402), // HLT
+            ],
+        );
+        pdp5.iot_bus.attach(Box::new(Interrupter { wanted: true }));
+        pdp5.start_program(100);
+        // Interrupted once the JMP had got to field 1.
+        assert_eq!(pdp5.memory[INTERRUPT_RETURN_ADDRESS.into()], 0o204.into());
+        assert_eq!(extension(&pdp5).save_field, 0o10);
+    }
+}
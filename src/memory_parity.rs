@@ -0,0 +1,366 @@
+use crate::iot_bus::IotResponse;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::unsigned_integer_12::u12;
+use std::io::Error;
+
+// Memory parity option, and faults to inject into the core to see what programs do when
+// it lets them down.
+//
+// With parity each word has a 13th bit, made from the MB on every write so that the 13
+// bits have an odd number of ones, and checked on every read. A word that doesn't agree
+// with its parity bit sets the parity error flag, which asks for a program interrupt
+// until it is cleared. It is device 10:
+//   6101 SMP  Skip if there hasn't been a parity error.
+//   6104 CMP  Clear the parity error flag.
+//
+// Rather than the parity bits themselves we keep whether each one disagrees with its word,
+// so words put in by indexing, which doesn't go through here, aren't all errors.
+//
+// Faults are given on the command line as kind:address:value, with the address in field 0:
+//   flip:0300:0004    Bits of the word change, as if hit by noise. Its parity bit doesn't,
+//                     so an odd number of flipped bits is caught on the next read.
+//   stick0:0300:4000  Bits read and write as 0 whatever is stored, until cleared.
+//   stick1:0300:4000  The same stuck at 1.
+//   fail:0300:2       The next 2 reads of the word get a parity error whatever it holds.
+
+const DEVICE: u16 = 0o10;
+const SMP: u16 = 0o1;
+const CMP: u16 = 0o4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Flip {
+        field: u8,
+        address: u12,
+        bits: u12,
+    },
+    Stick {
+        field: u8,
+        address: u12,
+        bits: u12,
+        value: bool,
+    },
+    FailReads {
+        field: u8,
+        address: u12,
+        count: u32,
+    },
+}
+impl Fault {
+    pub fn parse(text: &str) -> Result<Fault, String> {
+        let parts: Vec<&str> = text.split(':').collect();
+        let [kind, address, value] = parts[..] else {
+            return Err(format!("Expected kind:address:value, got {text}"));
+        };
+        let octal = |text: &str| match u16::from_str_radix(text, 8) {
+            Ok(value) if value <= 0o7777 => Ok(u12::from(value)),
+            _ => Err(format!("Invalid octal number: {text}")),
+        };
+        let (field, address, bits) = (0, octal(address)?, octal(value));
+        Ok(match kind {
+            "flip" => Fault::Flip {
+                field,
+                address,
+                bits: bits?,
+            },
+            "stick0" | "stick1" => Fault::Stick {
+                field,
+                address,
+                bits: bits?,
+                value: kind == "stick1",
+            },
+            "fail" => Fault::FailReads {
+                field,
+                address,
+                count: value
+                    .parse()
+                    .map_err(|_| format!("Invalid count: {value}"))?,
+            },
+            _ => return Err(format!("Unknown fault: {kind}")),
+        })
+    }
+
+    /// Whether this is a lasting fault on the word at field and address.
+    pub fn is_at(&self, field: u8, address: u12) -> bool {
+        match *self {
+            Fault::Flip { .. } => false,
+            Fault::Stick {
+                field: at_field,
+                address: at,
+                ..
+            }
+            | Fault::FailReads {
+                field: at_field,
+                address: at,
+                ..
+            } => at_field == field && at == address,
+        }
+    }
+}
+
+/// Odd parity, the bit that makes the number of ones odd.
+pub fn parity_bit(word: u12) -> bool {
+    u16::from(word).count_ones().is_multiple_of(2)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryParity {
+    wrong: Vec<bool>,         // For each word, its parity bit disagrees with it.
+    error: Option<(u8, u12)>, // The field and address of the first error since cleared.
+}
+impl MemoryParity {
+    pub fn new(words: usize) -> MemoryParity {
+        MemoryParity {
+            wrong: vec![false; words],
+            error: None,
+        }
+    }
+
+    /// The parity bit that goes with stored, as it would be in the 13th bit of the core.
+    pub fn bit(&self, slot: usize, stored: u12) -> bool {
+        parity_bit(stored) != self.wrong[slot]
+    }
+
+    /// A write of value, the parity bit is made from that, but stored is what the core
+    /// ended up holding.
+    pub fn write(&mut self, slot: usize, value: u12, stored: u12) {
+        self.wrong[slot] = parity_bit(value) != parity_bit(stored);
+    }
+
+    /// Some of a stored word's bits have been changed behind our back.
+    pub fn flip(&mut self, slot: usize, bits: u12) {
+        if u16::from(bits).count_ones() % 2 == 1 {
+            self.wrong[slot] = !self.wrong[slot];
+        }
+    }
+
+    /// A read of value, made from stored by any stuck bits.
+    pub fn read(&mut self, slot: usize, stored: u12, value: u12, failed: bool, at: (u8, u12)) {
+        if failed || self.bit(slot, stored) != parity_bit(value) {
+            self.error.get_or_insert(at);
+        }
+    }
+
+    pub fn error(&self) -> Option<(u8, u12)> {
+        self.error
+    }
+
+    #[cfg(test)]
+    pub fn clear_error(&mut self) {
+        self.error = None;
+    }
+
+    /// Handles an IOT if it is for us.
+    pub fn iot(&mut self, instruction: u12, ac: u12) -> Option<IotResponse> {
+        if instruction.field(3, 8) != DEVICE {
+            return None;
+        }
+        let pulse = instruction.field(9, 11);
+        let skip = pulse & SMP != 0 && self.error.is_none();
+        if pulse & CMP != 0 {
+            self.error = None;
+        }
+        Some(IotResponse { ac, skip })
+    }
+
+    pub fn save(&self, writer: &mut SnapshotWriter) {
+        for wrong in &self.wrong {
+            writer.bool(*wrong);
+        }
+        match self.error {
+            Some((field, address)) => {
+                writer.bool(true);
+                writer.u8(field);
+                writer.u12(address);
+            }
+            None => writer.bool(false),
+        }
+    }
+
+    pub fn load(reader: &mut SnapshotReader, words: usize) -> Result<MemoryParity, Error> {
+        let mut parity = MemoryParity::new(words);
+        for wrong in parity.wrong.iter_mut() {
+            *wrong = reader.bool()?;
+        }
+        if reader.bool()? {
+            parity.error = Some((reader.u8()?, reader.u12()?));
+        }
+        Ok(parity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MachineState;
+    use crate::consts::*;
+    use crate::memory::Memory;
+
+    fn memory() -> Memory {
+        let mut memory = Memory::default([0.into(); 4096]);
+        memory.enable_parity();
+        memory
+    }
+
+    fn error(memory: &Memory) -> Option<(u8, u12)> {
+        memory.parity().unwrap().error()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Fault::parse("flip:0300:0004"),
+            Ok(Fault::Flip {
+                field: 0,
+                address: 0o300.into(),
+                bits: 0o4.into()
+            })
+        );
+        assert_eq!(
+            Fault::parse("stick1:7777:4000"),
+            Ok(Fault::Stick {
+                field: 0,
+                address: 0o7777.into(),
+                bits: 0o4000.into(),
+                value: true
+            })
+        );
+        assert_eq!(
+            Fault::parse("fail:0300:10"),
+            Ok(Fault::FailReads {
+                field: 0,
+                address: 0o300.into(),
+                count: 10
+            })
+        );
+        assert!(Fault::parse("flip:0300").is_err());
+        assert!(Fault::parse("flip:0800:1").is_err());
+        assert!(Fault::parse("melt:0300:1").is_err());
+        assert!(Fault::parse("fail:0300:x").is_err());
+    }
+
+    #[test]
+    fn test_parity_bit() {
+        assert!(parity_bit(0.into()));
+        assert!(!parity_bit(0o4000.into()));
+        assert!(parity_bit(0o4001.into()));
+    }
+
+    #[test]
+    fn test_flips() {
+        let mut memory = memory();
+        memory.write(0o300.into(), 0o1234.into());
+        assert_eq!(memory.read(0o300.into()), 0o1234.into());
+        assert_eq!(error(&memory), None);
+        // Two bits get past parity.
+        memory.inject(Fault::Flip {
+            field: 0,
+            address: 0o300.into(),
+            bits: 0o3.into(),
+        });
+        assert_eq!(memory.read(0o300.into()), 0o1237.into());
+        assert_eq!(error(&memory), None);
+        memory.inject(Fault::Flip {
+            field: 0,
+            address: 0o300.into(),
+            bits: 0o4000.into(),
+        });
+        assert_eq!(memory.read(0o300.into()), 0o5237.into());
+        assert_eq!(error(&memory), Some((0, 0o300.into())));
+        // Writing it again puts it right, the flag stays until cleared.
+        memory.parity_mut().unwrap().clear_error();
+        memory.write(0o300.into(), 0o5237.into());
+        memory.read(0o300.into());
+        assert_eq!(error(&memory), None);
+        // Nothing is left behind.
+        assert!(memory.faults().is_empty());
+    }
+
+    #[test]
+    fn test_stuck_bits() {
+        let mut memory = memory();
+        memory.inject(Fault::Stick {
+            field: 0,
+            address: 0o300.into(),
+            bits: 0o4000.into(),
+            value: true,
+        });
+        memory.write(0o300.into(), 0o0001.into());
+        assert_eq!(memory.read(0o300.into()), 0o4001.into());
+        assert_eq!(error(&memory), Some((0, 0o300.into())));
+        // Other words are fine.
+        memory.parity_mut().unwrap().clear_error();
+        memory.write(0o301.into(), 0o0001.into());
+        assert_eq!(memory.read(0o301.into()), 0o0001.into());
+        assert_eq!(error(&memory), None);
+        // Stuck at 0 on a bit that is already 0 does nothing.
+        memory.clear_faults();
+        memory.inject(Fault::Stick {
+            field: 0,
+            address: 0o301.into(),
+            bits: 0o0002.into(),
+            value: false,
+        });
+        assert_eq!(memory.read(0o301.into()), 0o0001.into());
+        assert_eq!(error(&memory), None);
+
+        // Without parity nobody notices.
+        let mut memory = Memory::default([0.into(); 4096]);
+        memory.inject(Fault::Stick {
+            field: 0,
+            address: 0o300.into(),
+            bits: 0o7.into(),
+            value: false,
+        });
+        memory.write(0o300.into(), 0o7777.into());
+        assert_eq!(memory.read(0o300.into()), 0o7770.into());
+        assert!(!memory.interrupt_request());
+    }
+
+    #[test]
+    fn test_failed_reads() {
+        let mut memory = memory();
+        memory.inject(Fault::FailReads {
+            field: 0,
+            address: 0o300.into(),
+            count: 2,
+        });
+        for _ in 0..2 {
+            memory.read(0o300.into());
+            assert!(memory.interrupt_request());
+            memory.parity_mut().unwrap().clear_error();
+        }
+        memory.read(0o300.into());
+        assert!(!memory.interrupt_request());
+    }
+
+    #[test]
+    fn test_error_interrupts_the_program() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o002, 0o6101), // SMP
+            (0o003, 0o5005), // JMP 5, there was an error
+            (0o004, 0o7402), // HLT, there wasn't
+            (0o005, 0o6104), // CMP
+            (0o006, 0o7402), // HLT
+            (0o200, 0o6001), // ION
+            (0o201, 0o7200), // CLA
+            (0o202, 0o1300), // TAD 300
+            (0o203, 0o5201), // JMP 201
+            (0o300, 0o0042),
+        ]);
+        pdp5.memory.enable_parity();
+        pdp5.inject_fault_at(
+            50,
+            Fault::Flip {
+                field: 0,
+                address: 0o300.into(),
+                bits: 0o1.into(),
+            },
+        );
+        pdp5.start_program(200);
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o7.into());
+        assert_eq!(pdp5.registers.hardware_registers.AC, 0o43.into());
+        assert_eq!(error(&pdp5.memory), None);
+        assert!(pdp5.scheduled_faults.is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::consts::*;
+use crate::{MachineState, StopReason};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Devices only ever see simulated time, CYCLE_TIME_NS for every major cycle, so their
+// timing is right however fast the host goes. Pacing is just about the operator: the
+// machine runs a slice of cycles then waits for the host clock to catch up with it.
+
+const SLICE_CYCLES: u64 = 1000; // 6ms of PDP5 time, short enough not to look jerky.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    Multiple(f64), // Of real PDP5 speed, 1 is real time.
+    Unthrottled,
+}
+impl Speed {
+    /// A multiple like 1 or 0.5, or max for as fast as the host will go.
+    pub fn parse(text: &str) -> Option<Speed> {
+        if text.eq_ignore_ascii_case("max") {
+            return Some(Speed::Unthrottled);
+        }
+        match text.parse::<f64>() {
+            Ok(multiple) if multiple > 0.0 && multiple.is_finite() => {
+                Some(Speed::Multiple(multiple))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct Pacer {
+    speed: Speed,
+    host_start: Instant,
+    simulated_start: u64, // Simulated ns when the host clock was started.
+}
+impl Pacer {
+    pub fn new(speed: Speed) -> Pacer {
+        Pacer {
+            speed,
+            host_start: Instant::now(),
+            simulated_start: 0,
+        }
+    }
+
+    /// Start keeping time from here, after the machine has been stopped for a while
+    /// so it doesn't rush to make up for it.
+    pub fn reset(&mut self, simulated_ns: u64) {
+        self.host_start = Instant::now();
+        self.simulated_start = simulated_ns;
+    }
+
+    /// How far the machine has got ahead of the host clock.
+    pub fn ahead(&self, simulated_ns: u64) -> Duration {
+        match self.speed {
+            Speed::Unthrottled => Duration::ZERO,
+            Speed::Multiple(multiple) => {
+                let simulated = simulated_ns.saturating_sub(self.simulated_start) as f64;
+                let due = Duration::from_nanos((simulated / multiple) as u64);
+                due.saturating_sub(self.host_start.elapsed())
+            }
+        }
+    }
+
+    /// Wait until the host clock has caught up.
+    pub fn pace(&self, simulated_ns: u64) {
+        let ahead = self.ahead(simulated_ns);
+        if !ahead.is_zero() {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+impl MachineState {
+    /// How long the machine has been running in PDP5 time, breaks included.
+    pub fn simulated_ns(&self) -> u64 {
+        self.cycles * CYCLE_TIME_NS as u64
+    }
+
+    /// start_program at the pacer's speed, timed from now.
+    pub fn run_paced(&mut self, pacer: &mut Pacer, max_cycles: u64) -> StopReason {
+        pacer.reset(self.simulated_ns());
+        let mut remaining = max_cycles;
+        while remaining > 0 {
+            let slice = remaining.min(SLICE_CYCLES);
+            remaining -= slice;
+            match self.start_program(slice) {
+                StopReason::CycleLimit => pacer.pace(self.simulated_ns()),
+                stop => return stop,
+            }
+        }
+        StopReason::CycleLimit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_protect::ProtectedRange;
+
+    fn machine() -> MachineState {
+        MachineState::with_program(&[(0o200, 0o5200)]) // JMP 200
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Speed::parse("1"), Some(Speed::Multiple(1.0)));
+        assert_eq!(Speed::parse("0.5"), Some(Speed::Multiple(0.5)));
+        assert_eq!(Speed::parse("MAX"), Some(Speed::Unthrottled));
+        assert_eq!(Speed::parse("0"), None);
+        assert_eq!(Speed::parse("fast"), None);
+    }
+
+    #[test]
+    fn test_ahead() {
+        let mut pacer = Pacer::new(Speed::Multiple(2.0));
+        pacer.reset(1_000_000);
+        // A second of PDP5 time is half a second at double speed.
+        let ahead = pacer.ahead(1_001_000_000);
+        assert!(ahead <= Duration::from_millis(500));
+        assert!(ahead > Duration::from_millis(400));
+        assert_eq!(pacer.ahead(0), Duration::ZERO);
+        assert_eq!(
+            Pacer::new(Speed::Unthrottled).ahead(u64::MAX),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_real_time() {
+        let mut pdp5 = machine();
+        let mut pacer = Pacer::new(Speed::Multiple(1.0));
+        let start = Instant::now();
+        // 3000 cycles are 18ms of PDP5 time.
+        assert_eq!(pdp5.run_paced(&mut pacer, 3000), StopReason::CycleLimit);
+        assert_eq!(pdp5.simulated_ns(), 18_000_000);
+        assert!(start.elapsed() >= Duration::from_millis(18));
+    }
+
+    #[test]
+    fn test_unthrottled_stops_at_a_halt() {
+        let mut pdp5 = machine();
+        pdp5.memory[0o200.into()] = 0o7402.into(); // HLT
+        let mut pacer = Pacer::new(Speed::Unthrottled);
+        assert_eq!(pdp5.run_paced(&mut pacer, 1_000_000), StopReason::Halted);
+        assert_eq!(pdp5.simulated_ns(), 2 * CYCLE_TIME_NS as u64);
+    }
+
+    /// NOPs from 200 then the instruction, starting at 200 so the NOPs take 2 cycles each.
+    fn after_nops(nops: u16, instruction: u16) -> MachineState {
+        let mut pdp5 = machine();
+        for address in 0o200..0o200 + nops {
+            pdp5.memory[address.into()] = 0o7000.into(); // NOP
+        }
+        pdp5.memory[(0o200 + nops).into()] = instruction.into();
+        pdp5
+    }
+
+    #[test]
+    fn test_halt_on_a_slice_boundary() {
+        // The HLT's F cycle is the last of the first slice, its P the first of the next.
+        let mut pdp5 = after_nops(499, 0o7402);
+        pdp5.memory[0o200.into()] = 0o1200.into(); // TAD 200, one cycle more than a NOP.
+        let mut pacer = Pacer::new(Speed::Unthrottled);
+        assert_eq!(pdp5.run_paced(&mut pacer, 5000), StopReason::Halted);
+        assert_eq!(pdp5.cycles, SLICE_CYCLES + 1);
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], (0o200 + 500).into());
+    }
+
+    #[test]
+    fn test_trap_on_a_slice_boundary() {
+        // The DCA's E1, the protected write, is the last cycle of the first slice.
+        let mut pdp5 = after_nops(499, 0o3100); // DCA 100
+        pdp5.memory.protect(ProtectedRange::parse("100").unwrap());
+        let mut pacer = Pacer::new(Speed::Unthrottled);
+        let stop = pdp5.run_paced(&mut pacer, 5000);
+        assert!(matches!(stop, StopReason::Protected(write) if write.address == 0o100.into()));
+        assert_eq!(pdp5.cycles, SLICE_CYCLES + 1);
+    }
+}
@@ -0,0 +1,282 @@
+use crate::disassembler::disassemble_with_symbols;
+use crate::instruction::OpCode;
+use crate::memory::{Memory, MemoryAccess, MemoryAccessKind};
+use crate::symbols::Symbols;
+use crate::unsigned_integer_12::u12;
+use std::fmt::Write;
+
+// Where a program spends its time. Every cycle is put down to the page of the instruction
+// it belongs to, break cycles included since they slow that code down, and every fetch counts
+// as an execution of its address. Reads and writes come off the memory access log, leaving
+// out the P cycle moving the PC on, otherwise location 0 would top every list.
+
+const PAGES: usize = 32;
+const WORDS_PER_PAGE: usize = 128;
+const OPCODES: [OpCode; 8] = [
+    OpCode::And,
+    OpCode::Tad,
+    OpCode::Isz,
+    OpCode::Dca,
+    OpCode::Jms,
+    OpCode::Jmp,
+    OpCode::Iot,
+    OpCode::Operate,
+];
+// Busier pages get later characters, a page nothing ran on is a space.
+const SHADES: [char; 10] = [' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+
+pub struct Profiler {
+    executions: Vec<u64>,
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+    classes: [u64; 8], // Instructions done by opcode, in OPCODES order.
+    page_cycles: [u64; PAGES],
+    cycles: u64,
+    instructions: u64,
+}
+impl Profiler {
+    pub fn default() -> Profiler {
+        Profiler {
+            executions: vec![0; 4096],
+            reads: vec![0; 4096],
+            writes: vec![0; 4096],
+            classes: [0; 8],
+            page_cycles: [0; PAGES],
+            cycles: 0,
+            instructions: 0,
+        }
+    }
+
+    /// A Fetch of the instruction at pc.
+    pub fn note_fetch(&mut self, pc: u12, instruction: u12) {
+        self.executions[usize::from(pc)] += 1;
+        let opcode = OpCode::from_instruction(instruction);
+        let class = OPCODES.iter().position(|&each| each == opcode).unwrap();
+        self.classes[class] += 1;
+        self.instructions += 1;
+    }
+
+    /// A cycle done for the instruction at pc, with the memory accesses it made.
+    pub fn note_cycle(&mut self, pc: u12, accesses: &[MemoryAccess], program_counter: bool) {
+        self.cycles += 1;
+        self.page_cycles[usize::from(pc) / WORDS_PER_PAGE] += 1;
+        if program_counter {
+            return;
+        }
+        for access in accesses {
+            let address = usize::from(access.address);
+            match access.kind {
+                MemoryAccessKind::Read => self.reads[address] += 1,
+                MemoryAccessKind::Write => self.writes[address] += 1,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn executions(&self, address: u12) -> u64 {
+        self.executions[usize::from(address)]
+    }
+
+    #[cfg(test)]
+    pub fn reads(&self, address: u12) -> u64 {
+        self.reads[usize::from(address)]
+    }
+
+    #[cfg(test)]
+    pub fn writes(&self, address: u12) -> u64 {
+        self.writes[usize::from(address)]
+    }
+
+    #[cfg(test)]
+    pub fn class(&self, opcode: OpCode) -> u64 {
+        let class = OPCODES.iter().position(|&each| each == opcode).unwrap();
+        self.classes[class]
+    }
+
+    /// Cycles spent on instructions in page, 0 to 037.
+    #[cfg(test)]
+    pub fn page_cycles(&self, page: usize) -> u64 {
+        self.page_cycles[page]
+    }
+
+    /// The top busiest addresses by score, busiest first and lower addresses first on a tie.
+    fn busiest(&self, top: usize, score: impl Fn(usize) -> u64) -> Vec<usize> {
+        let mut addresses: Vec<usize> = (0..4096).filter(|&address| score(address) > 0).collect();
+        addresses.sort_by_key(|&address| (std::cmp::Reverse(score(address)), address));
+        addresses.truncate(top);
+        addresses
+    }
+
+    fn percent(count: u64, total: u64) -> f64 {
+        match total {
+            0 => 0.0,
+            _ => count as f64 * 100.0 / total as f64,
+        }
+    }
+
+    /// Addresses described by name where there is one, and how wide a column needs
+    /// to be for them with room to spare.
+    fn describe(addresses: &[usize], symbols: &Symbols) -> (Vec<String>, usize) {
+        let described: Vec<String> = addresses
+            .iter()
+            .map(|&address| symbols.describe(address.into(), 4))
+            .collect();
+        let width = described.iter().map(String::len).max().unwrap_or(4) + 5;
+        (described, width)
+    }
+
+    /// The hottest top instructions with their disassembly, the most used data,
+    /// instructions by class and a map of the cycles spent on each page.
+    pub fn report(&self, memory: &Memory, symbols: &Symbols, top: usize) -> String {
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "{} cycles, {} instructions\n",
+            self.cycles, self.instructions
+        );
+
+        let hottest = self.busiest(top, |address| self.executions[address]);
+        let (described, width) = Profiler::describe(&hottest, symbols);
+        let _ = writeln!(report, "Hottest instructions");
+        let _ = writeln!(report, "{:width$}Executed      %  Instruction", "Address");
+        for (&address, location) in hottest.iter().zip(&described) {
+            let count = self.executions[address];
+            let _ = writeln!(
+                report,
+                "{location:width$}{count:8}  {:5.1}  {}",
+                Profiler::percent(count, self.instructions),
+                disassemble_with_symbols(address.into(), memory[address.into()], symbols)
+            );
+        }
+
+        let busiest = self.busiest(top, |address| self.reads[address] + self.writes[address]);
+        let (described, width) = Profiler::describe(&busiest, symbols);
+        let _ = writeln!(report, "\nBusiest data");
+        let _ = writeln!(report, "{:width$}   Reads    Writes", "Address");
+        for (&address, location) in busiest.iter().zip(&described) {
+            let _ = writeln!(
+                report,
+                "{location:width$}{:8}  {:8}",
+                self.reads[address], self.writes[address]
+            );
+        }
+
+        let _ = writeln!(report, "\nInstruction classes");
+        for (opcode, count) in OPCODES.iter().zip(self.classes) {
+            let name = match opcode {
+                OpCode::And => "AND",
+                OpCode::Tad => "TAD",
+                OpCode::Isz => "ISZ",
+                OpCode::Dca => "DCA",
+                OpCode::Jms => "JMS",
+                OpCode::Jmp => "JMP",
+                OpCode::Iot => "IOT",
+                OpCode::Operate => "OPR",
+            };
+            let _ = writeln!(
+                report,
+                "{name}  {count:10}  {:5.1}%",
+                Profiler::percent(count, self.instructions)
+            );
+        }
+
+        let _ = writeln!(
+            report,
+            "\nCycles per page, eight pages a row, darker is busier"
+        );
+        let busiest = self.page_cycles.iter().copied().max().unwrap_or(0);
+        for row in self.page_cycles.chunks(8).enumerate() {
+            let (row, pages) = row;
+            let shades: String = pages
+                .iter()
+                .map(|&cycles| match (cycles, busiest) {
+                    (0, _) => SHADES[0],
+                    // Anything at all gets at least the lightest shade.
+                    _ => SHADES[(cycles * 9).div_ceil(busiest) as usize],
+                })
+                .collect();
+            let first = row * 8 * WORDS_PER_PAGE;
+            let _ = writeln!(report, "{first:04o}  |{shades}|");
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MachineState;
+    use crate::symbols::Symbols;
+
+    fn machine() -> MachineState {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o1300), // TAD 300
+            (0o201, 0o2301), // ISZ 301
+            (0o202, 0o5200), // JMP 200
+            (0o203, 0o7402), // HLT
+            (0o300, 0o0001),
+            (0o301, 0o7775), // Three times round.
+        ]);
+        pdp5.enable_profiler();
+        pdp5
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut pdp5 = machine();
+        pdp5.start_program(1000);
+        let profiler = pdp5.profiler.as_ref().unwrap();
+        assert_eq!(profiler.executions(0o200.into()), 3);
+        assert_eq!(profiler.executions(0o202.into()), 2);
+        assert_eq!(profiler.executions(0o203.into()), 1);
+        assert_eq!(profiler.reads(0o300.into()), 3);
+        assert_eq!(profiler.reads(0o301.into()), 3);
+        assert_eq!(profiler.writes(0o301.into()), 3);
+        // Only the program's own use of the PC, not the P cycle's.
+        assert_eq!(profiler.reads(0.into()), 0);
+        assert_eq!(profiler.writes(0.into()), 0);
+        assert_eq!(profiler.class(super::OpCode::Tad), 3);
+        assert_eq!(profiler.class(super::OpCode::Operate), 1);
+        assert_eq!(profiler.page_cycles(1), pdp5.cycles);
+        assert_eq!(profiler.page_cycles(0), 0);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut pdp5 = machine();
+        pdp5.start_program(1000);
+        let symbols = Symbols::default();
+        let report = pdp5
+            .profiler
+            .as_ref()
+            .unwrap()
+            .report(&pdp5.memory, &symbols, 2);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "24 cycles, 9 instructions");
+        assert_eq!(lines[3], "Address  Executed      %  Instruction");
+        assert_eq!(lines[4], "0200            3   33.3  TAD 0300");
+        assert_eq!(lines[5], "0201            3   33.3  ISZ 0301");
+        assert!(report.contains("\nAddress     Reads    Writes\n"));
+        assert!(report.contains("0301            3         3"));
+        assert!(report.contains("TAD           3   33.3%"));
+        // All the time on page 1.
+        assert!(report.contains("0000  | @      |"));
+    }
+
+    #[test]
+    fn test_report_with_symbols() {
+        let mut pdp5 = machine();
+        pdp5.start_program(1000);
+        let symbols = Symbols::parse("LOOP=0200 ONE=0300 COUNT=0301").unwrap();
+        let report = pdp5
+            .profiler
+            .as_ref()
+            .unwrap()
+            .report(&pdp5.memory, &symbols, 2);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[3], "Address           Executed      %  Instruction");
+        assert_eq!(lines[4], "0200 <LOOP>              3   33.3  TAD ONE");
+        assert_eq!(lines[5], "0201 <LOOP+1>            3   33.3  ISZ COUNT");
+        assert!(report.contains("\nAddress             Reads    Writes\n"));
+        assert!(report.contains("\n0301 <COUNT>            3         3\n"));
+    }
+}
@@ -0,0 +1,356 @@
+use crate::breakpoints::watched_accesses;
+use crate::consts::*;
+use crate::memory::{MemoryAccess, MemoryAccessKind};
+use crate::memory_extension::MemoryExtension;
+use crate::unsigned_integer_12::u12;
+use crate::{CycleState, HWRegisters, Interrupts, MachineState, ProgramCounterUpdate, StateFetch};
+use std::collections::VecDeque;
+
+// Reverse execution. At every instruction boundary we note down the registers and the
+// little bits of state that aren't in memory, and as the instruction goes along the memory
+// cycles it does. Undoing it is putting back what each write replaced, last first.
+// That includes the P cycle's update of the PC and the program interrupt's store in
+// location 1, they are memory cycles like any other. Devices aren't wound back, what
+// has been printed stays printed.
+
+/// Everything needed to put the machine back to an instruction boundary.
+pub struct UndoRecord {
+    registers: HWRegisters,
+    pc_update: ProgramCounterUpdate,
+    interrupts: Interrupts,
+    extension: Option<MemoryExtension>,
+    cycles: u64,
+    pc: u12,
+    accesses: Vec<MemoryAccess>,
+    watched: Vec<MemoryAccess>, // What watchpoints saw of them.
+}
+impl UndoRecord {
+    pub fn capture(state: &MachineState) -> UndoRecord {
+        UndoRecord {
+            registers: state.registers.hardware_registers,
+            pc_update: state.pc_update,
+            interrupts: state.interrupts,
+            extension: state.memory.extension().copied(),
+            cycles: state.cycles,
+            pc: state.memory[PC_ADDRESS.into()],
+            accesses: Vec::new(),
+            watched: Vec::new(),
+        }
+    }
+
+    pub fn restore(&self, state: &mut MachineState) {
+        for access in self.accesses.iter().rev() {
+            if access.kind == MemoryAccessKind::Write {
+                state
+                    .memory
+                    .set(access.field, access.address, access.previous);
+            }
+        }
+        state.registers.hardware_registers = self.registers;
+        state.pc_update = self.pc_update;
+        state.interrupts = self.interrupts;
+        if let (Some(extension), Some(saved)) = (state.memory.extension_mut(), self.extension) {
+            *extension = saved;
+        }
+        state.cycles = self.cycles;
+        state.state = CycleState::F(StateFetch {});
+        state.run = false;
+        state.watchpoint_hit = None;
+        state.breakpoint_stop = None;
+    }
+
+    /// The reads and writes the instruction did, including any break cycles during it.
+    pub fn accesses(&self) -> &[MemoryAccess] {
+        &self.accesses
+    }
+
+    /// The accesses less the P cycle moving the PC on, as watchpoints see them going forwards.
+    pub fn watched(&self) -> &[MemoryAccess] {
+        &self.watched
+    }
+
+    /// Address of the instruction this undoes.
+    pub fn pc(&self) -> u12 {
+        self.pc
+    }
+}
+
+/// The last limit instructions, oldest first.
+pub struct History {
+    records: VecDeque<UndoRecord>,
+    limit: usize,
+    open: Option<UndoRecord>, // The instruction in progress.
+}
+impl History {
+    pub fn new(limit: usize) -> History {
+        History {
+            records: VecDeque::new(),
+            limit,
+            open: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    #[allow(dead_code)] // Goes with len.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty() && self.open.is_none()
+    }
+
+    pub fn recording(&self) -> bool {
+        self.open.is_some()
+    }
+
+    pub fn begin(&mut self, record: UndoRecord) {
+        self.open = Some(record);
+    }
+
+    /// The accesses of a cycle of the open instruction, program_counter if it is the P cycle.
+    pub fn note(&mut self, accesses: &[MemoryAccess], program_counter: bool) {
+        if let Some(record) = &mut self.open {
+            record.accesses.extend_from_slice(accesses);
+            record
+                .watched
+                .extend(watched_accesses(accesses, program_counter));
+        }
+    }
+
+    pub fn end(&mut self) {
+        if let Some(record) = self.open.take() {
+            self.records.push_back(record);
+            while self.records.len() > self.limit {
+                self.records.pop_front();
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.open = None;
+    }
+
+    /// The instruction in progress if we are part way through one, otherwise the last one done.
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        self.open.take().or_else(|| self.records.pop_back())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StopReason;
+    use crate::breakpoints::{Breakpoint, Watch};
+    use crate::iot_bus::{IotDevice, IotResponse};
+    use crate::scheduler::Events;
+
+    fn machine(program: &[(u16, u16)]) -> MachineState {
+        let mut pdp5 = MachineState::with_program(program);
+        pdp5.enable_history(1000);
+        pdp5
+    }
+
+    // Counts up in 0300 and keeps the count in the AC as well.
+    const COUNTER: [(u16, u16); 4] = [
+        (0o200, 0o2300), // ISZ 300
+        (0o201, 0o1300), // TAD 300
+        (0o202, 0o7200), // CLA
+        (0o203, 0o5200), // JMP 200
+    ];
+
+    #[test]
+    fn test_step_back() {
+        let mut pdp5 = machine(&COUNTER);
+        pdp5.start_program(4 * 10);
+        let count = pdp5.memory[0o300.into()];
+        let pc = pdp5.memory[PC_ADDRESS.into()];
+        let cycles = pdp5.cycles;
+        // Four instructions is once round the loop.
+        for _ in 0..4 {
+            assert!(pdp5.step_back().is_some());
+        }
+        assert_eq!(pdp5.memory[0o300.into()], count - 1.into());
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], pc);
+        assert!(pdp5.cycles < cycles);
+        for _ in 0..4 {
+            pdp5.step_instruction();
+        }
+        assert_eq!(pdp5.memory[0o300.into()], count);
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], pc);
+        assert_eq!(pdp5.cycles, cycles);
+    }
+
+    #[test]
+    fn test_step_back_to_the_start() {
+        let mut pdp5 = machine(&COUNTER);
+        pdp5.start_program(100);
+        while pdp5.step_back().is_some() {}
+        assert_eq!(pdp5.memory[0o300.into()], 0.into());
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o200.into());
+        assert_eq!(pdp5.cycles, 0);
+        assert_eq!(pdp5.reverse_continue(), StopReason::HistoryStart);
+    }
+
+    #[test]
+    fn test_store_into_the_pc_is_undone() {
+        let mut pdp5 = machine(&[
+            (0o200, 0o1250), // TAD 250
+            (0o201, 0o3000), // DCA 0, jumps to 0301
+            (0o250, 0o0300),
+            (0o301, 0o7402), // HLT
+        ]);
+        assert_eq!(pdp5.start_program(100), StopReason::Halted);
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o302.into());
+        pdp5.step_back();
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o301.into());
+        pdp5.step_back();
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o201.into());
+        assert_eq!(pdp5.registers.hardware_registers.AC, 0o300.into());
+    }
+
+    /// Always wants an interrupt.
+    struct Interrupter;
+    impl IotDevice for Interrupter {
+        fn device_codes(&self) -> &[u8] {
+            &[0o40]
+        }
+        fn pulse(
+            &mut self,
+            _device_code: u8,
+            _pulse: u8,
+            ac: u12,
+            _events: &mut Events,
+        ) -> IotResponse {
+            IotResponse::unchanged(ac)
+        }
+        fn interrupt_request(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_interrupt_is_undone() {
+        let mut pdp5 = machine(&[(0o200, 0o6001), (0o201, 0o7000), (0o202, 0o7402)]);
+        pdp5.iot_bus.attach(Box::new(Interrupter));
+        pdp5.memory[INTERRUPT_RETURN_ADDRESS.into()] = 0o1234.into();
+        pdp5.start_program(20);
+        assert_eq!(pdp5.memory[INTERRUPT_RETURN_ADDRESS.into()], 0o202.into());
+        while pdp5.step_back().is_some() {}
+        assert!(!pdp5.interrupts.enabled);
+        assert!(!pdp5.interrupts.enable_pending);
+        assert_eq!(pdp5.memory[INTERRUPT_RETURN_ADDRESS.into()], 0o1234.into());
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o200.into());
+    }
+
+    #[test]
+    fn test_field_changes_are_undone() {
+        let mut pdp5 = MachineState::with_fields([0.into(); 4096], 2);
+        pdp5.enable_history(1000);
+        pdp5.memory[0o200.into()] = 0o6211.into(); // CDF 1
+        pdp5.memory[0o201.into()] = 0o3650.into(); // DCA I 250, into field 1
+        pdp5.memory[0o250.into()] = 0o300.into();
+        pdp5.memory.set(1, 0o300.into(), 0o1234.into());
+        pdp5.set_initial_start_address(0o200);
+        pdp5.step_instruction();
+        pdp5.step_instruction();
+        assert_eq!(pdp5.memory.get(1, 0o300.into()), 0.into());
+        while pdp5.step_back().is_some() {}
+        assert_eq!(pdp5.memory.get(1, 0o300.into()), 0o1234.into());
+        assert_eq!(pdp5.memory.extension().unwrap().data_field, 0);
+    }
+
+    #[test]
+    fn test_reverse_continue_to_breakpoint() {
+        let mut pdp5 = machine(&COUNTER);
+        pdp5.start_program(4 * 10);
+        let count = pdp5.memory[0o300.into()];
+        pdp5.breakpoints.add(Breakpoint::Execute {
+            address: 0o202.into(),
+            condition: None,
+        });
+        assert!(matches!(
+            pdp5.reverse_continue(),
+            StopReason::Breakpoint(hit) if hit.pc == 0o202.into()
+        ));
+        assert_eq!(pdp5.memory[0o300.into()], count);
+        // Going forward again doesn't stop on the breakpoint we are sat on.
+        let StopReason::Breakpoint(hit) = pdp5.start_program(100) else {
+            panic!("Expected the breakpoint next time round.");
+        };
+        assert_eq!(hit.pc, 0o202.into());
+        assert_eq!(pdp5.memory[0o300.into()], count + 1.into());
+    }
+
+    #[test]
+    fn test_reverse_continue_to_watchpoint() {
+        let mut pdp5 = machine(&COUNTER);
+        pdp5.start_program(4 * 10);
+        let count = pdp5.memory[0o300.into()];
+        pdp5.breakpoints.add(Breakpoint::Watch {
+            watch: Watch::Write,
+            first: 0o300.into(),
+            last: 0o300.into(),
+        });
+        // Back to just before the ISZ that did the last write.
+        assert!(matches!(pdp5.reverse_continue(), StopReason::Breakpoint(_)));
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o200.into());
+        assert_eq!(pdp5.memory[0o300.into()], count - 1.into());
+    }
+
+    #[test]
+    fn test_reverse_continue_to_pc_watchpoint() {
+        let mut pdp5 = machine(&[
+            (0o200, 0o7001), // IAC
+            (0o201, 0o1000), // TAD 0, reads the PC
+            (0o202, 0o7200), // CLA
+            (0o203, 0o5200), // JMP 200
+        ]);
+        for _ in 0..9 {
+            pdp5.step_instruction();
+        }
+        pdp5.breakpoints.add(Breakpoint::Watch {
+            watch: Watch::ReadWrite,
+            first: PC_ADDRESS.into(),
+            last: PC_ADDRESS.into(),
+        });
+        // The P cycles of the IAC, JMP and CLA on the way back don't count.
+        let StopReason::Breakpoint(hit) = pdp5.reverse_continue() else {
+            panic!("Expected the TAD 0 to set off the watchpoint.");
+        };
+        assert_eq!(hit.pc, 0o201.into());
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o201.into());
+    }
+
+    #[test]
+    fn test_reverse_to_last_write() {
+        let mut pdp5 = machine(&COUNTER);
+        pdp5.start_program(4 * 10 + 2);
+        let count = pdp5.memory[0o300.into()];
+        let StopReason::LastWrite(access) = pdp5.reverse_to_write(0o300.into()) else {
+            panic!("Expected to find the write.");
+        };
+        assert_eq!(access.value, count);
+        assert_eq!(access.previous, count - 1.into());
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o200.into());
+        assert_eq!(
+            pdp5.reverse_to_write(0o301.into()),
+            StopReason::HistoryStart
+        );
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let mut pdp5 = machine(&COUNTER);
+        pdp5.enable_history(10);
+        pdp5.start_program(1000);
+        if !pdp5.at_instruction_boundary() {
+            pdp5.step_instruction();
+        }
+        let mut steps = 0;
+        while pdp5.step_back().is_some() {
+            steps += 1;
+        }
+        assert_eq!(steps, 10);
+    }
+}
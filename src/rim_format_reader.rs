@@ -1,66 +1,80 @@
-
 use crate::unsigned_integer_12::u12;
-use std::io::Error;
 use std::fs;
+use std::io::{Error, ErrorKind};
 use std::path::Path;
 
-pub struct RimFormat{}
-impl RimFormat{
-
-    fn process_address_content_pair(data: &[u8; 4]) -> [u12; 2]{
-        let mut address: u16 = 0;
-        let mut content: u16 = 0;
+pub struct RimFormat {}
+impl RimFormat {
+    fn process_address_content_pair(data: &[u8; 4]) -> [u12; 2] {
         // println!("Address: {:08b} {:08b}", data[0], data[1]);
-        address = data[0] as u16;
-        address = address << 6; // Shift left 6 bits
+        let mut address = data[0] as u16;
+        address <<= 6; // Shift left 6 bits
         address += data[1] as u16;
         // println!("Content: {:08b} {:08b}", data[0], data[1]);
-        content = data[2] as u16;
-        content = content << 6; // Shift left 6 bits
+        let mut content = data[2] as u16;
+        content <<= 6; // Shift left 6 bits
         content += data[3] as u16;
         [address.into(), content.into()]
     }
 
-    pub fn load_from_file<'a>(path: &Path, buf: &'a mut [u12; 4096]) -> Result<&'a mut [u12; 4096], Error> {
+    pub fn load_from_file<'a>(
+        path: &Path,
+        buf: &'a mut [u12; 4096],
+    ) -> Result<&'a mut [u12; 4096], Error> {
         let data: Vec<u8> = fs::read(path)?;
-        assert!(data.len() <= 4095, "Data is larger than available memory.");
+        RimFormat::load(&data, buf)
+    }
 
-        // Leader trailer codes mean nothing, Required since you needed to be able to tear tape so you wanted 
+    /// Every word on the tape has its own address, so a tape can be any length and a tape
+    /// with more than one word after an address (BIN format say) is an error.
+    pub fn load<'a>(data: &[u8], buf: &'a mut [u12; 4096]) -> Result<&'a mut [u12; 4096], Error> {
+        // Leader trailer codes mean nothing, Required since you needed to be able to tear tape so you wanted
         // To be able to write nothing. This mean's the tape is essentially 7 bits not 8 bits.
         const LEADER_TRAILER_CODE: u8 = 0b1000_0000;
         // The RIM format uses the 2nd bit to determine where each absolute address: content pair starts
-        // This means the data is essentially 6 bits. 
+        // This means the data is essentially 6 bits.
         // So 2 lines of tape per address for a single 12 bit word
-        // Then 2 lines of tape per content of address for another 12 bit word. 
+        // Then 2 lines of tape per content of address for another 12 bit word.
         // Then alternate again to address then content etc until you hit another LEADER_TRAILER_CODE.
-        // This is all my speculation so grain of salt. 
+        // This is all my speculation so grain of salt.
         const START_ADDRESS_CONTENT_PAIR: u8 = 0b0100_0000;
         const DATA_MASK: u8 = 0b0011_1111; // We only want to load the data not start_address_content_pair bits.
         let mut buffer: [u8; 4] = [0; 4];
         let mut buffer_counter = 0;
-        for byte in data{
-            // We use mask since I don't think any data with a leading 1 bit has any value. 
-            if byte & LEADER_TRAILER_CODE == LEADER_TRAILER_CODE{
+        for &byte in data {
+            // We use mask since I don't think any data with a leading 1 bit has any value.
+            if byte & LEADER_TRAILER_CODE == LEADER_TRAILER_CODE {
                 continue;
-            }
-            else{
-                if byte & START_ADDRESS_CONTENT_PAIR == START_ADDRESS_CONTENT_PAIR{
+            } else {
+                if byte & START_ADDRESS_CONTENT_PAIR == START_ADDRESS_CONTENT_PAIR {
                     // println!("{buffer:?}");
-                    let [address, content] = RimFormat::process_address_content_pair(&buffer);
-                    buf[address] = content;
+                    if buffer_counter == buffer.len() {
+                        let [address, content] = RimFormat::process_address_content_pair(&buffer);
+                        buf[address] = content;
+                    }
                     // println!("{data:?}");
                     buffer_counter = 0;
                 }
-                let data= byte & DATA_MASK;
+                if buffer_counter == buffer.len() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Not a RIM tape, more than one word after an address.",
+                    ));
+                }
+                let data = byte & DATA_MASK;
                 buffer[buffer_counter] = data;
                 buffer_counter += 1;
             }
         }
+        // The last pair is followed by trailer rather than another address.
+        if buffer_counter == buffer.len() {
+            let [address, content] = RimFormat::process_address_content_pair(&buffer);
+            buf[address] = content;
+        }
         Ok(&mut *buf)
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -70,7 +84,39 @@ mod tests {
         let path = Path::new("example_code/binhalt-pm/binhalt-pm");
         let mut buf: [u12; 4096] = [0.into(); 4096];
         let result = RimFormat::load_from_file(path, &mut buf).expect("Don't expect an IO error");
-        println!("{result:?}")
+        println!("{result:?}");
+        assert_eq!(result[0o7600], 0o1211.into());
+        assert_eq!(result[0o7712], 0.into());
+        // The last word on the tape.
+        assert_eq!(result[0o7777], 0o5221.into());
+    }
+
+    #[test]
+    fn test_long_tape() {
+        // Every word in memory, well over 4K of tape.
+        let mut tape = vec![0o200; 100];
+        for address in 0..4096u16 {
+            let word = 0o7777 - address;
+            tape.extend([
+                0o100 | (address >> 6) as u8,
+                (address & 0o77) as u8,
+                (word >> 6) as u8,
+                (word & 0o77) as u8,
+            ]);
+        }
+        tape.extend([0o200; 100]);
+        let mut buf: [u12; 4096] = [0.into(); 4096];
+        let result = RimFormat::load(&tape, &mut buf).unwrap();
+        assert_eq!(result[0], 0o7777.into());
+        assert_eq!(result[0o7777], 0.into());
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_bin_tape_is_an_error() {
+        // An origin of 0200 followed by three words.
+        let tape = [0o200, 0o102, 0o0, 0o72, 0o01, 0o74, 0o02, 0o76, 0o03, 0o200];
+        let mut buf: [u12; 4096] = [0.into(); 4096];
+        let error = RimFormat::load(&tape, &mut buf).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::Error;
+
+// Devices that take a while (printing a line, reading a character off tape) schedule an
+// event for when they will be done instead of counting down every cycle, so all it costs
+// a machine with slow devices sat idle is a look at the front of the queue each cycle.
+// Events at the same time go off in the order they were scheduled.
+
+/// An event comes back to the device that scheduled it with its token,
+/// the device decides what the token means.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Event {
+    time_ns: u64,
+    sequence: u64,
+    device: usize,
+    token: u32,
+}
+
+pub struct Scheduler {
+    now_ns: u64,
+    sequence: u64,
+    queue: BinaryHeap<Reverse<Event>>,
+}
+impl Scheduler {
+    pub fn default() -> Scheduler {
+        Scheduler {
+            now_ns: 0,
+            sequence: 0,
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    /// Simulated time since power up.
+    #[cfg(test)]
+    pub fn now_ns(&self) -> u64 {
+        self.now_ns
+    }
+
+    pub fn schedule(&mut self, delay_ns: u64, device: usize, token: u32) {
+        self.queue.push(Reverse(Event {
+            time_ns: self.now_ns + delay_ns,
+            sequence: self.sequence,
+            device,
+            token,
+        }));
+        self.sequence += 1;
+    }
+
+    pub fn cancel(&mut self, device: usize, token: u32) {
+        self.queue
+            .retain(|Reverse(event)| event.device != device || event.token != token);
+    }
+
+    #[cfg(test)]
+    pub fn pending(&self, device: usize, token: u32) -> bool {
+        self.queue
+            .iter()
+            .any(|Reverse(event)| event.device == device && event.token == token)
+    }
+
+    /// Every event is for one of the first count devices.
+    pub fn only_for_devices(&self, count: usize) -> bool {
+        self.queue.iter().all(|Reverse(event)| event.device < count)
+    }
+
+    /// Simulated time of the next event.
+    pub fn next_ns(&self) -> Option<u64> {
+        self.queue.peek().map(|Reverse(event)| event.time_ns)
+    }
+
+    pub fn advance(&mut self, elapsed_ns: u64) {
+        self.now_ns += elapsed_ns;
+    }
+
+    /// The next event that is due by now, as device and token.
+    pub fn pop_due(&mut self) -> Option<(usize, u32)> {
+        if self.next_ns()? > self.now_ns {
+            return None;
+        }
+        self.queue
+            .pop()
+            .map(|Reverse(event)| (event.device, event.token))
+    }
+
+    /// Times are kept relative to now so they carry on the same after a restore.
+    pub fn save(&self, writer: &mut SnapshotWriter) {
+        writer.u64(self.now_ns);
+        let mut events: Vec<&Event> = self.queue.iter().map(|Reverse(event)| event).collect();
+        events.sort();
+        writer.u32(events.len() as u32);
+        for event in events {
+            writer.u64(event.time_ns - self.now_ns);
+            writer.u16(event.device as u16);
+            writer.u32(event.token);
+        }
+    }
+
+    pub fn load(reader: &mut SnapshotReader) -> Result<Scheduler, Error> {
+        let mut scheduler = Scheduler::default();
+        scheduler.now_ns = reader.u64()?;
+        for _ in 0..reader.u32()? {
+            let delay_ns = reader.u64()?;
+            let device = reader.u16()? as usize;
+            scheduler.schedule(delay_ns, device, reader.u32()?);
+        }
+        Ok(scheduler)
+    }
+}
+
+/// A device's view of the scheduler, handed to it whenever it might want to schedule something.
+pub struct Events<'a> {
+    scheduler: &'a mut Scheduler,
+    device: usize,
+}
+impl<'a> Events<'a> {
+    pub fn new(scheduler: &'a mut Scheduler, device: usize) -> Events<'a> {
+        Events { scheduler, device }
+    }
+
+    /// Come back with token once delay_ns of simulated time has gone by.
+    pub fn after(&mut self, delay_ns: u64, token: u32) {
+        self.scheduler.schedule(delay_ns, self.device, token);
+    }
+
+    /// Forget any event with token that hasn't gone off yet.
+    pub fn cancel(&mut self, token: u32) {
+        self.scheduler.cancel(self.device, token);
+    }
+
+    #[cfg(test)]
+    pub fn pending(&self, token: u32) -> bool {
+        self.scheduler.pending(self.device, token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn due(scheduler: &mut Scheduler) -> Vec<(usize, u32)> {
+        std::iter::from_fn(|| scheduler.pop_due()).collect()
+    }
+
+    #[test]
+    fn test_events_go_off_in_time_order() {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule(300, 0, 1);
+        scheduler.schedule(100, 1, 2);
+        scheduler.schedule(100, 0, 3);
+        assert_eq!(scheduler.next_ns(), Some(100));
+        scheduler.advance(99);
+        assert!(due(&mut scheduler).is_empty());
+        scheduler.advance(1);
+        assert_eq!(due(&mut scheduler), vec![(1, 2), (0, 3)]);
+        scheduler.advance(1000);
+        assert_eq!(due(&mut scheduler), vec![(0, 1)]);
+        assert_eq!(scheduler.next_ns(), None);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut scheduler = Scheduler::default();
+        let mut events = Events::new(&mut scheduler, 4);
+        events.after(10, 7);
+        events.after(20, 8);
+        assert!(events.pending(7));
+        events.cancel(7);
+        assert!(!events.pending(7));
+        scheduler.advance(20);
+        assert_eq!(due(&mut scheduler), vec![(4, 8)]);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut scheduler = Scheduler::default();
+        scheduler.advance(50);
+        scheduler.schedule(10, 2, 5);
+        scheduler.schedule(5, 1, 6);
+        let mut writer = SnapshotWriter::default();
+        scheduler.save(&mut writer);
+        let bytes = writer.into_bytes();
+        let mut loaded = Scheduler::load(&mut SnapshotReader::new(&bytes)).unwrap();
+        assert_eq!(loaded.now_ns(), 50);
+        loaded.advance(10);
+        assert_eq!(due(&mut loaded), vec![(1, 6), (2, 5)]);
+    }
+}
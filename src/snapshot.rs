@@ -0,0 +1,546 @@
+use crate::data_break::Break;
+use crate::memory_extension::MemoryExtension;
+use crate::memory_parity::MemoryParity;
+use crate::unsigned_integer_12::u12;
+use crate::{
+    CycleState, Defer, Execute_1, Execute_2, HWRegisters, Interrupts, MachineState,
+    ProgramCounterUpdate, StateFetch, StateProgramCounter,
+};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+// Snapshot file format, everything little endian:
+//
+//   magic       "PDP5SNAP"
+//   version     u16
+//   memory      4096 x u16, field 0
+//   fields      u8 count, then 4096 x u16 for each field past 0, and with more than one
+//               u8 IF, u8 DF, u8 IB, u8 SF, u8 interrupt inhibit
+//   parity      u8, 1 with parity followed by a u8 for each word in every field, 1 if its
+//               parity bit is wrong, then u8 error flag and if set u8 field, u16 address
+//   registers   AC u16, L u8, MB u16, MA u16, IR u8, SR u16
+//   cycle       u8, 0 P, 1 F, 2 D, 3 E1, 4 E2, 5 B followed by the break (see Break::save)
+//   pc update   u8, 0 increment, 1 skip, 2 jump followed by a u16, 3 hold
+//   interrupts  u8 enabled, u8 enable pending
+//   run         u8, then u8 halting, the instruction in progress is a HLT
+//   cycles      u64
+//   scheduler   u64 now, u32 count, then for each event in the order they go off:
+//               u64 ns from now, u16 device, u32 token
+//   devices     u16 count, then for each in the order attached:
+//               u8 count and the device codes, u32 length and the device's own state
+//
+// Devices can't be made from a snapshot since they are wired up to files on the host,
+// so the machine being restored must have the same devices attached in the same order,
+// and the same number of memory fields, with parity or not. Injected faults aren't saved.
+// Breakpoints, history, the tracer and write protection belong to whoever is debugging
+// and aren't saved.
+
+const MAGIC: &[u8; 8] = b"PDP5SNAP";
+pub const VERSION: u16 = 5; // 2 added the scheduler, 3 memory fields, 4 parity, 5 halting.
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Builds up the bytes of a snapshot.
+pub struct SnapshotWriter {
+    bytes: Vec<u8>,
+}
+impl SnapshotWriter {
+    pub fn default() -> SnapshotWriter {
+        SnapshotWriter { bytes: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u12(&mut self, value: u12) {
+        self.u16(value.into());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Length first so the reader knows where it ends.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn string(&mut self, text: &str) {
+        self.bytes(text.as_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads back what a SnapshotWriter wrote, running off the end is an InvalidData error.
+pub struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+impl<'a> SnapshotReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> SnapshotReader<'a> {
+        SnapshotReader { bytes, position: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        let end = self.position + length;
+        let bytes = self
+            .bytes
+            .get(self.position..end)
+            .ok_or_else(|| invalid("Snapshot is cut short."))?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("Snapshot has a flag that is neither 0 nor 1.")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u12(&mut self) -> Result<u12, Error> {
+        match self.u16()? {
+            value if value <= 0o7777 => Ok(value.into()),
+            _ => Err(invalid("Snapshot has a word wider than 12 bits.")),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    pub fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("Snapshot text isn't UTF-8."))
+    }
+
+    pub fn finished(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+}
+
+pub fn save_cycle_state(state: &CycleState, writer: &mut SnapshotWriter) {
+    match state {
+        CycleState::PC(_) => writer.u8(0),
+        CycleState::F(_) => writer.u8(1),
+        CycleState::D(_) => writer.u8(2),
+        CycleState::E1(_) => writer.u8(3),
+        CycleState::E2(_) => writer.u8(4),
+        CycleState::B(cycle) => {
+            writer.u8(5);
+            cycle.save(writer);
+        }
+    }
+}
+
+pub fn load_cycle_state(reader: &mut SnapshotReader) -> Result<CycleState, Error> {
+    Ok(match reader.u8()? {
+        0 => CycleState::PC(StateProgramCounter {}),
+        1 => CycleState::F(StateFetch {}),
+        2 => CycleState::D(Defer {}),
+        3 => CycleState::E1(Execute_1 {}),
+        4 => CycleState::E2(Execute_2 {}),
+        5 => CycleState::B(Break::load(reader)?),
+        _ => return Err(invalid("Snapshot has an unknown major state.")),
+    })
+}
+
+pub struct Snapshot;
+impl Snapshot {
+    pub fn save(state: &MachineState) -> Vec<u8> {
+        let mut writer = SnapshotWriter::default();
+        for byte in MAGIC {
+            writer.u8(*byte);
+        }
+        writer.u16(VERSION);
+        for address in 0..4096_u16 {
+            writer.u12(state.memory[address.into()]);
+        }
+        writer.u8(state.memory.fields());
+        for field in 1..state.memory.fields() {
+            for address in 0..4096_u16 {
+                writer.u12(state.memory.get(field, address.into()));
+            }
+        }
+        if let Some(extension) = state.memory.extension() {
+            writer.u8(extension.instruction_field);
+            writer.u8(extension.data_field);
+            writer.u8(extension.instruction_buffer);
+            writer.u8(extension.save_field);
+            writer.bool(extension.interrupt_inhibit);
+        }
+        writer.bool(state.memory.parity().is_some());
+        if let Some(parity) = state.memory.parity() {
+            parity.save(&mut writer);
+        }
+        let registers = &state.registers.hardware_registers;
+        writer.u12(registers.AC);
+        writer.u8(registers.L);
+        writer.u12(registers.MB);
+        writer.u12(registers.MA);
+        writer.u8(registers.IR);
+        writer.u12(registers.SR);
+        save_cycle_state(&state.state, &mut writer);
+        match state.pc_update {
+            ProgramCounterUpdate::Increment => writer.u8(0),
+            ProgramCounterUpdate::Skip => writer.u8(1),
+            ProgramCounterUpdate::Jump(address) => {
+                writer.u8(2);
+                writer.u12(address);
+            }
+            ProgramCounterUpdate::Hold => writer.u8(3),
+        }
+        writer.bool(state.interrupts.enabled);
+        writer.bool(state.interrupts.enable_pending);
+        writer.bool(state.run);
+        writer.bool(state.halting);
+        writer.u64(state.cycles);
+        state.iot_bus.save(&mut writer);
+        writer.into_bytes()
+    }
+
+    /// The machine is left alone unless the whole snapshot could be read, short of
+    /// a device turning down its own state.
+    pub fn restore(state: &mut MachineState, bytes: &[u8]) -> Result<(), Error> {
+        let mut reader = SnapshotReader::new(bytes);
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(invalid("Not a PDP5 snapshot."));
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(invalid(&format!(
+                "Snapshot is version {version}, we can only read version {VERSION}."
+            )));
+        }
+        let fields = state.memory.fields();
+        let mut memory = Vec::with_capacity(4096 * fields as usize);
+        for _ in 0..4096 {
+            memory.push(reader.u12()?);
+        }
+        let saved_fields = reader.u8()?;
+        if saved_fields != fields {
+            return Err(invalid(&format!(
+                "Snapshot has {saved_fields} memory fields, this machine has {fields}."
+            )));
+        }
+        for _ in 4096..4096 * fields as usize {
+            memory.push(reader.u12()?);
+        }
+        let mut extension = None;
+        if fields > 1 {
+            let mut field = || match reader.u8()? {
+                field if field < fields => Ok(field),
+                _ => Err(invalid("Snapshot has a field past the end of memory.")),
+            };
+            extension = Some(MemoryExtension {
+                instruction_field: field()?,
+                data_field: field()?,
+                instruction_buffer: field()?,
+                save_field: reader.u8()? & 0o77,
+                interrupt_inhibit: reader.bool()?,
+            });
+        }
+        let with_parity = state.memory.parity().is_some();
+        if reader.bool()? != with_parity {
+            return Err(invalid(match with_parity {
+                true => "Snapshot is from a machine without parity.",
+                false => "Snapshot is from a machine with parity.",
+            }));
+        }
+        let parity = match with_parity {
+            true => Some(MemoryParity::load(&mut reader, memory.len())?),
+            false => None,
+        };
+        let registers = HWRegisters {
+            AC: reader.u12()?,
+            L: reader.u8()? & 1,
+            MB: reader.u12()?,
+            MA: reader.u12()?,
+            IR: reader.u8()? & 0o17,
+            SR: reader.u12()?,
+        };
+        let cycle_state = load_cycle_state(&mut reader)?;
+        let pc_update = match reader.u8()? {
+            0 => ProgramCounterUpdate::Increment,
+            1 => ProgramCounterUpdate::Skip,
+            2 => ProgramCounterUpdate::Jump(reader.u12()?),
+            3 => ProgramCounterUpdate::Hold,
+            _ => return Err(invalid("Snapshot has an unknown PC update.")),
+        };
+        let interrupts = Interrupts {
+            enabled: reader.bool()?,
+            enable_pending: reader.bool()?,
+        };
+        let run = reader.bool()?;
+        let halting = reader.bool()?;
+        let cycles = reader.u64()?;
+        // The devices go last as they change as they are read.
+        let devices = reader.position;
+        state.iot_bus.check_snapshot(&mut reader)?;
+        if !reader.finished() {
+            return Err(invalid("Snapshot has something on the end of it."));
+        }
+        reader.position = devices;
+        state.iot_bus.restore(&mut reader)?;
+
+        for (index, word) in memory.into_iter().enumerate() {
+            let field = (index / 4096) as u8;
+            state.memory.set(field, (index % 4096).into(), word);
+        }
+        if let (Some(saved), Some(extension)) = (extension, state.memory.extension_mut()) {
+            *extension = saved;
+        }
+        // After the words, which went in with the right parity.
+        if let (Some(saved), Some(parity)) = (parity, state.memory.parity_mut()) {
+            *parity = saved;
+        }
+        state.registers.hardware_registers = registers;
+        state.state = cycle_state;
+        state.pc_update = pc_update;
+        state.interrupts = interrupts;
+        state.run = run;
+        state.halting = halting;
+        state.cycles = cycles;
+        // Anything we knew about the past no longer applies.
+        state.watchpoint_hit = None;
+        state.breakpoint_stop = None;
+        if let Some(history) = &mut state.history {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    pub fn save_to_file(state: &MachineState, path: &Path) -> Result<(), Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&Snapshot::save(state))?;
+        file.flush()
+    }
+
+    pub fn restore_from_file(state: &mut MachineState, path: &Path) -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+        Snapshot::restore(state, &bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::*;
+    use crate::data_break::{BreakDirection, BreakMode, BreakRequest, BreakTransfer};
+    use crate::iot_bus::{IotDevice, IotResponse};
+    use crate::memory_parity::Fault;
+    use crate::scheduler::Events;
+
+    /// Keeps a count of its IOTs, adding 0100 a while after each, and sends words in
+    /// by data break while it has any left.
+    struct Counter {
+        count: u16,
+        words: u16,
+    }
+    impl IotDevice for Counter {
+        fn device_codes(&self) -> &[u8] {
+            &[0o41]
+        }
+        fn pulse(
+            &mut self,
+            _device_code: u8,
+            _pulse: u8,
+            _ac: u12,
+            events: &mut Events,
+        ) -> IotResponse {
+            self.count += 1;
+            events.after(200_000, 0);
+            IotResponse {
+                ac: self.count.into(),
+                skip: false,
+            }
+        }
+        fn data_break_request(&self) -> Option<BreakRequest> {
+            (self.words > 0).then_some(BreakRequest {
+                priority: 0,
+                mode: BreakMode::ThreeCycle(0o20.into()),
+                direction: BreakDirection::In(self.words.into()),
+            })
+        }
+        fn data_break_granted(&mut self, _transfer: BreakTransfer, _events: &mut Events) {
+            self.words -= 1;
+        }
+        fn event(&mut self, _token: u32, _events: &mut Events) {
+            self.count += 0o100;
+        }
+        fn save_state(&self, writer: &mut SnapshotWriter) {
+            writer.u16(self.count);
+            writer.u16(self.words);
+        }
+        fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), Error> {
+            self.count = reader.u16()?;
+            self.words = reader.u16()?;
+            Ok(())
+        }
+    }
+
+    fn machine() -> MachineState {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o020, 0o7770), // Word count for the breaks
+            (0o021, 0o0477), // and the address before the buffer.
+            (0o200, 0o6411), // IOT 41, count in the AC
+            (0o201, 0o3410), // DCA I 10, autoindexed
+            (0o202, 0o6001), // ION
+            (0o203, 0o2300), // ISZ 300
+            (0o204, 0o5200), // JMP 200
+            (0o205, 0o7402), // HLT
+            (0o010, 0o0777), // Address before the counts.
+            (0o300, 0o7760),
+        ]);
+        pdp5.iot_bus
+            .attach(Box::new(Counter { count: 0, words: 6 }));
+        pdp5
+    }
+
+    fn memory(pdp5: &MachineState) -> Vec<u12> {
+        (0..4096_u16)
+            .map(|address| pdp5.memory[address.into()])
+            .collect()
+    }
+
+    #[test]
+    fn test_restored_machine_carries_on_the_same() {
+        let mut original = machine();
+        // Stop part way through an instruction, in the middle of a break.
+        for _ in 0..2 {
+            original.step_cycle();
+        }
+        assert!(matches!(original.state, CycleState::B(_)));
+        let snapshot = Snapshot::save(&original);
+
+        let mut restored = machine();
+        restored.memory[0o300.into()] = 0.into();
+        restored.step_cycle();
+        Snapshot::restore(&mut restored, &snapshot).unwrap();
+        assert_eq!(Snapshot::save(&restored), snapshot);
+
+        original.start_program(200);
+        restored.start_program(200);
+        assert_eq!(memory(&original), memory(&restored));
+        assert_eq!(
+            original.registers.hardware_registers.AC,
+            restored.registers.hardware_registers.AC
+        );
+        assert_eq!(original.cycles, restored.cycles);
+        assert_eq!(original.memory[0o500.into()], 6.into());
+        assert_eq!(
+            original.memory[PC_ADDRESS.into()],
+            restored.memory[PC_ADDRESS.into()]
+        );
+    }
+
+    #[test]
+    fn test_file() {
+        let path = std::env::temp_dir().join(format!("pdp5_snapshot_{}.snap", std::process::id()));
+        let mut original = machine();
+        original.start_program(50);
+        Snapshot::save_to_file(&original, &path).unwrap();
+        let mut restored = machine();
+        Snapshot::restore_from_file(&mut restored, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(memory(&original), memory(&restored));
+        assert_eq!(original.cycles, restored.cycles);
+        original.start_program(500);
+        restored.start_program(500);
+        assert_eq!(memory(&original), memory(&restored));
+        // Events from before the snapshot went off after it.
+        assert_eq!(restored.memory[0o1003.into()], 0o104.into());
+    }
+
+    #[test]
+    fn test_bad_snapshots_change_nothing() {
+        let snapshot = Snapshot::save(&machine());
+        let mut pdp5 = machine();
+        pdp5.memory[0o300.into()] = 0o1234.into();
+
+        let mut newer = snapshot.clone();
+        newer[8..10].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(Snapshot::restore(&mut pdp5, &newer).is_err());
+        assert!(Snapshot::restore(&mut pdp5, &snapshot[..snapshot.len() - 1]).is_err());
+        assert!(Snapshot::restore(&mut pdp5, b"PDP8SNAP").is_err());
+        // Different devices attached.
+        let mut other = MachineState::default([0.into(); 4096]);
+        assert!(Snapshot::restore(&mut other, &snapshot).is_err());
+        assert_eq!(pdp5.memory[0o300.into()], 0o1234.into());
+    }
+
+    #[test]
+    fn test_fields() {
+        let mut original = MachineState::with_fields([0.into(); 4096], 2);
+        original.memory.set(1, 0o300.into(), 0o4321.into());
+        original.memory.extension_mut().unwrap().data_field = 1;
+        let snapshot = Snapshot::save(&original);
+
+        let mut restored = MachineState::with_fields([0.into(); 4096], 2);
+        Snapshot::restore(&mut restored, &snapshot).unwrap();
+        assert_eq!(restored.memory.get(1, 0o300.into()), 0o4321.into());
+        assert_eq!(restored.memory.extension(), original.memory.extension());
+        assert_eq!(Snapshot::save(&restored), snapshot);
+        // Not without the same memory.
+        let mut smaller = MachineState::default([0.into(); 4096]);
+        assert!(Snapshot::restore(&mut smaller, &snapshot).is_err());
+    }
+
+    #[test]
+    fn test_parity() {
+        let mut original = MachineState::default([0.into(); 4096]);
+        original.memory.enable_parity();
+        original.memory.inject(Fault::Flip {
+            field: 0,
+            address: 0o300.into(),
+            bits: 0o1.into(),
+        });
+        let snapshot = Snapshot::save(&original);
+
+        let mut restored = MachineState::default([0.into(); 4096]);
+        restored.memory.enable_parity();
+        Snapshot::restore(&mut restored, &snapshot).unwrap();
+        assert_eq!(restored.memory.read(0o300.into()), 0o1.into());
+        assert!(restored.memory.interrupt_request());
+        let mut without = MachineState::default([0.into(); 4096]);
+        assert!(Snapshot::restore(&mut without, &snapshot).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use crate::unsigned_integer_12::u12;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// Names for addresses, for showing and typing addresses as labels. A symbol file is
+// either name=octal assignments or the name and value pairs PAL prints as its symbol
+// table at the end of a listing, several to a line:
+//
+//     START=0200 COUNT=0300        / Comments run from a slash to the end of the line.
+//     LOOP   0203   TEST   0210
+//
+// Names are case insensitive and kept in upper case. An address with more than one name
+// is shown by the first one.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbols {
+    addresses: BTreeMap<String, u12>,
+    names: BTreeMap<u16, String>,
+}
+
+fn valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn parse_value(name: &str, value: &str) -> Result<u12, String> {
+    match u16::from_str_radix(value, 8) {
+        Ok(value) if value <= 0o7777 => Ok(value.into()),
+        _ => Err(format!("{name} has an invalid value: {value}")),
+    }
+}
+
+impl Symbols {
+    pub fn default() -> Symbols {
+        Symbols {
+            addresses: BTreeMap::new(),
+            names: BTreeMap::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('/').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let error = |message: String| format!("line {}: {message}", index + 1);
+            let pairs: Vec<(&str, &str)> = match line.contains('=') {
+                true => words
+                    .iter()
+                    .map(|word| {
+                        word.split_once('=')
+                            .ok_or_else(|| error(format!("expected name=value, got {word}")))
+                    })
+                    .collect::<Result<_, _>>()?,
+                false if words.len().is_multiple_of(2) => {
+                    words.chunks(2).map(|pair| (pair[0], pair[1])).collect()
+                }
+                false => return Err(error(format!("{} has no value", words[words.len() - 1]))),
+            };
+            for (name, value) in pairs {
+                if !valid_name(name) {
+                    return Err(error(format!("invalid name: {name}")));
+                }
+                let address = parse_value(name, value).map_err(error)?;
+                symbols.add(name, address);
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<Symbols, Error> {
+        Symbols::parse(&fs::read_to_string(path)?)
+            .map_err(|message| Error::new(ErrorKind::InvalidData, message))
+    }
+
+    pub fn add(&mut self, name: &str, address: u12) {
+        let name = name.to_ascii_uppercase();
+        self.names
+            .entry(address.into())
+            .or_insert_with(|| name.clone());
+        self.addresses.insert(name, address);
+    }
+
+    /// Everything added or loaded from other, on top of what is already here.
+    pub fn extend(&mut self, other: &Symbols) {
+        for (name, address) in &other.addresses {
+            self.add(name, *address);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// In address order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u12)> {
+        let mut symbols: Vec<(&str, u12)> = self
+            .addresses
+            .iter()
+            .map(|(name, address)| (name.as_str(), *address))
+            .collect();
+        symbols.sort_by_key(|&(name, address)| (address, name));
+        symbols.into_iter()
+    }
+
+    pub fn address(&self, name: &str) -> Option<u12> {
+        self.addresses.get(&name.to_ascii_uppercase()).copied()
+    }
+
+    pub fn name(&self, address: u12) -> Option<&str> {
+        self.names.get(&u16::from(address)).map(String::as_str)
+    }
+
+    /// An address as an operand, the name if it has one otherwise octal.
+    pub fn operand(&self, address: u12) -> String {
+        match self.name(address) {
+            Some(name) => name.to_string(),
+            None => format!("{address:04o}"),
+        }
+    }
+
+    /// Where an address is, as the nearest name at or before it on the same page
+    /// with the octal offset from it, LOOP or LOOP+3.
+    pub fn location(&self, address: u12) -> Option<String> {
+        let address = u16::from(address);
+        let (&named, name) = self.names.range(..=address).next_back()?;
+        match address - named {
+            0 => Some(name.clone()),
+            _ if named & 0o7600 != address & 0o7600 => None,
+            offset => Some(format!("{name}+{offset:o}")),
+        }
+    }
+
+    /// An address followed by where it is if we know, 0203 <LOOP+1>.
+    pub fn describe(&self, address: u12, digits: usize) -> String {
+        match self.location(address) {
+            Some(location) => format!("{address:0digits$o} <{location}>"),
+            None => format!("{address:0digits$o}"),
+        }
+    }
+
+    /// An octal address, a name, or a name plus an octal offset.
+    pub fn parse_address(&self, text: &str) -> Result<u12, String> {
+        if let Ok(value) = u16::from_str_radix(text, 8) {
+            return match value <= 0o7777 {
+                true => Ok(value.into()),
+                false => Err(format!("Invalid octal number: {text}")),
+            };
+        }
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => match u16::from_str_radix(offset, 8) {
+                Ok(offset) => (name, offset),
+                Err(_) => return Err(format!("Invalid offset: {offset}")),
+            },
+            None => (text, 0),
+        };
+        match self.address(name) {
+            Some(address) => match u16::from(address).checked_add(offset) {
+                Some(address) if address <= 0o7777 => Ok(address.into()),
+                _ => Err(format!("{text} is past the end of memory")),
+            },
+            None if valid_name(name) => Err(format!("Unknown symbol: {name}")),
+            None => Err(format!("Invalid octal number: {text}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let symbols = Symbols::parse(
+            "/ Symbols for the counter\n\
+             start=0200 COUNT=300   / The count.\n\
+             \n\
+             LOOP   0203   TEST   0210\n\
+             AGAIN=0203\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.address("START"), Some(0o200.into()));
+        assert_eq!(symbols.address("count"), Some(0o300.into()));
+        assert_eq!(symbols.address("TEST"), Some(0o210.into()));
+        assert_eq!(symbols.address("AGAIN"), Some(0o203.into()));
+        // First name wins.
+        assert_eq!(symbols.name(0o203.into()), Some("LOOP"));
+        let names: Vec<&str> = symbols.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["START", "AGAIN", "LOOP", "TEST", "COUNT"]);
+
+        assert!(Symbols::parse("A=10000").is_err());
+        assert!(Symbols::parse("A=9").is_err());
+        assert!(Symbols::parse("A 0200 B").is_err());
+        assert!(Symbols::parse("1A=0200").is_err());
+        assert!(Symbols::parse("A=0200 B").is_err());
+    }
+
+    #[test]
+    fn test_lookups() {
+        let symbols = Symbols::parse("START=0200 LOOP=0203 COUNT=0300").unwrap();
+        assert_eq!(symbols.operand(0o300.into()), "COUNT");
+        assert_eq!(symbols.operand(0o301.into()), "0301");
+        assert_eq!(symbols.location(0o203.into()), Some("LOOP".to_string()));
+        assert_eq!(symbols.location(0o215.into()), Some("LOOP+12".to_string()));
+        // Nothing before it, and COUNT is on the page before.
+        assert_eq!(symbols.location(0o177.into()), None);
+        assert_eq!(symbols.location(0o400.into()), None);
+        assert_eq!(symbols.describe(0o204.into(), 5), "00204 <LOOP+1>");
+        assert_eq!(symbols.describe(0o100.into(), 4), "0100");
+
+        assert_eq!(symbols.parse_address("0210"), Ok(0o210.into()));
+        assert_eq!(symbols.parse_address("loop"), Ok(0o203.into()));
+        assert_eq!(symbols.parse_address("COUNT+10"), Ok(0o310.into()));
+        assert_eq!(
+            symbols.parse_address("NOWHERE"),
+            Err("Unknown symbol: NOWHERE".to_string())
+        );
+        assert_eq!(
+            symbols.parse_address("8000"),
+            Err("Invalid octal number: 8000".to_string())
+        );
+        assert!(symbols.parse_address("LOOP+9").is_err());
+        assert!(symbols.parse_address("10000").is_err());
+        assert_eq!(
+            symbols.parse_address("LOOP+177777"),
+            Err("LOOP+177777 is past the end of memory".to_string())
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use crate::disassembler::disassemble_with_symbols;
+use crate::instruction::OpCode;
+use crate::memory::{MemoryAccess, MemoryAccessKind};
+use crate::symbols::Symbols;
+use crate::unsigned_integer_12::u12;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::path::Path;
+
+// The tracer is told about every major cycle by MachineState::step_cycle, before the cycle
+// with the PC and registers and afterwards with what the cycle did. A Fetch starts a new
+// instruction and the next Fetch finishes it, so break cycles in between are part of the
+// instruction they interrupted and their memory writes show up with it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceGranularity {
+    Instruction,
+    Cycle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    JsonLines,
+}
+
+pub struct TracerConfig {
+    pub granularity: TraceGranularity,
+    pub format: TraceFormat,
+    pub ranges: Vec<(u12, u12)>, // Only instructions at these addresses (inclusive), everything if empty.
+    pub ring: Option<usize>,     // Keep the last N entries and only write them out at a halt.
+}
+impl TracerConfig {
+    pub fn default() -> TracerConfig {
+        TracerConfig {
+            granularity: TraceGranularity::Instruction,
+            format: TraceFormat::Text,
+            ranges: Vec::new(),
+            ring: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub cycle: Option<&'static str>, // Which major cycle, per cycle tracing only.
+    pub pc: u12,
+    pub instruction: u12,
+    pub address: Option<u12>, // Effective address of a memory reference instruction, or the MA after a cycle.
+    pub ac_before: u12,
+    pub link_before: u8,
+    pub ac_after: u12,
+    pub link_after: u8,
+    pub writes: Vec<(u12, u12)>, // Address and the word written there.
+}
+impl TraceEntry {
+    /// PAL style, with the instruction's label in front of it if it has one.
+    fn disassembly(&self, symbols: &Symbols) -> String {
+        let instruction = disassemble_with_symbols(self.pc, self.instruction, symbols);
+        match symbols.name(self.pc) {
+            Some(label) => format!("{label}, {instruction}"),
+            None => instruction,
+        }
+    }
+
+    fn text(&self, symbols: &Symbols) -> String {
+        let mut line = String::new();
+        if let Some(cycle) = self.cycle {
+            line.push_str(&format!("{cycle:<2} "));
+        }
+        line.push_str(&format!(
+            "{:05o}  {:04o}  {:<16}",
+            self.pc,
+            self.instruction,
+            self.disassembly(symbols)
+        ));
+        match self.address {
+            Some(address) => line.push_str(&format!("  {address:04o}")),
+            None => line.push_str("      "),
+        }
+        line.push_str(&format!(
+            "  AC {:04o} L {} -> AC {:04o} L {}",
+            self.ac_before, self.link_before, self.ac_after, self.link_after
+        ));
+        for (address, value) in &self.writes {
+            line.push_str(&format!("  {}={value:04o}", symbols.operand(*address)));
+        }
+        line
+    }
+
+    fn json(&self, symbols: &Symbols) -> String {
+        let mut fields = Vec::new();
+        if let Some(cycle) = self.cycle {
+            fields.push(format!("\"cycle\":\"{cycle}\""));
+        }
+        fields.push(format!("\"pc\":\"{:04o}\"", self.pc));
+        fields.push(format!("\"instruction\":\"{:04o}\"", self.instruction));
+        // Only letters, digits, spaces and commas so nothing needs escaping.
+        fields.push(format!("\"disassembly\":\"{}\"", self.disassembly(symbols)));
+        match self.address {
+            Some(address) => fields.push(format!("\"address\":\"{address:04o}\"")),
+            None => fields.push("\"address\":null".to_string()),
+        }
+        fields.push(format!("\"ac_before\":\"{:04o}\"", self.ac_before));
+        fields.push(format!("\"link_before\":{}", self.link_before));
+        fields.push(format!("\"ac_after\":\"{:04o}\"", self.ac_after));
+        fields.push(format!("\"link_after\":{}", self.link_after));
+        let writes: Vec<String> = self
+            .writes
+            .iter()
+            .map(|(address, value)| {
+                format!("{{\"address\":\"{address:04o}\",\"value\":\"{value:04o}\"}}")
+            })
+            .collect();
+        fields.push(format!("\"writes\":[{}]", writes.join(",")));
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// What the machine looked like at the start of a cycle.
+pub struct CycleStart {
+    pub fetch: bool, // The cycle is a Fetch, so a new instruction.
+    pub pc: u12,
+    pub instruction: u12, // What is at the PC.
+    pub ac: u12,
+    pub link: u8,
+}
+
+/// What the cycle did.
+pub struct CycleEnd<'a> {
+    pub cycle: &'static str,
+    pub program_counter: bool, // A P cycle.
+    pub ma: u12,
+    pub ac: u12,
+    pub link: u8,
+    pub accesses: &'a [MemoryAccess],
+    pub instruction_done: bool, // The next cycle is a Fetch.
+    pub halted: bool,
+    pub symbols: &'a Symbols, // The machine's, so any added while running show up too.
+}
+
+pub struct Tracer {
+    config: TracerConfig,
+    output: Box<dyn Write>,
+    ring: VecDeque<TraceEntry>,
+    instruction: Option<TraceEntry>, // The one in progress.
+    cycle: Option<TraceEntry>,       // Per cycle tracing only.
+}
+impl Tracer {
+    pub fn new(output: Box<dyn Write>, config: TracerConfig) -> Tracer {
+        Tracer {
+            config,
+            output,
+            ring: VecDeque::new(),
+            instruction: None,
+            cycle: None,
+        }
+    }
+
+    pub fn to_file(path: &Path, config: TracerConfig) -> Result<Tracer, Error> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file)), config))
+    }
+
+    pub fn begin_cycle(&mut self, start: CycleStart) {
+        let entry = TraceEntry {
+            cycle: None,
+            pc: start.pc,
+            instruction: start.instruction,
+            address: None,
+            ac_before: start.ac,
+            link_before: start.link,
+            ac_after: start.ac,
+            link_after: start.link,
+            writes: Vec::new(),
+        };
+        if start.fetch || self.instruction.is_none() {
+            self.instruction = Some(entry.clone());
+        }
+        if self.config.granularity == TraceGranularity::Cycle {
+            // Cycles belong to the instruction being done, not wherever the PC has got to.
+            let (pc, instruction) = match &self.instruction {
+                Some(current) => (current.pc, current.instruction),
+                None => (start.pc, start.instruction),
+            };
+            self.cycle = Some(TraceEntry {
+                pc,
+                instruction,
+                ..entry
+            });
+        }
+    }
+
+    pub fn end_cycle(&mut self, end: CycleEnd) {
+        let writes: Vec<(u12, u12)> = end
+            .accesses
+            .iter()
+            .filter(|access| access.kind == MemoryAccessKind::Write)
+            .map(|access| (access.address, access.value))
+            .collect();
+        if let Some(mut entry) = self.cycle.take() {
+            entry.cycle = Some(end.cycle);
+            entry.address = Some(end.ma);
+            entry.ac_after = end.ac;
+            entry.link_after = end.link;
+            entry.writes = writes.clone();
+            self.record(entry, end.symbols);
+        }
+        // Per instruction, the P cycle moving the PC on isn't something the instruction wrote.
+        if let Some(entry) = &mut self.instruction
+            && !end.program_counter
+        {
+            entry.writes.extend(writes);
+        }
+        if end.instruction_done {
+            if let Some(mut entry) = self.instruction.take()
+                && self.config.granularity == TraceGranularity::Instruction
+            {
+                // IOT and operate instructions have no effective address.
+                entry.address = match OpCode::from_instruction(entry.instruction) {
+                    OpCode::Iot | OpCode::Operate => None,
+                    _ => Some(end.ma),
+                };
+                entry.ac_after = end.ac;
+                entry.link_after = end.link;
+                self.record(entry, end.symbols);
+            }
+            if end.halted {
+                self.finish(end.symbols);
+            }
+        }
+    }
+
+    fn record(&mut self, entry: TraceEntry, symbols: &Symbols) {
+        let wanted = self.config.ranges.is_empty()
+            || self
+                .config
+                .ranges
+                .iter()
+                .any(|(first, last)| entry.pc >= *first && entry.pc <= *last);
+        if !wanted {
+            return;
+        }
+        match self.config.ring {
+            Some(size) => {
+                self.ring.push_back(entry);
+                while self.ring.len() > size {
+                    self.ring.pop_front();
+                }
+            }
+            None => self.write(&entry, symbols),
+        }
+    }
+
+    fn write(&mut self, entry: &TraceEntry, symbols: &Symbols) {
+        let line = match self.config.format {
+            TraceFormat::Text => entry.text(symbols),
+            TraceFormat::JsonLines => entry.json(symbols),
+        };
+        writeln!(self.output, "{line}").expect("Tracer could not write to its output.");
+    }
+
+    /// Write out whatever the ring buffer has, done by itself at a halt.
+    pub fn finish(&mut self, symbols: &Symbols) {
+        while let Some(entry) = self.ring.pop_front() {
+            self.write(&entry, symbols);
+        }
+        self.output
+            .flush()
+            .expect("Tracer could not flush its output.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MachineState, StopReason};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Somewhere to write that the test can still look at once the tracer has it.
+    #[derive(Clone)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(config: TracerConfig) -> Vec<String> {
+        run_with_symbols(config, "")
+    }
+
+    /// Symbols go in after the tracer, as the debugger's SYMBOLS command would add them.
+    fn run_with_symbols(config: TracerConfig, symbols: &str) -> Vec<String> {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o1210), // TAD 210
+            (0o201, 0o3211), // DCA 211
+            (0o202, 0o7020), // CML
+            (0o203, 0o7402), // HLT
+            (0o210, 0o0042),
+        ]);
+        let buffer = SharedBuffer(Rc::new(RefCell::new(Vec::new())));
+        pdp5.attach_tracer(Tracer::new(Box::new(buffer.clone()), config));
+        pdp5.symbols.extend(&Symbols::parse(symbols).unwrap());
+        assert_eq!(pdp5.start_program(100), StopReason::Halted);
+        let text = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_instruction_trace() {
+        let lines = run(TracerConfig::default());
+        assert_eq!(
+            lines,
+            vec![
+                "00200  1210  TAD 0210          0210  AC 0000 L 0 -> AC 0042 L 0",
+                "00201  3211  DCA 0211          0211  AC 0042 L 0 -> AC 0000 L 0  0211=0042",
+                "00202  7020  CML                     AC 0000 L 0 -> AC 0000 L 1",
+                "00203  7402  HLT                     AC 0000 L 1 -> AC 0000 L 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let config = TracerConfig {
+            format: TraceFormat::JsonLines,
+            ranges: vec![(0o201.into(), 0o201.into())],
+            ..TracerConfig::default()
+        };
+        assert_eq!(
+            run(config),
+            vec![
+                "{\"pc\":\"0201\",\"instruction\":\"3211\",\"disassembly\":\"DCA 0211\",\"address\":\"0211\",\
+                 \"ac_before\":\"0042\",\"link_before\":0,\"ac_after\":\"0000\",\"link_after\":0,\
+                 \"writes\":[{\"address\":\"0211\",\"value\":\"0042\"}]}"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let lines = run_with_symbols(TracerConfig::default(), "START=0200 VALUE=0210 RESULT=0211");
+        assert_eq!(
+            lines[0],
+            "00200  1210  START, TAD VALUE  0210  AC 0000 L 0 -> AC 0042 L 0"
+        );
+        assert!(lines[1].starts_with("00201  3211  DCA RESULT"));
+        assert!(lines[1].ends_with("  RESULT=0042"));
+    }
+
+    #[test]
+    fn test_cycle_trace() {
+        let config = TracerConfig {
+            granularity: TraceGranularity::Cycle,
+            ..TracerConfig::default()
+        };
+        let lines = run(config);
+        let cycles: Vec<&str> = lines.iter().map(|line| &line[..2]).collect();
+        assert_eq!(
+            cycles,
+            vec!["F ", "E1", "P ", "F ", "E1", "P ", "F ", "P ", "F ", "P "]
+        );
+        assert!(lines[4].starts_with("E1 00201  3211  DCA 0211          0211"));
+        assert!(lines[4].ends_with("0211=0042"));
+    }
+
+    #[test]
+    fn test_ring_buffer_keeps_the_last_entries() {
+        let config = TracerConfig {
+            ring: Some(2),
+            ..TracerConfig::default()
+        };
+        let lines = run(config);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("00202"));
+        assert!(lines[1].starts_with("00203"));
+    }
+}
@@ -1,20 +1,148 @@
 use std::{
     fmt::{Binary, Debug, LowerHex, Octal},
-    ops::{Add, AddAssign, BitAnd, Index, IndexMut, Mul, Shr},
+    ops::{
+        Add, AddAssign, BitAnd, BitOr, BitXor, Index, IndexMut, Mul, Neg, Not, Shl, Shr, Sub,
+        SubAssign,
+    },
 };
 const MASK: u16 = 0b0000_1111_1111_1111;
+const SIGN: u16 = 0b0000_1000_0000_0000;
 
+/// Arithmetic wraps at 12 bits like the machine does. Signed values are twos complement
+/// with bit 0 as the sign, and bits are numbered the PDP way, bit 0 is the most significant.
 #[allow(non_camel_case_types)]
 #[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord)]
 pub struct u12 {
     value: u16,
 }
-impl Shr for u12{
+impl u12 {
+    pub const ZERO: u12 = u12 { value: 0 };
+    pub const MAX: u12 = u12 { value: MASK };
+
+    /// -2048 to 2047.
+    pub fn to_i16(self) -> i16 {
+        if self.value & SIGN != 0 {
+            self.value as i16 - 0o10000
+        } else {
+            self.value as i16
+        }
+    }
+
+    /// Anything out of range wraps, so -1 is 7777.
+    pub fn from_i16(value: i16) -> u12 {
+        (value as u16).into()
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.value & SIGN != 0
+    }
+
+    /// The sum and whether there was a carry out of bit 0.
+    pub fn overflowing_add(self, rhs: u12) -> (u12, bool) {
+        let result = self.value + rhs.value;
+        (result.into(), result > MASK)
+    }
+
+    /// The difference and whether there was a borrow into bit 0.
+    pub fn overflowing_sub(self, rhs: u12) -> (u12, bool) {
+        (self.value.wrapping_sub(rhs.value).into(), rhs.value > self.value)
+    }
+
+    pub fn checked_add(self, rhs: u12) -> Option<u12> {
+        match self.overflowing_add(rhs) {
+            (result, false) => Some(result),
+            (_, true) => None,
+        }
+    }
+
+    pub fn checked_sub(self, rhs: u12) -> Option<u12> {
+        match self.overflowing_sub(rhs) {
+            (result, false) => Some(result),
+            (_, true) => None,
+        }
+    }
+
+    /// Rotate the 13 bits of the link and the word together one place left (RAL),
+    /// bit 0 goes into the link and the link into bit 11.
+    pub fn rotate_left_through_link(self, link: u8) -> (u12, u8) {
+        let rotated = (self.value << 1) | (link & 1) as u16;
+        (rotated.into(), ((self.value & SIGN) >> 11) as u8)
+    }
+
+    /// One place right (RAR), bit 11 goes into the link and the link into bit 0.
+    pub fn rotate_right_through_link(self, link: u8) -> (u12, u8) {
+        let rotated = (((link & 1) as u16) << 11) | (self.value >> 1);
+        (rotated.into(), (self.value & 1) as u8)
+    }
+
+    /// Bit 0 is the most significant, bit 11 the least.
+    pub fn bit(self, bit: u8) -> bool {
+        debug_assert!(bit < 12, "A u12 only has bits 0 to 11.");
+        self.value & (SIGN >> bit) != 0
+    }
+
+    pub fn with_bit(self, bit: u8, on: bool) -> u12 {
+        debug_assert!(bit < 12, "A u12 only has bits 0 to 11.");
+        match on {
+            true => (self.value | (SIGN >> bit)).into(),
+            false => (self.value & !(SIGN >> bit)).into(),
+        }
+    }
+
+    /// Bits first to last inclusive, so field(0, 2) is the opcode of an instruction.
+    pub fn field(self, first: u8, last: u8) -> u16 {
+        debug_assert!(first <= last && last < 12, "A u12 only has bits 0 to 11.");
+        let width = last - first + 1;
+        (self.value >> (11 - last)) & ((1 << width) - 1)
+    }
+}
+impl Neg for u12 {
+    type Output = u12;
+    /// Twos complement.
+    fn neg(self) -> Self::Output {
+        self.value.wrapping_neg().into()
+    }
+}
+impl Not for u12 {
+    type Output = u12;
+    fn not(self) -> Self::Output {
+        (!self.value).into()
+    }
+}
+impl Sub for u12 {
+    type Output = u12;
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.overflowing_sub(rhs).0
+    }
+}
+impl SubAssign for u12 {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+impl BitOr for u12 {
+    type Output = u12;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        (self.value | rhs.value).into()
+    }
+}
+impl BitXor for u12 {
+    type Output = u12;
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        (self.value ^ rhs.value).into()
+    }
+}
+impl Shl for u12 {
+    type Output = u12;
+    /// Bits shifted past bit 0 are lost.
+    fn shl(self, rhs: Self) -> Self::Output {
+        self.value.checked_shl(rhs.value as u32).unwrap_or(0).into()
+    }
+}
+impl Shr for u12 {
     type Output = u12;
     fn shr(self, rhs: Self) -> Self::Output {
-        let result = self.value >> rhs.value;
-        (result & MASK).into()
-
+        self.value.checked_shr(rhs.value as u32).unwrap_or(0).into()
     }
 }
 impl BitAnd for u12 {
@@ -23,36 +151,40 @@ impl BitAnd for u12 {
         (self.value & rhs.value).into()
     }
 }
-impl Add for u12{
+impl Add for u12 {
     type Output = u12;
     fn add(self, rhs: Self) -> Self::Output {
         let result = u16::add(self.value, rhs.value);
-        (result & MASK).into()
+        result.into() // From masks off the top bits.
     }
 }
-impl Mul for u12{
+impl Mul for u12 {
     type Output = u12;
     fn mul(self, rhs: Self) -> Self::Output {
-        let result = u16::mul(self.value, rhs.value);
-        (result & MASK).into()
+        // Only the bottom 12 bits are kept, so wrapping in 16 bits loses nothing.
+        let result = u16::wrapping_mul(self.value, rhs.value);
+        result.into()
     }
 }
-impl AddAssign for u12{
+impl AddAssign for u12 {
     fn add_assign(&mut self, rhs: Self) {
         self.value += rhs.value;
-        self.value = self.value & MASK;
-        debug_assert!(self.value <= 4095, "Index Out of Bounds or Overflow occurred")
+        self.value &= MASK;
+        debug_assert!(
+            self.value <= 4095,
+            "Index Out of Bounds or Overflow occurred"
+        )
     }
 }
-impl Index<u12> for [u12]{
+impl Index<u12> for [u12] {
     type Output = u12;
     fn index(&self, index: u12) -> &Self::Output {
-        &self[*&usize::from(index)]
+        &self[usize::from(index)]
     }
 }
-impl IndexMut<u12> for [u12]{
+impl IndexMut<u12> for [u12] {
     fn index_mut(&mut self, index: u12) -> &mut Self::Output {
-        &mut self[*&usize::from(index)]
+        &mut self[usize::from(index)]
     }
 }
 impl Binary for u12 {
@@ -65,7 +197,7 @@ impl Octal for u12 {
         std::fmt::Octal::fmt(&self.value, f)
     }
 }
-impl LowerHex for u12{
+impl LowerHex for u12 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         std::fmt::LowerHex::fmt(&self.value, f)
     }
@@ -115,7 +247,6 @@ impl From<u12> for u16 {
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -153,4 +284,189 @@ mod tests {
         println!("{result:?}");
         assert_eq!(39, result.value);
     }
+
+    #[test]
+    fn test_signed() {
+        assert_eq!(u12::from(0o7777).to_i16(), -1);
+        assert_eq!(u12::from(0o4000).to_i16(), -2048);
+        assert_eq!(u12::from(0o3777).to_i16(), 2047);
+        assert_eq!(u12::from_i16(-2), 0o7776.into());
+        assert_eq!(-u12::from(1), 0o7777.into());
+        assert_eq!(-u12::ZERO, u12::ZERO);
+        assert!(u12::from(0o4000).is_negative());
+    }
+
+    #[test]
+    fn test_wrapping() {
+        assert_eq!(u12::ZERO - 1.into(), u12::MAX);
+        assert_eq!(u12::MAX * u12::MAX, 1.into());
+        assert_eq!(u12::from(0o4001) << 1.into(), 2.into());
+        assert_eq!(u12::from(0o7777) >> 13.into(), 0.into());
+        assert_eq!(!u12::from(0o1234), 0o6543.into());
+        assert_eq!(u12::from(0o1200) | 0o34.into(), 0o1234.into());
+        assert_eq!(u12::from(0o7070) ^ 0o7777.into(), 0o0707.into());
+    }
+
+    #[test]
+    fn test_carry() {
+        assert_eq!(u12::MAX.overflowing_add(1.into()), (0.into(), true));
+        assert_eq!(u12::from(1).overflowing_add(1.into()), (2.into(), false));
+        assert_eq!(u12::ZERO.overflowing_sub(1.into()), (u12::MAX, true));
+        assert_eq!(u12::MAX.checked_add(1.into()), None);
+        assert_eq!(u12::from(5).checked_sub(3.into()), Some(2.into()));
+    }
+
+    #[test]
+    fn test_rotate_through_link() {
+        assert_eq!(u12::from(0o4001).rotate_left_through_link(0), (2.into(), 1));
+        assert_eq!(u12::from(0o4001).rotate_right_through_link(1), (0o6000.into(), 1));
+        assert_eq!(u12::ZERO.rotate_right_through_link(1), (0o4000.into(), 0));
+    }
+
+    #[test]
+    fn test_bits() {
+        let word = u12::from(0o5201);
+        assert!(word.bit(0));
+        assert!(!word.bit(1));
+        assert!(word.bit(11));
+        assert_eq!(word.field(0, 2), 5);
+        assert_eq!(word.field(3, 8), 0o20);
+        assert_eq!(word.with_bit(0, false), 0o1201.into());
+        assert_eq!(word.with_bit(1, true), 0o7201.into());
+    }
+
+    // Everything below checks u12 against the same sums done in wider integers and then
+    // cut down to 12 bits, for every value, and every pair of values for the binary ops.
+    const WORDS: u32 = 0o10000;
+
+    fn words() -> impl Iterator<Item = u12> {
+        (0..WORDS as u16).map(u12::from)
+    }
+
+    #[test]
+    fn test_binary_operations_against_reference() {
+        for a in 0..WORDS {
+            let x = u12::from(a as u16);
+            for b in 0..WORDS {
+                let y = u12::from(b as u16);
+                let sum = a + b;
+                let difference = a as i32 - b as i32;
+                assert_eq!(u16::from(x + y) as u32, sum % WORDS);
+                assert_eq!(x.overflowing_add(y), (x + y, sum >= WORDS));
+                assert_eq!(x.checked_add(y).is_none(), sum >= WORDS);
+                assert_eq!(i32::from(u16::from(x - y)), difference.rem_euclid(WORDS as i32));
+                assert_eq!(x.overflowing_sub(y), (x - y, difference < 0));
+                assert_eq!(x.checked_sub(y).is_none(), difference < 0);
+                assert_eq!(u16::from(x * y) as u32, (a * b) % WORDS);
+                assert_eq!(u16::from(x & y) as u32, a & b);
+                assert_eq!(u16::from(x | y) as u32, a | b);
+                assert_eq!(u16::from(x ^ y) as u32, a ^ b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_assignments_against_reference() {
+        for a in words() {
+            for b in [0, 1, 0o3777, 0o4000, 0o7776, 0o7777].map(u12::from) {
+                let mut sum = a;
+                sum += b;
+                assert_eq!(sum, a + b);
+                let mut difference = a;
+                difference -= b;
+                assert_eq!(difference, a - b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shifts_against_reference() {
+        for x in words() {
+            let a = u16::from(x) as u32;
+            for amount in 0..20u32 {
+                let left = (a << amount) % WORDS;
+                assert_eq!(u16::from(x << u12::from(amount as u16)) as u32, left);
+                assert_eq!(u16::from(x >> u12::from(amount as u16)) as u32, a >> amount);
+            }
+        }
+    }
+
+    #[test]
+    fn test_signs_against_reference() {
+        for x in words() {
+            let a = u16::from(x) as i32;
+            let signed = if a >= 0o4000 { a - WORDS as i32 } else { a };
+            assert_eq!(x.to_i16() as i32, signed);
+            assert_eq!(u12::from_i16(signed as i16), x);
+            assert_eq!(x.is_negative(), signed < 0);
+            assert_eq!(u16::from(-x) as i32, (-a).rem_euclid(WORDS as i32));
+            assert_eq!(-x + x, u12::ZERO);
+            assert_eq!(u16::from(!x) as i32, (WORDS as i32 - 1) - a);
+        }
+        for value in i16::MIN..=i16::MAX {
+            let wrapped = (value as i32).rem_euclid(WORDS as i32);
+            assert_eq!(u16::from(u12::from_i16(value)) as i32, wrapped);
+        }
+    }
+
+    #[test]
+    fn test_rotates_against_reference() {
+        for x in words() {
+            for link in 0..2u8 {
+                // The link sits above bit 0 as a 13 bit word.
+                let thirteen = ((link as u32) << 12) | u16::from(x) as u32;
+                let left = ((thirteen << 1) | (thirteen >> 12)) & 0o17777;
+                let right = ((thirteen >> 1) | ((thirteen & 1) << 12)) & 0o17777;
+                let (ac, l) = x.rotate_left_through_link(link);
+                assert_eq!((u16::from(ac) as u32, l as u32), (left & 0o7777, left >> 12));
+                let (ac, l) = x.rotate_right_through_link(link);
+                assert_eq!((u16::from(ac) as u32, l as u32), (right & 0o7777, right >> 12));
+                // Rotating right undoes rotating left.
+                let (ac, l) = x.rotate_left_through_link(link);
+                assert_eq!(ac.rotate_right_through_link(l), (x, link));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bits_against_reference() {
+        for x in words() {
+            let a = u16::from(x);
+            for bit in 0..12u8 {
+                let mask = 1 << (11 - bit);
+                assert_eq!(x.bit(bit), a & mask != 0);
+                assert_eq!(u16::from(x.with_bit(bit, true)), a | mask);
+                assert_eq!(u16::from(x.with_bit(bit, false)), a & !mask);
+                for last in bit..12 {
+                    let width = last - bit + 1;
+                    let expected = (a >> (11 - last)) & ((1 << width) - 1);
+                    assert_eq!(x.field(bit, last), expected);
+                }
+            }
+            assert_eq!(x.field(0, 11), a);
+        }
+    }
+
+    #[test]
+    fn test_conversions_against_reference() {
+        for value in 0..=u16::MAX {
+            let x = u12::from(value);
+            assert_eq!(u16::from(x), value & 0o7777);
+            assert_eq!(u12::from(value as usize), x);
+            assert_eq!(u12::from(value as i32), x);
+            assert_eq!(usize::from(x), (value & 0o7777) as usize);
+        }
+        for value in 0..=u8::MAX {
+            assert_eq!(u16::from(u12::from(&value)), value as u16);
+        }
+    }
+
+    #[test]
+    fn test_ordering_against_reference() {
+        for x in words().step_by(7) {
+            for y in words().step_by(5) {
+                assert_eq!(x.cmp(&y), u16::from(x).cmp(&u16::from(y)));
+            }
+        }
+    }
 }
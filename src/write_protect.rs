@@ -0,0 +1,323 @@
+use crate::symbols::Symbols;
+use crate::unsigned_integer_12::u12;
+use std::fmt::{Display, Formatter};
+
+// Write protected ranges of memory, so a program running wild can't wreck what it shouldn't,
+// the RIM loader at the top of memory most of all. Writes by the processor or a data break
+// into a range don't happen, and what else is done about them is up to the range:
+//   ignore  Nothing, the program carries on none the wiser.
+//   trap    The machine stops at the end of the instruction, for the debugger.
+//   log     The write is kept in a list for looking at later.
+// Deposits from the console and loading tapes still go in, as do the P cycle's update of
+// the PC in location 0 and an interrupt's store into location 1. A program's own stores
+// there are stopped. Stopped writes don't show up as memory accesses.
+
+/// Where the RIM loader's code lives. Not 7776, it keeps the address it is loading in there.
+pub const RIM_LOADER: (u16, u16) = (0o7756, 0o7775);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    Ignore,
+    Trap,
+    Log,
+}
+impl Protection {
+    pub fn parse(text: &str) -> Result<Protection, String> {
+        match text.to_ascii_lowercase().as_str() {
+            "ignore" => Ok(Protection::Ignore),
+            "trap" => Ok(Protection::Trap),
+            "log" => Ok(Protection::Log),
+            _ => Err(format!("Expected ignore, trap or log, got {text}")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protection::Ignore => "ignore",
+            Protection::Trap => "trap",
+            Protection::Log => "log",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectedRange {
+    pub field: u8,
+    pub first: u12,
+    pub last: u12,
+    pub protection: Protection,
+}
+impl ProtectedRange {
+    /// first-last or a single address in octal, or rim for the RIM loader, then optionally
+    /// :ignore, :trap or :log, trap if not. Always in field 0.
+    pub fn parse(text: &str) -> Result<ProtectedRange, String> {
+        let (range, protection) = match text.split_once(':') {
+            Some((range, protection)) => (range, Protection::parse(protection)?),
+            None => (text, Protection::Trap),
+        };
+        let octal = |text: &str| match u16::from_str_radix(text, 8) {
+            Ok(value) if value <= 0o7777 => Ok(value),
+            _ => Err(format!("Invalid octal number: {text}")),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range.eq_ignore_ascii_case("rim") => RIM_LOADER,
+            Some((first, last)) => (octal(first)?, octal(last)?),
+            None => (octal(range)?, octal(range)?),
+        };
+        if first > last {
+            return Err(format!("Range is backwards: {range}"));
+        }
+        Ok(ProtectedRange {
+            field: 0,
+            first: first.into(),
+            last: last.into(),
+            protection,
+        })
+    }
+
+    pub fn contains(&self, field: u8, address: u12) -> bool {
+        field == self.field && self.first <= address && address <= self.last
+    }
+}
+impl Display for ProtectedRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.first == self.last {
+            true => write!(f, "{:04o}", self.first)?,
+            false => write!(f, "{:04o}-{:04o}", self.first, self.last)?,
+        }
+        if self.field != 0 {
+            write!(f, " in field {}", self.field)?;
+        }
+        write!(f, " {}", self.protection.name())
+    }
+}
+
+/// A write that a protected range stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockedWrite {
+    pub pc: u12, // The instruction doing the write.
+    pub field: u8,
+    pub address: u12,
+    pub value: u12, // What it would have written.
+}
+impl BlockedWrite {
+    pub fn describe(&self, symbols: &Symbols) -> String {
+        format!(
+            "Write of {:04o} to protected {} by {}",
+            self.value,
+            symbols.describe(self.address, 4),
+            symbols.describe(self.pc, 4)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::*;
+    use crate::iot_bus::{IotDevice, IotResponse};
+    use crate::memory::Memory;
+    use crate::scheduler::Events;
+    use crate::{MachineState, StopReason};
+
+    fn range(text: &str) -> ProtectedRange {
+        ProtectedRange::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            range("7756-7777"),
+            ProtectedRange {
+                field: 0,
+                first: 0o7756.into(),
+                last: 0o7777.into(),
+                protection: Protection::Trap
+            }
+        );
+        assert_eq!(range("RIM:log"), range("7756-7775:log"));
+        assert_eq!(range("300:ignore").protection, Protection::Ignore);
+        assert_eq!(range("300").last, 0o300.into());
+        assert_eq!(range("200-277:LOG").to_string(), "0200-0277 log");
+        assert!(ProtectedRange::parse("300-200").is_err());
+        assert!(ProtectedRange::parse("300:shout").is_err());
+        assert!(ProtectedRange::parse("10000").is_err());
+    }
+
+    #[test]
+    fn test_protected_writes() {
+        let mut memory = Memory::default([0.into(); 4096]);
+        memory[0.into()] = 0o200.into(); // The PC doing the writes.
+        memory.protect(range("300:ignore"));
+        memory.protect(range("300-307:log"));
+        memory.protect(range("310:trap"));
+        for address in [0o300, 0o301, 0o310, 0o311] {
+            memory.write(address.into(), 0o1234.into());
+        }
+        // Only 311 isn't protected, and 300 is quietly ignored by the first range.
+        assert_eq!(memory[0o300.into()], 0.into());
+        assert_eq!(memory[0o301.into()], 0.into());
+        assert_eq!(memory[0o310.into()], 0.into());
+        assert_eq!(memory[0o311.into()], 0o1234.into());
+        let write = BlockedWrite {
+            pc: 0o200.into(),
+            field: 0,
+            address: 0o301.into(),
+            value: 0o1234.into(),
+        };
+        assert_eq!(memory.logged_writes(), [write]);
+        assert_eq!(
+            memory.take_trapped_write(),
+            Some(BlockedWrite {
+                address: 0o310.into(),
+                ..write
+            })
+        );
+        assert_eq!(memory.take_trapped_write(), None);
+        // The console still gets in.
+        memory[0o300.into()] = 0o7777.into();
+        assert_eq!(memory[0o300.into()], 0o7777.into());
+        memory.clear_protection();
+        memory.write(0o310.into(), 0o1234.into());
+        assert_eq!(memory[0o310.into()], 0o1234.into());
+    }
+
+    #[test]
+    fn test_blocked_writes_are_not_accesses() {
+        let mut memory = Memory::default([0.into(); 4096]);
+        memory.protect(range("300:ignore"));
+        memory.set_access_logging(true);
+        memory.write(0o300.into(), 0o1234.into());
+        memory.write(0o301.into(), 0o1234.into());
+        let written: Vec<u12> = memory
+            .accesses()
+            .iter()
+            .map(|access| access.address)
+            .collect();
+        assert_eq!(written, [0o301.into()]);
+    }
+
+    #[test]
+    fn test_the_pc_is_not_protected() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o7001), // IAC
+            (0o201, 0o3005), // DCA 5
+            (0o202, 0o7402), // HLT
+        ]);
+        pdp5.memory.protect(range("0-177:trap"));
+        // Only the program's store is stopped, the P cycles still move the PC on.
+        assert!(matches!(
+            pdp5.start_program(100),
+            StopReason::Protected(write) if write.address == 0o5.into()
+        ));
+        assert_eq!(pdp5.start_program(100), StopReason::Halted);
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o203.into());
+        assert_eq!(pdp5.memory[0o5.into()], 0.into());
+    }
+
+    /// A low speed reader with the tape already in it. KCC starts the next character coming
+    /// and it has arrived by the time anyone asks with KSF.
+    struct Reader {
+        tape: Vec<u8>,
+        next: usize,
+        buffer: u8,
+        flag: bool,
+        reading: bool,
+    }
+    impl IotDevice for Reader {
+        fn device_codes(&self) -> &[u8] {
+            &[0o03]
+        }
+        fn pulse(
+            &mut self,
+            _device_code: u8,
+            pulse: u8,
+            ac: u12,
+            _events: &mut Events,
+        ) -> IotResponse {
+            match pulse {
+                1 => {
+                    if self.reading && self.next < self.tape.len() {
+                        self.buffer = self.tape[self.next];
+                        self.next += 1;
+                        self.reading = false;
+                        self.flag = true;
+                    }
+                    return IotResponse {
+                        ac,
+                        skip: self.flag,
+                    };
+                }
+                2 => {
+                    self.flag = false;
+                    self.reading = true;
+                    return IotResponse::unchanged(0.into());
+                }
+                4 => {
+                    return IotResponse::unchanged((u16::from(ac) | u16::from(self.buffer)).into());
+                }
+                _ => {}
+            }
+            IotResponse::unchanged(ac)
+        }
+    }
+
+    #[test]
+    fn test_rim_loader_runs_protected() {
+        let mut pdp5 = MachineState::default([0.into(); 4096]);
+        pdp5.toggle_in(
+            crate::console::RIM_LOADER_ADDRESS.into(),
+            &crate::console::RIM_LOADER,
+        );
+        pdp5.memory.protect(range("rim"));
+        let mut tape = vec![0o200; 8];
+        for (address, word) in [(0o200u16, 0o7001u16), (0o201, 0o7402)] {
+            tape.extend([
+                0o100 | (address >> 6) as u8,
+                (address & 0o77) as u8,
+                (word >> 6) as u8,
+                (word & 0o77) as u8,
+            ]);
+        }
+        tape.extend([0o200; 8]);
+        pdp5.iot_bus.attach(Box::new(Reader {
+            tape,
+            next: 0,
+            buffer: 0,
+            flag: false,
+            reading: false,
+        }));
+        pdp5.set_initial_start_address(crate::console::RIM_LOADER_ADDRESS.into());
+        // Loads the tape and then waits for more.
+        assert_eq!(pdp5.start_program(10_000), StopReason::CycleLimit);
+        assert_eq!(pdp5.memory[0o200.into()], 0o7001.into());
+        assert_eq!(pdp5.memory[0o201.into()], 0o7402.into());
+        assert!(pdp5.memory.logged_writes().is_empty());
+    }
+
+    #[test]
+    fn test_rim_loader_survives() {
+        let mut pdp5 = MachineState::with_program(&[
+            (0o200, 0o7240),  // CLA CMA
+            (0o201, 0o3610),  // DCA I 210, meant for somewhere else
+            (0o202, 0o7402),  // HLT
+            (0o210, 0o7757),  // A pointer gone wrong.
+            (0o7757, 0o6032), // KCC, part of the loader
+        ]);
+        pdp5.memory.protect(range("rim"));
+        let stop = pdp5.start_program(100);
+        assert_eq!(
+            stop,
+            StopReason::Protected(BlockedWrite {
+                pc: 0o201.into(),
+                field: 0,
+                address: 0o7757.into(),
+                value: 0o7777.into(),
+            })
+        );
+        assert_eq!(pdp5.memory[0o7757.into()], 0o6032.into());
+        // Stopped after the store, carrying on gets to the halt.
+        assert_eq!(pdp5.memory[PC_ADDRESS.into()], 0o202.into());
+        assert_eq!(pdp5.start_program(100), StopReason::Halted);
+    }
+}